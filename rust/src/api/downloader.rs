@@ -1,22 +1,32 @@
 #![allow(dead_code)]
 #![allow(unused_imports, unused_variables)]
+#![allow(clippy::too_many_arguments, clippy::upper_case_acronyms)]
 
-use aes::Aes128;
+use aes::cipher::block_padding::NoPadding;
+use aes::cipher::{BlockDecryptMut, KeyIvInit};
 use anyhow::{anyhow, bail, Context, Result};
-use block_modes::block_padding::Pkcs7;
-use block_modes::{BlockMode, Cbc};
-use futures::stream::{self, StreamExt};
+use futures::future::BoxFuture;
+use futures::stream::{self, Stream, StreamExt};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use log::{error, info, warn};
 use m3u8_rs::{parse_playlist, Playlist};
-use reqwest::{header, Client};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{header, Client, ClientBuilder};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 #[cfg(target_os = "android")]
 use std::sync::OnceLock;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 #[cfg(target_os = "android")]
 use std::env;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
 use tokio::sync::Semaphore;
 use tokio::{fs, process::Command, sync::Mutex};
 use url::Url;
@@ -27,591 +37,5593 @@ use jni::objects::{GlobalRef, JClass, JObject, JValue};
 #[cfg(target_os = "android")]
 use jni::JavaVM;
 
-type Aes128Cbc = Cbc<Aes128, Pkcs7>;
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
 
-#[derive(Clone, Copy, Debug)]
-enum AccelType {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccelType {
     Nvidia,
     AMD,
     CPU,
 }
 
-#[derive(Clone, Copy, Debug)]
-enum TranscoderKind {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TranscoderKind {
     Ffmpeg(AccelType),
     AndroidHardware,
+    /// The caller's [`Transcoder`] from [`DownloadOptions::transcoder`].
+    Custom,
 }
 
-#[derive(Clone)]
-pub struct ProgressUpdate {
-    pub message: String,
-    pub progress: f64,
+/// Turns the downloaded media into `output`. The built-in FFmpeg and MediaCodec backends
+/// implement it; set [`DownloadOptions::transcoder`] to use another one instead.
+pub trait Transcoder: Send + Sync + std::fmt::Debug {
+    /// Whether [`TranscodeInput::Stream`] is supported, which `pipelined` runs need.
+    fn accepts_stream(&self) -> bool {
+        false
+    }
+
+    fn transcode<'a>(
+        &'a self,
+        input: TranscodeInput<'a>,
+        output: &'a str,
+        options: &'a DownloadOptions,
+        progress: &'a TranscodeProgress<'a>,
+    ) -> BoxFuture<'a, Result<TranscodeOutcome>>;
 }
 
-#[cfg(target_os = "android")]
-static ANDROID_HW_TRANSCODER: OnceLock<Arc<AndroidMediaCodecTranscoder>> = OnceLock::new();
+/// Where a [`Transcoder`] reads the media from.
+pub enum TranscodeInput<'a> {
+    /// The merged TS on disk.
+    File(&'a str),
+    /// The segments in playlist order while the rest are still downloading. `probe` is a
+    /// file holding only the first one, for inspecting the streams.
+    Stream { probe: &'a str, segments: stream::BoxStream<'static, Vec<u8>> },
+}
 
-#[cfg(target_os = "android")]
-static ANDROID_CONTEXT: OnceLock<AndroidContextData> = OnceLock::new();
+/// Progress of a running transcode, reported on the 0.9..1.0 range of the overall progress.
+pub struct TranscodeProgress<'a> {
+    /// Seconds of media in the input, when known.
+    pub expected_duration: Option<f64>,
+    pb: &'a ProgressBar,
+    reporter: &'a Reporter,
+}
 
-/// Cached MediaTranscoder class reference (must be cached from main thread with app classloader)
-#[cfg(target_os = "android")]
-static MEDIA_TRANSCODER_CLASS: OnceLock<GlobalRef> = OnceLock::new();
+impl TranscodeProgress<'_> {
+    /// Reports `fraction` (0 to 1) of the input as transcoded.
+    pub fn update(&self, fraction: f64) {
+        let fraction = fraction.clamp(0.0, 1.0);
+        let message = format!("Converting to MP4... {:.0}%", fraction * 100.0);
+        self.pb.set_message(message.clone());
+        self.reporter.progress(message, 0.9 + fraction * 0.1);
+    }
+}
 
-#[cfg(target_os = "android")]
-pub struct AndroidMediaCodecTranscoder {
-    jvm: Arc<JavaVM>,
+/// What a [`Transcoder`] reports once the output is written.
+#[derive(Clone, Debug)]
+pub struct TranscodeOutcome {
+    /// Backend that produced the output, e.g. after a CPU fallback.
+    pub kind: TranscoderKind,
+    /// Captured log, when `capture_ffmpeg_log` is set and the backend has one.
+    pub log: Option<String>,
 }
 
-#[cfg(target_os = "android")]
-impl AndroidMediaCodecTranscoder {
-    pub fn new(jvm: Arc<JavaVM>) -> Self {
-        Self { jvm }
-    }
+#[derive(Clone, Debug)]
+pub struct ProgressUpdate {
+    pub message: String,
+    pub progress: f64,
+}
 
-    pub async fn transcode(
-        &self,
-        input_ts: &str,
-        output_mp4: &str,
-        video_bitrate: u32,
-        audio_bitrate: u32,
-    ) -> Result<()> {
-        let jvm = self.jvm.clone();
-        let input_ts = input_ts.to_string();
-        let output_mp4 = output_mp4.to_string();
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Fetching the playlist URL itself, master or media.
+    FetchPlaylist,
+    /// Fetching the media playlist of the selected variant.
+    FetchMediaPlaylist,
+    /// Fetching decryption keys (EXT-X-SESSION-KEY and EXT-X-KEY) before the download.
+    FetchKeys,
+    Download,
+    Merge,
+    Transcode,
+}
 
-        tokio::task::spawn_blocking(move || {
-            let mut env = jvm
-                .attach_current_thread()
-                .map_err(|e| anyhow!("JNI attach thread failed: {}", e))?;
+#[derive(Clone, Debug)]
+pub enum DownloadEvent {
+    Progress(ProgressUpdate),
+    PhaseStarted {
+        phase: Phase,
+        at: SystemTime,
+    },
+    PhaseEnded {
+        phase: Phase,
+        at: SystemTime,
+        elapsed: Duration,
+    },
+    /// Free space in the temp dir fell below [`DownloadOptions::min_free_space`]; new segment
+    /// downloads are paused until it recovers.
+    LowStorage {
+        available: u64,
+        required: u64,
+    },
+    StorageRecovered {
+        available: u64,
+    },
+    /// Emitted once the stream and transcoder are chosen, before any segment is fetched.
+    /// `variant` is `None` when the URL was a media playlist.
+    StreamSelected {
+        variant: Option<VariantInfo>,
+        backend: TranscoderKind,
+    },
+}
 
-            // Try to get cached class first, otherwise load it using app ClassLoader
-            let class: JClass = if let Some(class_ref) = MEDIA_TRANSCODER_CLASS.get() {
-                // SAFETY: GlobalRef was created from a JClass, so it's safe to cast back
-                unsafe { JClass::from_raw(class_ref.as_obj().as_raw()) }
-            } else {
-                // Load class using app context's ClassLoader (works from any thread)
-                let ctx = get_android_context()
-                    .map_err(|e| anyhow!("Failed to get Android context: {}", e))?;
-                
-                // Get ClassLoader from app context
-                let class_loader = env
-                    .call_method(ctx.app_context.as_obj(), "getClassLoader", "()Ljava/lang/ClassLoader;", &[])
-                    .map_err(|e| anyhow!("Failed to get ClassLoader: {:?}", e))?
-                    .l()
-                    .map_err(|e| anyhow!("ClassLoader is not an object: {:?}", e))?;
-                
-                // Use ClassLoader.loadClass() to load MediaTranscoder
-                let class_name = env
-                    .new_string("com.bluevale.m3u8_downloader.MediaTranscoder")
-                    .map_err(|e| anyhow!("Failed to create class name string: {:?}", e))?;
-                
-                let loaded_class = env
-                    .call_method(
-                        &class_loader,
-                        "loadClass",
-                        "(Ljava/lang/String;)Ljava/lang/Class;",
-                        &[JValue::Object(&class_name)],
-                    )
-                    .map_err(|e| anyhow!("Failed to load MediaTranscoder class: {:?}", e))?
-                    .l()
-                    .map_err(|e| anyhow!("loadClass did not return a Class: {:?}", e))?;
-                
-                info!("✅ MediaTranscoder class loaded via ClassLoader");
-                
-                // Cache the class for future use
-                if let Ok(global_ref) = env.new_global_ref(&loaded_class) {
-                    let _ = MEDIA_TRANSCODER_CLASS.set(global_ref);
-                }
-                
-                // SAFETY: loaded_class is a java.lang.Class object
-                unsafe { JClass::from_raw(loaded_class.as_raw()) }
-            };
+/// Callback receiving every [`DownloadEvent`] emitted by a run.
+#[derive(Clone)]
+pub struct EventCallback(pub Arc<dyn Fn(&DownloadEvent) + Send + Sync>);
 
-            let input_ts_jstring = env
-                .new_string(&input_ts)
-                .map_err(|e| anyhow!("JNI new_string failed: {}", e))?;
+impl std::fmt::Debug for EventCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EventCallback")
+    }
+}
 
-            let output_mp4_jstring = env
-                .new_string(&output_mp4)
-                .map_err(|e| anyhow!("JNI new_string failed: {}", e))?;
+#[derive(Clone, Debug)]
+pub struct PhaseTiming {
+    pub phase: Phase,
+    pub started_at: SystemTime,
+    pub elapsed: Duration,
+}
 
-            let result = env
-                .call_static_method(
-                    class,
-                    "transcode",
-                    "(Ljava/lang/String;Ljava/lang/String;II)Z",
-                    &[
-                        JValue::Object(&input_ts_jstring),
-                        JValue::Object(&output_mp4_jstring),
-                        JValue::Int(video_bitrate as i32),
-                        JValue::Int(audio_bitrate as i32),
-                    ],
-                )
-                .map_err(|e| anyhow!("JNI call_static_method failed: {}", e))?;
+#[derive(Clone, Debug, Default)]
+pub struct DownloadResult {
+    pub output_path: String,
+    pub phase_timings: Vec<PhaseTiming>,
+    /// Media duration in seconds, summed from the playlist's EXTINF values.
+    pub total_duration: f64,
+    /// Duration of the written file as reported by ffprobe, when it was checked.
+    pub output_duration: Option<f64>,
+    pub stats: DownloadStats,
+    /// Transcoder that produced the output; differs from the selected one after a CPU fallback.
+    pub transcoder: Option<TranscoderKind>,
+    /// The intermediate merged TS, when `keep_temp` retained it.
+    pub merged_ts_path: Option<String>,
+    /// With `segments_only`, a VOD playlist referencing the saved (decrypted) segments.
+    pub local_playlist_path: Option<String>,
+    /// Video size of the output as reported by ffprobe.
+    pub output_resolution: Option<(u32, u32)>,
+    /// Average video frame rate of the output as reported by ffprobe.
+    pub output_frame_rate: Option<f64>,
+    /// Sample rate in Hz and channel count of the output's audio as reported by ffprobe.
+    pub output_audio: Option<(u32, u32)>,
+    /// FFmpeg's stderr, with `capture_ffmpeg_log`.
+    pub ffmpeg_log: Option<String>,
+    /// Video bitrate in kbps picked by `auto_bitrate`.
+    pub auto_video_bitrate: Option<u32>,
+    /// Audio and subtitle renditions of the variant picked by `preferred_languages`. Ones
+    /// with their own `uri` aren't downloaded into the output; fetch them separately.
+    pub audio_rendition: Option<RenditionInfo>,
+    pub subtitle_rendition: Option<RenditionInfo>,
+    /// How the segments were encrypted; `None` when none of them were.
+    pub encryption: Option<EncryptionInfo>,
+}
 
-            let success = result
-                .z()
-                .map_err(|e| anyhow!("JNI get boolean return failed: {}", e))?;
+/// Encryption detected from a media playlist's EXT-X-KEY tags, for debugging decryption.
+#[derive(Clone, Debug, Default)]
+pub struct EncryptionInfo {
+    /// Distinct methods in playlist order, e.g. `["AES-128"]` or `["SAMPLE-AES"]`.
+    pub methods: Vec<String>,
+    /// Distinct key URLs; query strings are removed unless `report_key_uris`.
+    pub key_uris: Vec<String>,
+    /// Encrypted segments with an explicit IV, and with one derived from the media sequence.
+    pub explicit_iv_segments: usize,
+    pub sequence_iv_segments: usize,
+    /// `decryption_key` was used instead of the playlist's keys.
+    pub key_override: bool,
+}
 
-            if success {
-                Ok(())
-            } else {
-                Err(anyhow!("Java MediaCodec transcode failed"))
-            }
-        })
-        .await
-        .map_err(|e| anyhow!("tokio spawn_blocking failed: {}", e))?
-    }
+/// Throughput figures for the segment download phase.
+#[derive(Clone, Debug, Default)]
+pub struct DownloadStats {
+    pub total_bytes: u64,
+    pub download_time: Duration,
+    /// Bytes per second over the whole download phase.
+    pub average_throughput: f64,
+    /// Fastest single segment, in bytes per second.
+    pub peak_throughput: f64,
+    /// Failed attempts that were retried.
+    pub retries: u64,
+    /// Segments that succeeded after at least one failed attempt.
+    pub recovered_segments: u64,
+    /// EXT-X-GAP segments, which were skipped rather than requested.
+    pub gap_segments: u64,
 }
 
-#[cfg(target_os = "android")]
-pub fn register_android_mediacodec_transcoder(jvm: Arc<JavaVM>) -> Result<()> {
-    if ANDROID_HW_TRANSCODER.get().is_some() {
-        info!("Android MediaCodec transcoder already registered");
-        return Ok(());
+#[derive(Default)]
+struct StatsCollector {
+    bytes: AtomicU64,
+    retries: AtomicU64,
+    recovered: AtomicU64,
+    peak_bps: AtomicU64,
+}
+
+impl StatsCollector {
+    fn record_segment(&self, bytes: usize, elapsed: Duration, attempt: u8) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        let secs = elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.peak_bps
+                .fetch_max((bytes as f64 / secs) as u64, Ordering::Relaxed);
+        }
+        if attempt > 1 {
+            self.recovered.fetch_add(1, Ordering::Relaxed);
+        }
     }
 
-    let transcoder = AndroidMediaCodecTranscoder::new(jvm);
-    ANDROID_HW_TRANSCODER
-        .set(Arc::new(transcoder))
-        .map_err(|_| anyhow!("Failed to register Android MediaCodec transcoder"))?;
+    fn finish(&self, download_time: Duration) -> DownloadStats {
+        let total_bytes = self.bytes.load(Ordering::Relaxed);
+        let secs = download_time.as_secs_f64();
+        DownloadStats {
+            total_bytes,
+            download_time,
+            average_throughput: if secs > 0.0 { total_bytes as f64 / secs } else { 0.0 },
+            peak_throughput: self.peak_bps.load(Ordering::Relaxed) as f64,
+            retries: self.retries.load(Ordering::Relaxed),
+            recovered_segments: self.recovered.load(Ordering::Relaxed),
+            gap_segments: 0,
+        }
+    }
+}
 
-    info!("鉁� Android MediaCodec transcoder registered");
-    Ok(())
+/// What to do when the output's duration differs from the playlist's by more than the tolerance.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DurationCheck {
+    Off,
+    #[default]
+    Warn,
+    Strict,
 }
 
-#[cfg(target_os = "android")]
-struct AndroidContextData {
-    jvm: Arc<JavaVM>,
-    app_context: GlobalRef,
+/// What to do with a successful segment response that looks like an error page (HTML or
+/// JSON content type or body) or is empty.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SegmentValidation {
+    Off,
+    #[default]
+    Warn,
+    /// Treat it as a failed attempt and retry.
+    Strict,
 }
 
-#[cfg(target_os = "android")]
-pub fn init_android_context(jvm: Arc<JavaVM>, context: GlobalRef) -> Result<()> {
-    let data = AndroidContextData {
-        jvm,
-        app_context: context,
-    };
+/// The order in which downloaded segments are handed on for writing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DownloadOrder {
+    /// As they complete, for the most throughput (default).
+    #[default]
+    Unordered,
+    /// In playlist order, within a bounded look-ahead window, so the head of the stream is
+    /// available sooner. Always used when segments are streamed (`pipelined`,
+    /// `segment_stream`) or their timestamps rebased (`fix_timestamps`).
+    Sequential,
+}
 
-    ANDROID_CONTEXT
-        .set(data)
-        .map_err(|_| anyhow!("Android Context already initialized"))?;
+/// How [`DownloadOptions::frame_reduction`] thins out the video frames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameReduction {
+    /// Resample to this many frames per second (FFmpeg's `fps` filter).
+    Rate(f64),
+    /// Keep only keyframes, at their original timestamps.
+    KeyframesOnly,
+}
 
-    info!("Android context initialized");
-    Ok(())
+/// What download progress is measured in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProgressUnit {
+    /// Bytes when the playlist gives the total size (byte ranges or EXT-X-BITRATE),
+    /// otherwise segments (default).
+    #[default]
+    Auto,
+    Segments,
+    /// Bytes, against a total extrapolated from the downloaded media duration when the
+    /// playlist doesn't give one.
+    Bytes,
 }
 
-#[cfg(target_os = "android")]
-fn get_android_context() -> Result<&'static AndroidContextData> {
-    ANDROID_CONTEXT
-        .get()
-        .ok_or_else(|| anyhow!("Android Context not initialized; call init_android_context()"))
+/// How a segment response should be treated by the retry loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryDecision {
+    Success,
+    Retry,
+    Fatal,
 }
 
-#[cfg(target_os = "android")]
-fn verify_directory_writable(path: &PathBuf) -> bool {
-    if !path.exists() {
-        if let Err(e) = std::fs::create_dir_all(&path) {
-            warn!("Failed to create directory {}: {}", path.display(), e);
-            return false;
+/// Classifies segment responses, e.g. to retry a CDN's rate-limit 403s or fail fast on 404.
+/// A `Success` body is taken as the segment; when a byte-range request is answered with a
+/// plain 200, the requested range is cut out of the whole resource.
+#[derive(Clone)]
+pub struct RetryPredicate(pub Arc<dyn Fn(&reqwest::Response) -> RetryDecision + Send + Sync>);
+
+impl RetryPredicate {
+    /// Without a predicate only 200 and 206 are successes. Everything else is retried,
+    /// including bodiless 2xx like 204 and 3xx that weren't followed (e.g. a stray 304).
+    fn decide(predicate: Option<&RetryPredicate>, response: &reqwest::Response) -> RetryDecision {
+        match predicate {
+            Some(predicate) => (predicate.0)(response),
+            None => match response.status() {
+                reqwest::StatusCode::OK | reqwest::StatusCode::PARTIAL_CONTENT => {
+                    RetryDecision::Success
+                }
+                _ => RetryDecision::Retry,
+            },
         }
     }
+}
 
-    if !path.is_dir() {
-        warn!("Path exists but is not a directory: {}", path.display());
-        return false;
+impl std::fmt::Debug for RetryPredicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RetryPredicate")
     }
+}
 
-    let test_file = path.join(".writable_test_temp");
-    match std::fs::write(&test_file, b"test") {
-        Ok(_) => {
-            if let Err(e) = std::fs::remove_file(&test_file) {
-                warn!("Failed to remove test file: {}", e);
+/// Fetches AES-128 keys in place of the default GET, for key servers that want a POST, a
+/// license exchange or another bespoke handshake. Gets the resolved key URL (not passed
+/// through the [`UrlSigner`]) and the EXT-X-KEY KEYFORMAT, and returns the raw 16-byte key.
+#[derive(Clone)]
+pub struct KeyRequestHandler(pub Arc<KeyRequestFn>);
+
+pub type KeyRequestFn =
+    dyn Fn(Url, Option<String>) -> BoxFuture<'static, Result<Vec<u8>>> + Send + Sync;
+
+impl std::fmt::Debug for KeyRequestHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("KeyRequestHandler")
+    }
+}
+
+/// Rewrites each segment and key URL right before it is requested (including on retries),
+/// e.g. to append a freshly signed CDN token.
+#[derive(Clone)]
+pub struct UrlSigner(pub Arc<dyn Fn(Url) -> Url + Send + Sync>);
+
+impl UrlSigner {
+    fn sign(signer: Option<&UrlSigner>, url: &str) -> Result<String> {
+        match signer {
+            Some(signer) => {
+                let parsed = Url::parse(url).with_context(|| format!("Invalid URL: {}", url))?;
+                Ok((signer.0)(parsed).to_string())
             }
-            info!("Directory is writable: {}", path.display());
-            true
-        }
-        Err(e) => {
-            warn!(
-                "Directory {} is not writable: {} (OS Error: {})",
-                path.display(),
-                e,
-                e.raw_os_error().unwrap_or(0)
-            );
-            false
+            None => Ok(url.to_string()),
         }
     }
 }
 
-#[cfg(target_os = "android")]
-pub fn get_app_cache_dir() -> Result<PathBuf> {
-    let ctx_data = get_android_context()?;
-    let mut env = ctx_data
-        .jvm
-        .attach_current_thread()
-        .map_err(|e| anyhow!("JNI attach thread failed: {}", e))?;
+impl std::fmt::Debug for UrlSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UrlSigner")
+    }
+}
 
-    let context_obj = ctx_data.app_context.as_obj();
+/// Kind of stream advertised by a master playlist.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StreamKind {
+    /// Regular `EXT-X-STREAM-INF` variant (default).
+    #[default]
+    Main,
+    /// Trick-play `EXT-X-I-FRAME-STREAM-INF` variant.
+    IFrame,
+    /// Thumbnail `EXT-X-IMAGE-STREAM-INF` variant.
+    Image,
+}
 
-    let cache_dir_obj = env
-        .call_method(context_obj, "getCacheDir", "()Ljava/io/File;", &[])
-        .map_err(|e| anyhow!("JNI getCacheDir call failed: {}", e))?
-        .l()
-        .map_err(|e| anyhow!("JNI getCacheDir returned invalid object: {}", e))?;
+#[derive(Clone, Debug)]
+pub struct VariantInfo {
+    pub kind: StreamKind,
+    pub uri: String,
+    pub bandwidth: u64,
+    pub average_bandwidth: Option<u64>,
+    pub resolution: Option<(u64, u64)>,
+    pub codecs: Option<String>,
+    pub frame_rate: Option<f64>,
+    /// `GROUP-ID` of the EXT-X-MEDIA audio renditions this variant plays with.
+    pub audio_group: Option<String>,
+    pub subtitle_group: Option<String>,
+}
 
-    let path_str = env
-        .call_method(
-            &cache_dir_obj,
-            "getAbsolutePath",
-            "()Ljava/lang/String;",
-            &[],
-        )
-        .map_err(|e| anyhow!("JNI getAbsolutePath call failed: {}", e))?
-        .l()
-        .map_err(|e| anyhow!("JNI getAbsolutePath returned invalid object: {}", e))?;
+/// An EXT-X-MEDIA rendition picked for the selected variant.
+#[derive(Clone, Debug)]
+pub struct RenditionInfo {
+    pub group_id: String,
+    pub name: String,
+    pub language: Option<String>,
+    /// Playlist of the rendition; `None` when it is carried in the variant's own segments.
+    pub uri: Option<String>,
+    pub default: bool,
+}
 
-    let path_jstring = env
-        .get_string((&path_str).into())
-        .map_err(|e| anyhow!("JNI string conversion failed: {}", e))?;
+#[derive(Clone, Debug, Default)]
+pub struct PlaylistProbe {
+    pub is_master: bool,
+    pub variants: Vec<VariantInfo>,
+    pub i_frame_streams: Vec<VariantInfo>,
+    pub image_streams: Vec<VariantInfo>,
+}
 
-    Ok(PathBuf::from(path_jstring.to_string_lossy().to_string()))
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlaylistKind {
+    Master,
+    #[default]
+    Media,
 }
 
-#[cfg(target_os = "android")]
-pub fn get_app_files_dir() -> Result<PathBuf> {
-    let ctx_data = get_android_context()?;
-    let mut env = ctx_data
-        .jvm
-        .attach_current_thread()
-        .map_err(|e| anyhow!("JNI attach thread failed: {}", e))?;
+/// Everything a UI needs to show before committing to a download. For master playlists the
+/// media figures describe the variant that would be downloaded by default.
+#[derive(Clone, Debug, Default)]
+pub struct PlaylistInspection {
+    pub kind: PlaylistKind,
+    pub is_encrypted: bool,
+    /// No `EXT-X-ENDLIST`.
+    pub is_live: bool,
+    pub segment_count: usize,
+    pub total_duration: f64,
+    /// Expected download size in bytes from EXT-X-BITRATE hints, when every segment has one.
+    pub estimated_size: Option<u64>,
+    pub variants: Vec<VariantInfo>,
+}
 
-    let context_obj = ctx_data.app_context.as_obj();
+/// Outcome of [`validate`]: whether a download of the playlist could start.
+#[derive(Clone, Debug, Default)]
+pub struct ValidationReport {
+    pub passed: bool,
+    /// The media playlist the download would use, once resolved.
+    pub media_url: Option<String>,
+    pub segment_count: usize,
+    pub encryption: Option<EncryptionInfo>,
+    /// Why the check failed, empty when it passed.
+    pub errors: Vec<String>,
+}
 
-    let files_dir_obj = env
-        .call_method(context_obj, "getFilesDir", "()Ljava/io/File;", &[])
-        .map_err(|e| anyhow!("JNI getFilesDir call failed: {}", e))?
-        .l()
-        .map_err(|e| anyhow!("JNI getFilesDir returned invalid object: {}", e))?;
+/// Which variant of a master playlist to pick when not downloading all of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VariantChoice {
+    /// Highest resolution, then highest bandwidth.
+    #[default]
+    Best,
+    /// Lowest bandwidth, e.g. for quick previews.
+    Lowest,
+}
 
-    let path_str = env
-        .call_method(
-            &files_dir_obj,
-            "getAbsolutePath",
-            "()Ljava/lang/String;",
-            &[],
-        )
+/// How a run reports progress.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// `Bars` when attached to a terminal, otherwise `Plain` (default).
+    #[default]
+    Auto,
+    /// Terminal progress bars plus sink/callback events.
+    Bars,
+    /// Progress as periodic log lines instead of bars, for services, CI and embedding apps.
+    Plain,
+    /// Every event, and finally each result, as one JSON object per line on stdout (logs
+    /// stay on stderr). Each object names its kind in `"type"`.
+    Json,
+    /// No terminal output and no progress events; only the final result.
+    Quiet,
+    /// No terminal output; progress goes to the sink/callback only.
+    CallbackOnly,
+}
+
+impl ProgressMode {
+    /// Settles `Auto`. indicatif draws on stderr, so that is the stream checked.
+    fn resolve(self) -> Self {
+        use std::io::IsTerminal;
+        match self {
+            ProgressMode::Auto if std::io::stderr().is_terminal() => ProgressMode::Bars,
+            ProgressMode::Auto => ProgressMode::Plain,
+            mode => mode,
+        }
+    }
+}
+
+/// Terminal progress bars; without a `MultiProgress` every bar is hidden and never ticks.
+#[derive(Clone)]
+struct TerminalProgress {
+    multi: Option<MultiProgress>,
+}
+
+impl TerminalProgress {
+    fn new(mode: ProgressMode) -> Self {
+        Self {
+            multi: (mode.resolve() == ProgressMode::Bars).then(MultiProgress::new),
+        }
+    }
+
+    fn spinner(&self, template: &str, tick: Duration) -> Result<ProgressBar> {
+        let Some(multi) = &self.multi else {
+            return Ok(ProgressBar::hidden());
+        };
+        let pb = multi.add(ProgressBar::new_spinner());
+        pb.set_style(
+            ProgressStyle::with_template(template)?.tick_strings(&["-", "\\", "|", "/"]),
+        );
+        pb.enable_steady_tick(tick);
+        Ok(pb)
+    }
+
+    fn bar(&self, len: u64, template: &str) -> Result<ProgressBar> {
+        let Some(multi) = &self.multi else {
+            return Ok(ProgressBar::hidden());
+        };
+        let pb = multi.add(ProgressBar::new(len));
+        pb.set_style(ProgressStyle::with_template(template)?.progress_chars("##-"));
+        Ok(pb)
+    }
+}
+
+/// Lock-free throttle for `Plain` progress logging, shared by every clone of a [`Reporter`].
+struct PlainLog {
+    started: Instant,
+    /// Milliseconds after `started` of the last logged line; `u64::MAX` until the first one.
+    last_ms: AtomicU64,
+}
+
+/// Fans progress and phase events out to the Dart sink and/or the Rust callback.
+#[derive(Clone)]
+struct Reporter {
+    quiet: bool,
+    json: bool,
+    /// Set in `Plain` mode: throttles how often progress is written to the log.
+    plain_logged: Option<Arc<PlainLog>>,
+    sink: Option<StreamSink<ProgressUpdate>>,
+    on_event: Option<EventCallback>,
+    timings: Arc<std::sync::Mutex<Vec<PhaseTiming>>>,
+}
+
+impl Reporter {
+    fn new(
+        mode: ProgressMode,
+        sink: Option<StreamSink<ProgressUpdate>>,
+        on_event: Option<EventCallback>,
+    ) -> Self {
+        let mode = mode.resolve();
+        Self {
+            quiet: mode == ProgressMode::Quiet,
+            json: mode == ProgressMode::Json,
+            plain_logged: (mode == ProgressMode::Plain).then(|| {
+                Arc::new(PlainLog {
+                    started: Instant::now(),
+                    last_ms: AtomicU64::new(u64::MAX),
+                })
+            }),
+            sink,
+            on_event,
+            timings: Arc::new(std::sync::Mutex::new(Vec::new())),
+        }
+    }
+
+    fn progress(&self, message: impl Into<String>, progress: f64) {
+        if self.quiet {
+            return;
+        }
+        let update = ProgressUpdate {
+            message: message.into(),
+            progress,
+        };
+        if let Some(logged) = &self.plain_logged {
+            // Every few seconds is plenty for a log; always show completion.
+            let now = logged.started.elapsed().as_millis() as u64;
+            let last = logged.last_ms.load(Ordering::Relaxed);
+            let due = last == u64::MAX || now.saturating_sub(last) >= 5_000;
+            // Only the task that wins the swap logs, so concurrent segments never queue up.
+            let claimed = (due || progress >= 1.0)
+                && logged
+                    .last_ms
+                    .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok();
+            if claimed || progress >= 1.0 {
+                info!("[{:>3.0}%] {}", progress * 100.0, update.message);
+            }
+        }
+        if let Some(sink) = &self.sink {
+            let _ = sink.add(update.clone());
+        }
+        self.event(DownloadEvent::Progress(update));
+    }
+
+    fn event(&self, event: DownloadEvent) {
+        if self.quiet {
+            return;
+        }
+        if self.json {
+            println!("{}", event_json(&event));
+        }
+        if let Some(callback) = &self.on_event {
+            (callback.0)(&event);
+        }
+    }
+
+    fn stream_selected(&self, variant: Option<&VariantInfo>, backend: TranscoderKind) {
+        let stream = match variant.and_then(|v| v.resolution) {
+            Some((_, h)) => format!("{}p", h),
+            None => "stream".to_string(),
+        };
+        let engine = match backend {
+            TranscoderKind::Ffmpeg(accel) => format!("FFmpeg ({:?})", accel),
+            TranscoderKind::AndroidHardware => "Android MediaCodec".to_string(),
+            TranscoderKind::Custom => "custom transcoder".to_string(),
+        };
+        self.progress(format!("Downloading {} with {}", stream, engine), 0.02);
+        self.event(DownloadEvent::StreamSelected {
+            variant: variant.cloned(),
+            backend,
+        });
+    }
+
+    fn phase_started(&self, phase: Phase) -> (SystemTime, Instant) {
+        let at = SystemTime::now();
+        info!("Phase {:?} started", phase);
+        self.event(DownloadEvent::PhaseStarted { phase, at });
+        (at, Instant::now())
+    }
+
+    fn phase_ended(&self, phase: Phase, (started_at, started): (SystemTime, Instant)) {
+        let elapsed = started.elapsed();
+        info!("Phase {:?} finished in {:.2?}", phase, elapsed);
+        if let Ok(mut timings) = self.timings.lock() {
+            timings.push(PhaseTiming {
+                phase,
+                started_at,
+                elapsed,
+            });
+        }
+        self.event(DownloadEvent::PhaseEnded {
+            phase,
+            at: SystemTime::now(),
+            elapsed,
+        });
+    }
+
+    /// Same destinations, but with its own phase timings (one per output file).
+    fn scoped(&self) -> Self {
+        Self {
+            timings: Arc::new(std::sync::Mutex::new(Vec::new())),
+            ..self.clone()
+        }
+    }
+
+    fn phase_timings(&self) -> Vec<PhaseTiming> {
+        self.timings.lock().map(|t| t.clone()).unwrap_or_default()
+    }
+
+    /// Emits the final result of one output in JSON mode.
+    fn result(&self, result: &DownloadResult) {
+        if self.json {
+            println!("{}", result_json(result));
+        }
+    }
+}
+
+fn unix_millis(at: SystemTime) -> u128 {
+    at.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0)
+}
+
+/// The UTC calendar date of `at` as `YYYY-MM-DD`.
+fn utc_date(at: SystemTime) -> String {
+    let days = (unix_millis(at) / 86_400_000) as i64;
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn variant_json(variant: &VariantInfo) -> serde_json::Value {
+    serde_json::json!({
+        "kind": format!("{:?}", variant.kind),
+        "uri": variant.uri,
+        "bandwidth": variant.bandwidth,
+        "resolution": variant.resolution.map(|(w, h)| format!("{}x{}", w, h)),
+        "codecs": variant.codecs,
+    })
+}
+
+fn event_json(event: &DownloadEvent) -> serde_json::Value {
+    match event {
+        DownloadEvent::Progress(update) => serde_json::json!({
+            "type": "progress",
+            "message": update.message,
+            "progress": update.progress,
+        }),
+        DownloadEvent::PhaseStarted { phase, at } => serde_json::json!({
+            "type": "phase_started",
+            "phase": format!("{:?}", phase),
+            "at_ms": unix_millis(*at),
+        }),
+        DownloadEvent::PhaseEnded { phase, at, elapsed } => serde_json::json!({
+            "type": "phase_ended",
+            "phase": format!("{:?}", phase),
+            "at_ms": unix_millis(*at),
+            "elapsed_secs": elapsed.as_secs_f64(),
+        }),
+        DownloadEvent::LowStorage { available, required } => serde_json::json!({
+            "type": "low_storage",
+            "available": available,
+            "required": required,
+        }),
+        DownloadEvent::StorageRecovered { available } => serde_json::json!({
+            "type": "storage_recovered",
+            "available": available,
+        }),
+        DownloadEvent::StreamSelected { variant, backend } => serde_json::json!({
+            "type": "stream_selected",
+            "variant": variant.as_ref().map(variant_json),
+            "backend": format!("{:?}", backend),
+        }),
+    }
+}
+
+fn result_json(result: &DownloadResult) -> serde_json::Value {
+    let size = std::fs::metadata(&result.output_path).ok().filter(|m| m.is_file()).map(|m| m.len());
+    serde_json::json!({
+        "type": "result",
+        "output_path": result.output_path,
+        "size": size,
+        "duration": result.output_duration.unwrap_or(result.total_duration),
+        "resolution": result.output_resolution.map(|(w, h)| format!("{}x{}", w, h)),
+        "frame_rate": result.output_frame_rate,
+        "audio_sample_rate": result.output_audio.map(|(rate, _)| rate),
+        "audio_channels": result.output_audio.map(|(_, channels)| channels),
+        "transcoder": result.transcoder.map(|t| format!("{:?}", t)),
+        "total_bytes": result.stats.total_bytes,
+        "download_secs": result.stats.download_time.as_secs_f64(),
+        "retries": result.stats.retries,
+        "local_playlist_path": result.local_playlist_path,
+        "encryption": result.encryption.as_ref().map(|e| serde_json::json!({
+            "methods": e.methods,
+            "key_uris": e.key_uris,
+            "explicit_iv_segments": e.explicit_iv_segments,
+            "sequence_iv_segments": e.sequence_iv_segments,
+            "key_override": e.key_override,
+        })),
+    })
+}
+
+/// Address family used when connecting to playlist, key and segment hosts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IpPreference {
+    /// Let the resolver and happy-eyeballs pick (default).
+    #[default]
+    Auto,
+    /// Only connect to IPv4 addresses.
+    V4Only,
+    /// Only connect to IPv6 addresses.
+    V6Only,
+}
+
+impl IpPreference {
+    fn allows(self, ip: IpAddr) -> bool {
+        match self {
+            IpPreference::Auto => true,
+            IpPreference::V4Only => ip.is_ipv4(),
+            IpPreference::V6Only => ip.is_ipv6(),
+        }
+    }
+}
+
+/// The `Referer` header sent with requests.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum RefererPolicy {
+    /// The playlist's origin on playlist requests, nothing on keys and segments.
+    #[default]
+    Auto,
+    /// No Referer at all, not even the one reqwest adds when following a redirect.
+    Omit,
+    /// This value on every playlist, key and segment request.
+    Custom(String),
+}
+
+/// HTTP version used by the playlist, key and segment clients.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/1.1 for playlists; segments as configured by [`HttpTuning`] (default).
+    #[default]
+    Auto,
+    /// HTTP/1.1 everywhere, for CDNs that break on HTTP/2.
+    Http1Only,
+    /// HTTP/2 without negotiation everywhere, for CDNs that only speak HTTP/2.
+    Http2PriorKnowledge,
+}
+
+/// Network settings applied to both the playlist and the segment clients.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkOptions {
+    pub ip_preference: IpPreference,
+    /// Local address to bind outgoing connections to.
+    pub local_address: Option<IpAddr>,
+    /// Network interface (e.g. `eth1`) to send all traffic through. Supported on Linux,
+    /// Android and Apple platforms.
+    pub interface: Option<String>,
+    /// Static DNS overrides (`host` -> address) used instead of resolving those hosts. They
+    /// are still filtered by `ip_preference` and `allow_private_addresses`.
+    pub dns_overrides: Vec<(String, SocketAddr)>,
+    /// Redirects to follow per request; `None` keeps reqwest's default of 10 and `Some(0)`
+    /// makes any redirect an error (useful to detect login walls).
+    pub max_redirects: Option<usize>,
+    /// `Referer` sent with requests: `Auto` sends the playlist's origin on playlist requests
+    /// only, `Omit` sends none at all and `Custom` sends the given value on every request.
+    pub referer: RefererPolicy,
+    /// HTTP version for playlist, key and segment requests; `Auto` by default.
+    pub http_version: HttpVersion,
+    /// When not empty, only these hosts are contacted for playlists, keys and segments
+    /// (including redirects), so an untrusted playlist can't point the downloader
+    /// at internal URLs. `example.com` matches that host, `*.example.com` its subdomains.
+    pub allowed_hosts: Vec<String>,
+    /// Hosts never contacted, in the same syntax; checked after `allowed_hosts`.
+    pub blocked_hosts: Vec<String>,
+    /// Allow connections to loopback, link-local and private (RFC 1918, IPv6 ULA) addresses,
+    /// which are refused by default so a playlist can't reach cloud metadata endpoints or
+    /// internal services. Set it for local servers and other trusted networks; the flat
+    /// [`hls2mp4_run`] entry point always does.
+    pub allow_private_addresses: bool,
+}
+
+/// [`NetworkOptions::allowed_hosts`] and `blocked_hosts`, checked before each request.
+#[derive(Clone, Debug, Default)]
+struct HostFilter {
+    allowed: Vec<String>,
+    blocked: Vec<String>,
+    allow_private: bool,
+}
+
+impl HostFilter {
+    fn new(network: &NetworkOptions) -> Self {
+        let lower = |hosts: &[String]| hosts.iter().map(|h| h.to_ascii_lowercase()).collect();
+        Self {
+            allowed: lower(&network.allowed_hosts),
+            blocked: lower(&network.blocked_hosts),
+            allow_private: network.allow_private_addresses,
+        }
+    }
+
+    fn allows_all(&self) -> bool {
+        self.allowed.is_empty() && self.blocked.is_empty() && self.allow_private
+    }
+
+    fn check(&self, url: &Url, what: &str) -> Result<()> {
+        if self.allows_all() {
+            return Ok(());
+        }
+        // Private addresses behind host names are caught once resolved, by the resolver.
+        let literal = match url.host() {
+            Some(url::Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+            Some(url::Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+            _ => None,
+        };
+        if let Some(ip) = literal.filter(|ip| !self.allow_private && is_private_address(*ip)) {
+            bail!("{} address {} is private; set allow_private_addresses to allow it", what, ip);
+        }
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+        let matches = |pattern: &String| match pattern.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.')),
+            None => host == *pattern,
+        };
+        if !self.allowed.is_empty() && !self.allowed.iter().any(matches) {
+            bail!("{} host {:?} is not in allowed_hosts ({})", what, host, url);
+        }
+        if self.blocked.iter().any(matches) {
+            bail!("{} host {:?} is in blocked_hosts ({})", what, host, url);
+        }
+        Ok(())
+    }
+}
+
+/// Bounds on playlist input, so an untrusted or adversarial playlist fails with an error
+/// instead of exhausting memory.
+#[derive(Clone, Debug)]
+pub struct PlaylistLimits {
+    /// Bytes of playlist text.
+    pub max_size: usize,
+    /// Bytes of a single line, such as a segment URI or an attribute list.
+    pub max_line_length: usize,
+    /// `#EXTINF` entries of a media playlist.
+    pub max_segments: usize,
+    /// `#EXT-X-STREAM-INF` and `#EXT-X-I-FRAME-STREAM-INF` entries of a master playlist.
+    pub max_variants: usize,
+}
+
+impl Default for PlaylistLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 64 * 1024 * 1024,
+            max_line_length: 64 * 1024,
+            max_segments: 200_000,
+            max_variants: 1_000,
+        }
+    }
+}
+
+/// Connection settings for the segment client. The default keeps plain HTTP/1.1 with
+/// reqwest's pool defaults.
+#[derive(Clone, Debug, Default)]
+pub struct HttpTuning {
+    /// Negotiate HTTP/2 via ALPN where the CDN offers it.
+    pub http2: bool,
+    /// Skip negotiation and speak HTTP/2 directly (the CDN must support it).
+    pub http2_prior_knowledge: bool,
+    pub http2_adaptive_window: bool,
+    /// Idle connections kept per host for reuse; unlimited when `None`. Keep it at least
+    /// `concurrency` for HTTP/1.1 CDNs, so many small segments don't each pay for a new
+    /// TCP/TLS handshake; `Some(0)` opens a connection per request.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How long an idle pooled connection is kept.
+    pub pool_idle_timeout: Option<Duration>,
+    /// Interval of TCP keep-alive probes, which stop NATs and proxies from dropping pooled
+    /// connections while they wait for the next segment.
+    pub tcp_keepalive: Option<Duration>,
+}
+
+/// FFmpeg's `-loglevel`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FfmpegLogLevel {
+    Quiet,
+    #[default]
+    Error,
+    Warning,
+    Info,
+    Verbose,
+}
+
+impl FfmpegLogLevel {
+    fn as_arg(self) -> &'static str {
+        match self {
+            FfmpegLogLevel::Quiet => "quiet",
+            FfmpegLogLevel::Error => "error",
+            FfmpegLogLevel::Warning => "warning",
+            FfmpegLogLevel::Info => "info",
+            FfmpegLogLevel::Verbose => "verbose",
+        }
+    }
+}
+
+/// What to do when the output file already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Fail before downloading anything.
+    #[default]
+    Fail,
+    Overwrite,
+    /// Write to `name_1.mp4`, `name_2.mp4`, ... instead.
+    Rename,
+}
+
+#[derive(Clone, Debug)]
+pub struct DownloadOptions {
+    /// Parallel segment downloads; 0 picks a value from the available CPU parallelism.
+    pub concurrency: usize,
+    /// Attempts per playlist and segment request; 0 is treated as 1.
+    pub retries: u8,
+    pub video_bitrate: u32,
+    pub audio_bitrate: u32,
+    pub keep_temp: bool,
+    /// Which kind of master-playlist variant to download.
+    pub stream_kind: StreamKind,
+    pub progress_mode: ProgressMode,
+    pub network: NetworkOptions,
+    pub on_event: Option<EventCallback>,
+    /// Overrides the default classification of segment responses: 200 and 206 are successes,
+    /// anything else (204 and unfollowed 3xx included) is retried.
+    pub retry_predicate: Option<RetryPredicate>,
+    pub duration_check: DurationCheck,
+    /// Allowed difference in seconds between expected and actual output duration.
+    pub duration_tolerance: f64,
+    /// When set, leftovers from earlier runs older than this are removed from the temp dir
+    /// before starting: files with the default `m3u8dl-<id>_` prefix (`seg_*.ts`,
+    /// `temp_merged.ts`, ...), including those in `variant_NN/`, which goes once emptied.
+    pub clean_stale_temp: Option<Duration>,
+    pub url_signer: Option<UrlSigner>,
+    pub key_request_handler: Option<KeyRequestHandler>,
+    /// Raw FFmpeg arguments inserted just before the output path. FFmpeg keeps the last value
+    /// of a repeated output option, so these override the codec/bitrate flags set here.
+    /// Inputs (`-i`) and progress reporting flags are rejected.
+    pub extra_ffmpeg_args: Vec<String>,
+    /// Segments read ahead concurrently while merging; bounds merge memory. 1 is sequential.
+    pub merge_buffer_segments: usize,
+    pub overwrite: OverwritePolicy,
+    /// Rebase the timestamps of each EXT-X-DISCONTINUITY group of MPEG-TS segments onto the
+    /// playlist's timeline while downloading (fetching segments in order to do so), and
+    /// regenerate PTS and ignore source DTS when converting, for merged segments whose
+    /// timestamps wrap or reset.
+    pub fix_timestamps: bool,
+    /// Only download (and decrypt) segments: `output` is the directory they are written to as
+    /// `seg_00000.ts`, ... (the extension follows the segment URIs, e.g. `.m4s`) and no merge
+    /// or conversion happens. See [`merge_and_convert`].
+    pub segments_only: bool,
+    /// Store downloaded segments zstd-compressed (`seg_00000.ts.zst`) and decompress them while
+    /// merging. Requires the `compress-temp` feature.
+    pub compress_temp: bool,
+    /// Decode on the GPU as well as encode (NVENC: `h264_cuvid`). Off by default, since
+    /// hardware decoders reject some H.264 profiles that software decoding handles.
+    pub hw_decode: bool,
+    pub variant_choice: VariantChoice,
+    /// Only download the segments covering the first this many seconds and cut the output
+    /// to that length.
+    pub clip_duration: Option<f64>,
+    /// Re-fetches of a live playlist that has no segments yet before giving up.
+    pub empty_playlist_retries: u32,
+    /// Fail when the resolved media playlist has fewer (non-gap) segments than this, e.g. a
+    /// truncated playlist standing in for content known to be long. Checked before clipping.
+    pub min_segments: Option<usize>,
+    /// Used instead of the automatically selected FFmpeg or MediaCodec backend, which is
+    /// then reported as [`TranscoderKind::Custom`].
+    pub transcoder: Option<Arc<dyn Transcoder>>,
+    /// Pause segment downloads while the temp dir has less free space than this many bytes.
+    pub min_free_space: Option<u64>,
+    /// How long to wait for space to recover before failing.
+    pub low_space_timeout: Duration,
+    pub http: HttpTuning,
+    /// Copy the streams into the output without re-encoding, regardless of bitrates.
+    /// Re-encoding also happens implicitly only when a bitrate or `scale` is set.
+    pub stream_copy: bool,
+    /// Output size as `(width, height)`; `-1` for one side keeps the aspect ratio.
+    pub scale: Option<(i32, i32)>,
+    /// Drop frames for a much smaller preview or timelapse-style output. Needs a re-encode.
+    pub frame_reduction: Option<FrameReduction>,
+    /// Resample the audio to this rate in Hz (`-ar`, e.g. 44100). Needs a re-encode.
+    pub audio_sample_rate: Option<u32>,
+    /// Output audio channels (`-ac`); 2 downmixes 5.1 to stereo. Needs a re-encode.
+    pub audio_channels: Option<u32>,
+    /// AES-128 key obtained out-of-band. When set, key URIs are never fetched and every
+    /// segment under an EXT-X-KEY other than METHOD=NONE is decrypted with this key.
+    pub decryption_key: Option<Vec<u8>>,
+    /// IV to use with `decryption_key`; falls back to the playlist's IV, then to the segment's
+    /// media sequence number, when unset.
+    pub decryption_iv: Option<Vec<u8>>,
+    /// How much FFmpeg writes to stderr (`-loglevel`); `Error` by default.
+    pub ffmpeg_log_level: FfmpegLogLevel,
+    /// Keep FFmpeg's stderr in [`DownloadResult::ffmpeg_log`] even when it succeeds.
+    pub capture_ffmpeg_log: bool,
+    /// Append the playlist URL's query to relative segment and key URIs that have none.
+    pub inherit_query: bool,
+    /// Record progress in a JSON manifest in the temp dir and, when a previous run of the
+    /// same playlist left one there, skip the segments it completed. A re-encode is also
+    /// written as `<output>.partN.mp4` parts, and a rerun with the same input and encode
+    /// settings only encodes what they don't cover yet, from the keyframe before that point
+    /// (so the seam is approximate). The parts are always joined into the output afterwards,
+    /// which writes the output twice even when nothing was interrupted. A `pipelined`
+    /// transcode isn't resumed; it starts over.
+    pub resume: bool,
+    /// fsync every segment file and the merged TS. Off by default; turning it on makes the
+    /// segments a resumed run trusts survive a crash, at the cost of much slower writes on
+    /// slow storage.
+    pub fsync: bool,
+    /// Write buffer for the merged TS, in bytes.
+    pub merge_write_buffer: usize,
+    /// Move the moov atom to the front (`-movflags +faststart`) so playback can start
+    /// before the whole file has loaded.
+    pub faststart: bool,
+    /// Write a fragmented MP4 (`+frag_keyframe+empty_moov`) for progressive streaming.
+    pub fragmented: bool,
+    /// Fetch segments of at least this many bytes as parallel byte ranges, when the server
+    /// advertises `Accept-Ranges: bytes`. Costs a HEAD request per segment; the parts share
+    /// the segment's concurrency slot.
+    pub split_threshold: Option<u64>,
+    /// Number of ranges a split segment is fetched in.
+    pub split_parts: usize,
+    /// When re-encoding without a `video_bitrate`, target the source's bitrate instead of
+    /// the encoder default.
+    pub auto_bitrate: bool,
+    /// Parallel key requests, kept apart from the segment `concurrency`.
+    pub key_concurrency: usize,
+    /// What to do with a segment that looks like an error page; warns by default.
+    pub segment_validation: SegmentValidation,
+    pub progress_unit: ProgressUnit,
+    /// Feed segments to the transcoder as they arrive instead of transcoding after the
+    /// download, so both overlap and no merged TS is written. Needs a transcoder that
+    /// [`Transcoder::accepts_stream`], like FFmpeg; a failed GPU encode isn't retried on the
+    /// CPU, and `resume` and `keep_temp` don't apply.
+    pub pipelined: bool,
+    /// Language tags in order of preference (e.g. `["en", "es"]`) for picking the audio and
+    /// subtitle renditions; `en` also matches `en-US`. Without a match the DEFAULT, then the
+    /// AUTOSELECT rendition is picked. Reported in the [`DownloadResult`].
+    pub preferred_languages: Vec<String>,
+    /// Report key URLs in [`EncryptionInfo`] with their query strings, which often carry
+    /// access tokens.
+    pub report_key_uris: bool,
+    /// Prepended to temp file names (`<prefix>seg_00000.ts`, `<prefix>temp_merged.ts`). By
+    /// default a unique `m3u8dl-<id>_` per run, so user files in the temp dir are never read
+    /// or overwritten; `Some("")` restores the bare names. Ignored for `segments_only` output.
+    pub temp_prefix: Option<String>,
+    /// Order segments are written in; as they complete (`Unordered`) by default.
+    pub download_order: DownloadOrder,
+    /// How many segments past the oldest unfinished one may be downloaded ahead in
+    /// sequential order. Defaults to `concurrency`; a larger window keeps all connections
+    /// busy behind a slow segment at the cost of buffering more in memory.
+    pub look_ahead: Option<usize>,
+    /// Append this many consecutive segments to each temp file instead of writing a file per
+    /// segment, for playlists with so many segments that the files would exhaust file
+    /// descriptors or inodes. Segments are then downloaded in playlist order. Ignored for
+    /// `segments_only` output.
+    pub segment_group_size: Option<usize>,
+    /// Codec prefixes in order of preference (e.g. `["avc1", "hvc1"]`) for choosing between
+    /// variants of equal resolution and bandwidth; otherwise the first listed one wins.
+    pub preferred_codecs: Vec<String>,
+    /// Bounds checked on every fetched playlist before it is parsed.
+    pub playlist_limits: PlaylistLimits,
+    /// URL that relative segment and key URIs of a playlist read from stdin
+    /// ([`STDIN_PLAYLIST`]) are resolved against.
+    pub base_url: Option<String>,
+    /// Global `-metadata` tags for the output, such as `("title", "...")` or
+    /// `("artist", "...")`. Keys are letters, digits, `_` and `-`.
+    pub metadata: Vec<(String, String)>,
+    /// Tag the output with a `comment` naming the source playlist (without its query string)
+    /// and the download date, unless `metadata` already sets one.
+    pub tag_source: bool,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 8,
+            retries: 3,
+            video_bitrate: 0,
+            audio_bitrate: 0,
+            keep_temp: false,
+            stream_kind: StreamKind::Main,
+            progress_mode: ProgressMode::Auto,
+            network: NetworkOptions::default(),
+            on_event: None,
+            retry_predicate: None,
+            duration_check: DurationCheck::Warn,
+            duration_tolerance: 2.0,
+            clean_stale_temp: None,
+            url_signer: None,
+            key_request_handler: None,
+            extra_ffmpeg_args: Vec::new(),
+            merge_buffer_segments: 2,
+            overwrite: OverwritePolicy::Fail,
+            fix_timestamps: true,
+            segments_only: false,
+            compress_temp: false,
+            hw_decode: false,
+            variant_choice: VariantChoice::Best,
+            clip_duration: None,
+            empty_playlist_retries: 5,
+            min_segments: None,
+            transcoder: None,
+            min_free_space: None,
+            low_space_timeout: Duration::from_secs(300),
+            http: HttpTuning::default(),
+            stream_copy: false,
+            scale: None,
+            frame_reduction: None,
+            audio_sample_rate: None,
+            audio_channels: None,
+            decryption_key: None,
+            decryption_iv: None,
+            ffmpeg_log_level: FfmpegLogLevel::Error,
+            capture_ffmpeg_log: false,
+            inherit_query: false,
+            resume: false,
+            fsync: false,
+            merge_write_buffer: 256 * 1024,
+            faststart: false,
+            fragmented: false,
+            split_threshold: None,
+            split_parts: 4,
+            auto_bitrate: false,
+            key_concurrency: 2,
+            segment_validation: SegmentValidation::Warn,
+            progress_unit: ProgressUnit::Auto,
+            pipelined: false,
+            preferred_languages: Vec::new(),
+            report_key_uris: false,
+            temp_prefix: None,
+            download_order: DownloadOrder::Unordered,
+            look_ahead: None,
+            segment_group_size: None,
+            preferred_codecs: Vec::new(),
+            playlist_limits: PlaylistLimits::default(),
+            base_url: None,
+            metadata: Vec::new(),
+            tag_source: false,
+        }
+    }
+}
+
+/// Chainable setters for the most common options, e.g.
+/// `DownloadOptions::default().concurrency(16).retries(5)`. Everything else is set with
+/// struct update syntax (`DownloadOptions { resume: true, ..options }`).
+impl DownloadOptions {
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn retries(mut self, retries: u8) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Video bitrate in kbps; 0 keeps the source's (or the encoder default when re-encoding).
+    pub fn video_bitrate(mut self, kbps: u32) -> Self {
+        self.video_bitrate = kbps;
+        self
+    }
+
+    /// Audio bitrate in kbps; 0 keeps the source's.
+    pub fn audio_bitrate(mut self, kbps: u32) -> Self {
+        self.audio_bitrate = kbps;
+        self
+    }
+
+    pub fn keep_temp(mut self, keep_temp: bool) -> Self {
+        self.keep_temp = keep_temp;
+        self
+    }
+
+    pub fn progress_mode(mut self, mode: ProgressMode) -> Self {
+        self.progress_mode = mode;
+        self
+    }
+
+    pub fn overwrite(mut self, policy: OverwritePolicy) -> Self {
+        self.overwrite = policy;
+        self
+    }
+
+    pub fn network(mut self, network: NetworkOptions) -> Self {
+        self.network = network;
+        self
+    }
+
+    pub fn on_event(mut self, callback: impl Fn(&DownloadEvent) + Send + Sync + 'static) -> Self {
+        self.on_event = Some(EventCallback(Arc::new(callback)));
+        self
+    }
+}
+
+/// DNS resolver that drops addresses not matching the configured [`IpPreference`] and,
+/// unless `allow_private`, private ones.
+struct FilteringResolver {
+    preference: IpPreference,
+    allow_private: bool,
+    /// [`NetworkOptions::dns_overrides`] by lowercase host, used instead of a lookup.
+    overrides: HashMap<String, Vec<SocketAddr>>,
+}
+
+impl Resolve for FilteringResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let preference = self.preference;
+        let allow_private = self.allow_private;
+        let overridden = self.overrides.get(&name.as_str().to_ascii_lowercase()).cloned();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let candidates: Vec<SocketAddr> = match overridden {
+                Some(addrs) => addrs,
+                None => tokio::net::lookup_host((host.as_str(), 0)).await?.collect(),
+            };
+            let resolved: Vec<SocketAddr> =
+                candidates.into_iter().filter(|addr| preference.allows(addr.ip())).collect();
+            if resolved.is_empty() {
+                return Err(format!("No {:?} address found for {}", preference, host).into());
+            }
+            let (addrs, private): (Vec<SocketAddr>, Vec<SocketAddr>) = resolved
+                .into_iter()
+                .partition(|addr| allow_private || !is_private_address(addr.ip()));
+            if addrs.is_empty() {
+                return Err(format!(
+                    "{} resolves to private address {}; set allow_private_addresses to allow it",
+                    host,
+                    private[0].ip()
+                )
+                .into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Loopback, link-local, private (RFC 1918, IPv6 unique local), carrier-grade NAT
+/// (100.64.0.0/10) and "this network" (0.0.0.0/8) addresses, including IPv4 ones mapped into
+/// IPv6 or embedded in a NAT64 address (64:ff9b::/96).
+fn is_private_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || a == 0
+                || (a == 100 && b & 0xc0 == 64)
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_private_address(IpAddr::V4(v4)),
+            None if v6.segments()[..6] == [0x64, 0xff9b, 0, 0, 0, 0] => {
+                let [.., a, b, c, d] = v6.octets();
+                is_private_address(IpAddr::from([a, b, c, d]))
+            }
+            None => {
+                v6.is_loopback()
+                    || v6.is_unique_local()
+                    || v6.is_unicast_link_local()
+                    || v6.is_unspecified()
+            }
+        },
+    }
+}
+
+fn apply_network_options(
+    mut builder: ClientBuilder,
+    network: &NetworkOptions,
+) -> Result<ClientBuilder> {
+    match network.http_version {
+        HttpVersion::Auto => {}
+        HttpVersion::Http1Only => builder = builder.http1_only(),
+        HttpVersion::Http2PriorKnowledge => builder = builder.http2_prior_knowledge(),
+    }
+    if let Some(addr) = network.local_address {
+        if !network.ip_preference.allows(addr) {
+            bail!(
+                "Local address {} conflicts with IP preference {:?}",
+                addr,
+                network.ip_preference
+            );
+        }
+        // Fail here rather than with a connect error on the first request.
+        std::net::UdpSocket::bind((addr, 0))
+            .with_context(|| format!("Local address {} is not usable on this machine", addr))?;
+        builder = builder.local_address(addr);
+    }
+
+    if let Some(interface) = &network.interface {
+        #[cfg(any(
+            target_os = "android",
+            target_os = "fuchsia",
+            target_os = "illumos",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "solaris",
+            target_os = "tvos",
+            target_os = "visionos",
+            target_os = "watchos",
+        ))]
+        {
+            builder = builder.interface(interface);
+        }
+        #[cfg(not(any(
+            target_os = "android",
+            target_os = "fuchsia",
+            target_os = "illumos",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "solaris",
+            target_os = "tvos",
+            target_os = "visionos",
+            target_os = "watchos",
+        )))]
+        bail!("Binding to interface {} is not supported on this platform", interface);
+    }
+
+    if network.referer == RefererPolicy::Omit {
+        builder = builder.referer(false);
+    }
+
+    let filtered = network.ip_preference != IpPreference::Auto || !network.allow_private_addresses;
+    if filtered || !network.dns_overrides.is_empty() {
+        // Overrides go through the resolver too, so they are filtered like looked-up addresses.
+        let mut overrides: HashMap<String, Vec<SocketAddr>> = HashMap::new();
+        for (host, addr) in &network.dns_overrides {
+            overrides.entry(host.to_ascii_lowercase()).or_default().push(*addr);
+        }
+        builder = builder.dns_resolver(Arc::new(FilteringResolver {
+            preference: network.ip_preference,
+            allow_private: network.allow_private_addresses,
+            overrides,
+        }));
+    }
+
+    let hosts = HostFilter::new(network);
+    match network.max_redirects {
+        Some(0) => builder = builder.redirect(reqwest::redirect::Policy::none()),
+        Some(max) if hosts.allows_all() => {
+            builder = builder.redirect(reqwest::redirect::Policy::limited(max))
+        }
+        None if hosts.allows_all() => {}
+        max => {
+            // Same limit as `Policy::limited`, plus the host check on every hop.
+            let max = max.unwrap_or(10);
+            builder = builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() > max {
+                    return attempt.error(format!("Exceeded {} redirects", max));
+                }
+                match hosts.check(attempt.url(), "Redirect") {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(e.to_string()),
+                }
+            }));
+        }
+    }
+
+    Ok(builder)
+}
+
+#[cfg(target_os = "android")]
+static ANDROID_HW_TRANSCODER: OnceLock<Arc<AndroidMediaCodecTranscoder>> = OnceLock::new();
+
+#[cfg(target_os = "android")]
+static ANDROID_CONTEXT: OnceLock<AndroidContextData> = OnceLock::new();
+
+/// Cached MediaTranscoder class reference (must be cached from main thread with app classloader)
+#[cfg(target_os = "android")]
+static MEDIA_TRANSCODER_CLASS: OnceLock<GlobalRef> = OnceLock::new();
+
+#[cfg(target_os = "android")]
+pub struct AndroidMediaCodecTranscoder {
+    jvm: Arc<JavaVM>,
+}
+
+#[cfg(target_os = "android")]
+impl AndroidMediaCodecTranscoder {
+    pub fn new(jvm: Arc<JavaVM>) -> Self {
+        Self { jvm }
+    }
+
+    pub async fn transcode(
+        &self,
+        input_ts: &str,
+        output_mp4: &str,
+        video_bitrate: u32,
+        audio_bitrate: u32,
+    ) -> Result<()> {
+        let jvm = self.jvm.clone();
+        let input_ts = input_ts.to_string();
+        let output_mp4 = output_mp4.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let mut env = jvm
+                .attach_current_thread()
+                .map_err(|e| anyhow!("JNI attach thread failed: {}", e))?;
+
+            // Try to get cached class first, otherwise load it using app ClassLoader
+            let class: JClass = if let Some(class_ref) = MEDIA_TRANSCODER_CLASS.get() {
+                // SAFETY: GlobalRef was created from a JClass, so it's safe to cast back
+                unsafe { JClass::from_raw(class_ref.as_obj().as_raw()) }
+            } else {
+                // Load class using app context's ClassLoader (works from any thread)
+                let ctx = get_android_context()
+                    .map_err(|e| anyhow!("Failed to get Android context: {}", e))?;
+                
+                // Get ClassLoader from app context
+                let class_loader = env
+                    .call_method(ctx.app_context.as_obj(), "getClassLoader", "()Ljava/lang/ClassLoader;", &[])
+                    .map_err(|e| anyhow!("Failed to get ClassLoader: {:?}", e))?
+                    .l()
+                    .map_err(|e| anyhow!("ClassLoader is not an object: {:?}", e))?;
+                
+                // Use ClassLoader.loadClass() to load MediaTranscoder
+                let class_name = env
+                    .new_string("com.bluevale.m3u8_downloader.MediaTranscoder")
+                    .map_err(|e| anyhow!("Failed to create class name string: {:?}", e))?;
+                
+                let loaded_class = env
+                    .call_method(
+                        &class_loader,
+                        "loadClass",
+                        "(Ljava/lang/String;)Ljava/lang/Class;",
+                        &[JValue::Object(&class_name)],
+                    )
+                    .map_err(|e| anyhow!("Failed to load MediaTranscoder class: {:?}", e))?
+                    .l()
+                    .map_err(|e| anyhow!("loadClass did not return a Class: {:?}", e))?;
+                
+                info!("✅ MediaTranscoder class loaded via ClassLoader");
+                
+                // Cache the class for future use
+                if let Ok(global_ref) = env.new_global_ref(&loaded_class) {
+                    let _ = MEDIA_TRANSCODER_CLASS.set(global_ref);
+                }
+                
+                // SAFETY: loaded_class is a java.lang.Class object
+                unsafe { JClass::from_raw(loaded_class.as_raw()) }
+            };
+
+            let input_ts_jstring = env
+                .new_string(&input_ts)
+                .map_err(|e| anyhow!("JNI new_string failed: {}", e))?;
+
+            let output_mp4_jstring = env
+                .new_string(&output_mp4)
+                .map_err(|e| anyhow!("JNI new_string failed: {}", e))?;
+
+            let result = env
+                .call_static_method(
+                    class,
+                    "transcode",
+                    "(Ljava/lang/String;Ljava/lang/String;II)Z",
+                    &[
+                        JValue::Object(&input_ts_jstring),
+                        JValue::Object(&output_mp4_jstring),
+                        JValue::Int(video_bitrate as i32),
+                        JValue::Int(audio_bitrate as i32),
+                    ],
+                )
+                .map_err(|e| anyhow!("JNI call_static_method failed: {}", e))?;
+
+            let success = result
+                .z()
+                .map_err(|e| anyhow!("JNI get boolean return failed: {}", e))?;
+
+            if success {
+                Ok(())
+            } else {
+                Err(anyhow!("Java MediaCodec transcode failed"))
+            }
+        })
+        .await
+        .map_err(|e| anyhow!("tokio spawn_blocking failed: {}", e))?
+    }
+}
+
+#[cfg(target_os = "android")]
+pub fn register_android_mediacodec_transcoder(jvm: Arc<JavaVM>) -> Result<()> {
+    if ANDROID_HW_TRANSCODER.get().is_some() {
+        info!("Android MediaCodec transcoder already registered");
+        return Ok(());
+    }
+
+    let transcoder = AndroidMediaCodecTranscoder::new(jvm);
+    ANDROID_HW_TRANSCODER
+        .set(Arc::new(transcoder))
+        .map_err(|_| anyhow!("Failed to register Android MediaCodec transcoder"))?;
+
+    info!("鉁� Android MediaCodec transcoder registered");
+    Ok(())
+}
+
+#[cfg(target_os = "android")]
+struct AndroidContextData {
+    jvm: Arc<JavaVM>,
+    app_context: GlobalRef,
+}
+
+#[cfg(target_os = "android")]
+pub fn init_android_context(jvm: Arc<JavaVM>, context: GlobalRef) -> Result<()> {
+    let data = AndroidContextData {
+        jvm,
+        app_context: context,
+    };
+
+    ANDROID_CONTEXT
+        .set(data)
+        .map_err(|_| anyhow!("Android Context already initialized"))?;
+
+    info!("Android context initialized");
+    Ok(())
+}
+
+#[cfg(target_os = "android")]
+fn get_android_context() -> Result<&'static AndroidContextData> {
+    ANDROID_CONTEXT
+        .get()
+        .ok_or_else(|| anyhow!("Android Context not initialized; call init_android_context()"))
+}
+
+#[cfg(target_os = "android")]
+fn verify_directory_writable(path: &PathBuf) -> bool {
+    if !path.exists() {
+        if let Err(e) = std::fs::create_dir_all(&path) {
+            warn!("Failed to create directory {}: {}", path.display(), e);
+            return false;
+        }
+    }
+
+    if !path.is_dir() {
+        warn!("Path exists but is not a directory: {}", path.display());
+        return false;
+    }
+
+    let test_file = path.join(".writable_test_temp");
+    match std::fs::write(&test_file, b"test") {
+        Ok(_) => {
+            if let Err(e) = std::fs::remove_file(&test_file) {
+                warn!("Failed to remove test file: {}", e);
+            }
+            info!("Directory is writable: {}", path.display());
+            true
+        }
+        Err(e) => {
+            warn!(
+                "Directory {} is not writable: {} (OS Error: {})",
+                path.display(),
+                e,
+                e.raw_os_error().unwrap_or(0)
+            );
+            false
+        }
+    }
+}
+
+#[cfg(target_os = "android")]
+pub fn get_app_cache_dir() -> Result<PathBuf> {
+    let ctx_data = get_android_context()?;
+    let mut env = ctx_data
+        .jvm
+        .attach_current_thread()
+        .map_err(|e| anyhow!("JNI attach thread failed: {}", e))?;
+
+    let context_obj = ctx_data.app_context.as_obj();
+
+    let cache_dir_obj = env
+        .call_method(context_obj, "getCacheDir", "()Ljava/io/File;", &[])
+        .map_err(|e| anyhow!("JNI getCacheDir call failed: {}", e))?
+        .l()
+        .map_err(|e| anyhow!("JNI getCacheDir returned invalid object: {}", e))?;
+
+    let path_str = env
+        .call_method(
+            &cache_dir_obj,
+            "getAbsolutePath",
+            "()Ljava/lang/String;",
+            &[],
+        )
+        .map_err(|e| anyhow!("JNI getAbsolutePath call failed: {}", e))?
+        .l()
+        .map_err(|e| anyhow!("JNI getAbsolutePath returned invalid object: {}", e))?;
+
+    let path_jstring = env
+        .get_string((&path_str).into())
+        .map_err(|e| anyhow!("JNI string conversion failed: {}", e))?;
+
+    Ok(PathBuf::from(path_jstring.to_string_lossy().to_string()))
+}
+
+#[cfg(target_os = "android")]
+pub fn get_app_files_dir() -> Result<PathBuf> {
+    let ctx_data = get_android_context()?;
+    let mut env = ctx_data
+        .jvm
+        .attach_current_thread()
+        .map_err(|e| anyhow!("JNI attach thread failed: {}", e))?;
+
+    let context_obj = ctx_data.app_context.as_obj();
+
+    let files_dir_obj = env
+        .call_method(context_obj, "getFilesDir", "()Ljava/io/File;", &[])
+        .map_err(|e| anyhow!("JNI getFilesDir call failed: {}", e))?
+        .l()
+        .map_err(|e| anyhow!("JNI getFilesDir returned invalid object: {}", e))?;
+
+    let path_str = env
+        .call_method(
+            &files_dir_obj,
+            "getAbsolutePath",
+            "()Ljava/lang/String;",
+            &[],
+        )
+        .map_err(|e| anyhow!("JNI getAbsolutePath call failed: {}", e))?
+        .l()
+        .map_err(|e| anyhow!("JNI getAbsolutePath returned invalid object: {}", e))?;
+
+    let path_jstring = env
+        .get_string((&path_str).into())
+        .map_err(|e| anyhow!("JNI string conversion failed: {}", e))?;
+
+    Ok(PathBuf::from(path_jstring.to_string_lossy().to_string()))
+}
+
+#[cfg(target_os = "android")]
+pub fn get_external_files_dir() -> Result<PathBuf> {
+    let ctx_data = get_android_context()?;
+    let mut env = ctx_data
+        .jvm
+        .attach_current_thread()
+        .map_err(|e| anyhow!("JNI attach thread failed: {}", e))?;
+
+    let context_obj = ctx_data.app_context.as_obj();
+
+    let ext_files_call = env.call_method(
+        context_obj,
+        "getExternalFilesDir",
+        "(Ljava/lang/String;)Ljava/io/File;",
+        &[JValue::Object(&JObject::null())],
+    );
+
+    let ext_files_obj = match ext_files_call {
+        Ok(v) => v
+            .l()
+            .map_err(|e| anyhow!("JNI getExternalFilesDir returned invalid object: {}", e))?,
+        Err(e) => {
+            warn!("JNI getExternalFilesDir call failed: {}", e);
+            return get_app_files_dir();
+        }
+    };
+
+    if ext_files_obj.is_null() {
+        warn!("getExternalFilesDir returned null, falling back to app files dir");
+        return get_app_files_dir();
+    }
+
+    let path_str = env
+        .call_method(
+            &ext_files_obj,
+            "getAbsolutePath",
+            "()Ljava/lang/String;",
+            &[],
+        )
         .map_err(|e| anyhow!("JNI getAbsolutePath call failed: {}", e))?
         .l()
         .map_err(|e| anyhow!("JNI getAbsolutePath returned invalid object: {}", e))?;
 
-    let path_jstring = env
-        .get_string((&path_str).into())
-        .map_err(|e| anyhow!("JNI string conversion failed: {}", e))?;
+    let path_jstring = env
+        .get_string((&path_str).into())
+        .map_err(|e| anyhow!("JNI string conversion failed: {}", e))?;
+
+    Ok(PathBuf::from(path_jstring.to_string_lossy().to_string()))
+}
+
+#[cfg(target_os = "android")]
+fn select_writable_temp_dir() -> Result<PathBuf> {
+    info!("Selecting writable temporary directory");
+
+    let candidates = vec![
+        ("app_cache", get_app_cache_dir()),
+        ("app_files", get_app_files_dir()),
+        ("external_files", get_external_files_dir()),
+    ];
+
+    for (name, result) in candidates {
+        match result {
+            Ok(dir) => {
+                info!("Trying candidate [{}]: {}", name, dir.display());
+                if verify_directory_writable(&dir) {
+                    info!("Selected writable temporary directory: {}", dir.display());
+                    return Ok(dir);
+                } else {
+                    warn!("Directory not writable: {} ({})", dir.display(), name);
+                }
+            }
+            Err(e) => {
+                warn!("Failed to get {}: {}", name, e);
+            }
+        }
+    }
+
+    let env_temp = env::temp_dir();
+    info!("Trying env::temp_dir(): {}", env_temp.display());
+    if verify_directory_writable(&env_temp) {
+        return Ok(env_temp);
+    }
+
+    let data_local_tmp = PathBuf::from("/data/local/tmp");
+    info!("Trying /data/local/tmp");
+    if verify_directory_writable(&data_local_tmp) {
+        return Ok(data_local_tmp);
+    }
+
+    let current = PathBuf::from(".");
+    info!("Trying current directory");
+    if verify_directory_writable(&current) {
+        return Ok(current);
+    }
+
+    bail!(
+        "No writable temporary directory found. Ensure init_android_context() was called with an Application context and that storage is configured."
+    )
+}
+
+#[flutter_rust_bridge::frb()]
+pub async fn hls2mp4_run(
+    sink: StreamSink<ProgressUpdate>,
+    url: String,
+    concurrency: i32,
+    output: String,
+    retries: i32,
+    video_bitrate: i32,
+    audio_bitrate: i32,
+    keep_temp: bool,
+) -> Result<()> {
+    let options = DownloadOptions::default()
+        .concurrency(concurrency.max(0) as usize)
+        .retries(retries.max(1) as u8)
+        .video_bitrate(video_bitrate.max(0) as u32)
+        .audio_bitrate(audio_bitrate.max(0) as u32)
+        .keep_temp(keep_temp)
+        // The app downloads from LAN and localhost servers; private address blocking is
+        // opted into through `DownloadOptions` by callers handling untrusted playlists.
+        .network(NetworkOptions { allow_private_addresses: true, ..Default::default() });
+
+    hls2mp4_run_with_options(Some(sink), url, output, options).await?;
+    Ok(())
+}
+
+/// Downloads `url` to `output` as configured by `options`; progress goes to
+/// `options.on_event` and the terminal. [`hls2mp4_run`] is the flat FFI form of this.
+#[flutter_rust_bridge::frb(ignore)]
+pub async fn run(url: String, output: String, options: DownloadOptions) -> Result<DownloadResult> {
+    hls2mp4_run_with_options(None, url, output, options).await
+}
+
+#[flutter_rust_bridge::frb(ignore)]
+pub async fn hls2mp4_run_with_options(
+    sink: Option<StreamSink<ProgressUpdate>>,
+    url: String,
+    output: String,
+    options: DownloadOptions,
+) -> Result<DownloadResult> {
+    hls2mp4_run_mirrors(sink, vec![url], output, options).await
+}
+
+/// Like [`hls2mp4_run_with_options`], but tries each mirror URL in order until one yields a
+/// parseable playlist. Segments and keys are then resolved against the mirror that succeeded.
+#[flutter_rust_bridge::frb(ignore)]
+pub async fn hls2mp4_run_mirrors(
+    sink: Option<StreamSink<ProgressUpdate>>,
+    urls: Vec<String>,
+    output: String,
+    options: DownloadOptions,
+) -> Result<DownloadResult> {
+    run_pipeline(sink, PlaylistSource::Urls(urls), output, options, false)
+        .await?
+        .pop()
+        .ok_or_else(|| anyhow!("Run produced no output"))
+}
+
+/// Downloads every variant of `options.stream_kind` from a master playlist, producing one
+/// output per variant (suffixed with its resolution). Variants share one HTTP client and
+/// one `concurrency` budget. A media playlist URL yields a single result.
+#[flutter_rust_bridge::frb(ignore)]
+pub async fn hls2mp4_run_all_variants(
+    sink: Option<StreamSink<ProgressUpdate>>,
+    url: String,
+    output: String,
+    options: DownloadOptions,
+) -> Result<Vec<DownloadResult>> {
+    run_pipeline(sink, PlaylistSource::Urls(vec![url]), output, options, true).await
+}
+
+/// Quick low-bandwidth look at a stream: the lowest-bandwidth variant, only the first
+/// `seconds`, remuxed without re-encoding. The rest of `options` (network settings and the
+/// like) applies as given.
+#[flutter_rust_bridge::frb(ignore)]
+pub async fn preview(
+    url: String,
+    seconds: f64,
+    output: String,
+    options: DownloadOptions,
+) -> Result<DownloadResult> {
+    if seconds.is_nan() || seconds <= 0.0 {
+        bail!("Preview length must be positive, got {}", seconds);
+    }
+    let options = DownloadOptions {
+        variant_choice: VariantChoice::Lowest,
+        clip_duration: Some(seconds),
+        overwrite: OverwritePolicy::Overwrite,
+        ..options
+    };
+    hls2mp4_run_with_options(None, url, output, options).await
+}
+
+/// Runs the pipeline on playlist text the caller already fetched (e.g. extracted from an API
+/// response). `base_url` is the playlist's own URL and resolves relative segment and key URIs;
+/// without it every URI in the playlist must be absolute.
+#[flutter_rust_bridge::frb(ignore)]
+pub async fn hls2mp4_run_from_content(
+    sink: Option<StreamSink<ProgressUpdate>>,
+    playlist_text: String,
+    base_url: Option<String>,
+    output: String,
+    options: DownloadOptions,
+) -> Result<DownloadResult> {
+    let source = PlaylistSource::Content {
+        text: playlist_text,
+        base_url,
+    };
+    run_pipeline(sink, source, output, options, false)
+        .await?
+        .pop()
+        .ok_or_else(|| anyhow!("Run produced no output"))
+}
+
+/// Merges pre-downloaded segment files and converts them, without any network access.
+/// `pattern` selects files in `segment_dir` (e.g. `seg_*.ts`, the layout written by
+/// `segments_only`); `key` is an AES-128 key and IV when the files are still encrypted, in
+/// which case uncompressed files are decrypted a chunk at a time.
+#[flutter_rust_bridge::frb(ignore)]
+pub async fn merge_and_convert(
+    segment_dir: String,
+    pattern: String,
+    output: String,
+    key: Option<(Vec<u8>, Vec<u8>)>,
+    mut options: DownloadOptions,
+) -> Result<DownloadResult> {
+    prepare_stdout_output(&output, &mut options)?;
+    let reporter = Reporter::new(options.progress_mode, None, options.on_event.clone());
+    init_logging(options.progress_mode);
+    validate_transcode_options(&options)?;
+    let output = resolve_output_path(output, options.overwrite)?;
+    let progress = TerminalProgress::new(options.progress_mode);
+    let (_, transcoder) = select_backend(&options).await?;
+
+    let segment_dir = PathBuf::from(segment_dir);
+    let paths = list_segment_files(&segment_dir, &pattern).await?;
+    if paths.is_empty() {
+        bail!("No files matching {} in {}", pattern, segment_dir.display());
+    }
+    info!("Merging {} segments from {}", paths.len(), segment_dir.display());
+
+    let prefix = options.temp_prefix.clone().unwrap_or_else(unique_temp_prefix);
+    let temp_ts = segment_dir.join(format!("{}temp_merged.ts", prefix));
+    let temp_ts_str = temp_ts.to_string_lossy().to_string();
+
+    let merge_clock = reporter.phase_started(Phase::Merge);
+    merge_segments(paths, &temp_ts_str, key, false, &options, &progress)
+    .await?;
+    reporter.phase_ended(Phase::Merge, merge_clock);
+
+    let transcode_clock = reporter.phase_started(Phase::Transcode);
+    let (transcode_options, auto_video_bitrate) =
+        with_auto_bitrate(&options, &temp_ts_str, None).await;
+    let (transcoder, ffmpeg_log) = convert_to_mp4(
+        &temp_ts_str,
+        &output,
+        &transcode_options,
+        None,
+        &progress,
+        transcoder.as_ref(),
+        &reporter,
+    )
+    .await?;
+    reporter.phase_ended(Phase::Transcode, transcode_clock);
+
+    let merged_ts_path = if options.keep_temp {
+        Some(temp_ts_str)
+    } else {
+        let _ = fs::remove_file(&temp_ts_str).await;
+        None
+    };
+
+    let result = DownloadResult {
+        output_path: output,
+        phase_timings: reporter.phase_timings(),
+        transcoder: Some(transcoder),
+        merged_ts_path,
+        ffmpeg_log,
+        auto_video_bitrate,
+        ..Default::default()
+    };
+    reporter.result(&result);
+    Ok(result)
+}
+
+/// Downloads a playlist's segments and yields their decrypted bytes in playlist order as they
+/// complete, for consumers that write them somewhere other than an MP4 (a custom muxer, the
+/// network, live playback). A master playlist is resolved to one variant by `stream_kind`
+/// and `variant_choice`; nothing is transcoded or written to disk. EXT-X-GAP segments are
+/// skipped, so indices can jump.
+#[flutter_rust_bridge::frb(ignore)]
+pub async fn segment_stream(
+    url: String,
+    mut options: DownloadOptions,
+) -> Result<impl Stream<Item = Result<(usize, Vec<u8>)>> + Send> {
+    validate_decryption_override(&options)?;
+    init_logging(options.progress_mode);
+    options.concurrency = resolve_concurrency(options.concurrency);
+
+    let (url, playlist) = resolve_media_playlist(url, &options).await?;
+    let mut playlist = wait_for_segments(playlist, &url, &options).await?;
+    if let Some(clip) = options.clip_duration {
+        truncate_to_duration(&mut playlist, clip);
+    }
+
+    let client = Arc::new(create_http_client(&options.network, &options.http)?);
+    let base_url = playlist_base_url(&url)?;
+    let keys = KeyCache::new(options.key_concurrency);
+    let jobs =
+        segment_jobs(&playlist, base_url.as_ref(), &keys, &client, &options).await?;
+    let reporter = Reporter::new(options.progress_mode, None, options.on_event.clone());
+    let fetcher = Arc::new(SegmentFetcher::new(
+        client,
+        Arc::new(Semaphore::new(options.concurrency)),
+        &options,
+        None,
+        reporter,
+        ProgressBar::hidden(),
+    ));
+    Ok(fetch_segments(fetcher, jobs, &options, true))
+}
+
+/// Fetches `url` and, for a master playlist, the variant picked by `stream_kind` and
+/// `variant_choice`. Returns the media playlist with the URL it was finally fetched from.
+async fn resolve_media_playlist(
+    url: String,
+    options: &DownloadOptions,
+) -> Result<(String, m3u8_rs::MediaPlaylist)> {
+    let (url, playlist) = fetch_first_playlist(&[url], options).await?;
+    match playlist {
+        Playlist::MediaPlaylist(mp) => Ok((url, mp)),
+        Playlist::MasterPlaylist(master) => {
+            let variant = select_variant(&collect_variants(&master), options)?;
+            let base_url = playlist_base_url(&url)?;
+            let (final_url, mp) =
+                fetch_media_playlist(base_url.as_ref(), &variant.uri, options).await?;
+            Ok((final_url.to_string(), mp))
+        }
+    }
+}
+
+/// Master playlists followed through variant URIs before giving up, in case they loop.
+const MAX_MASTER_DEPTH: usize = 5;
+
+/// Fetches the media playlist `variant_uri` refers to. When it is another master playlist
+/// (a master of per-CDN or per-region masters), a variant is picked from that one by
+/// `stream_kind` and `variant_choice` and followed, up to [`MAX_MASTER_DEPTH`] levels.
+/// Returns the URL the media playlist was finally served from.
+async fn fetch_media_playlist(
+    base_url: Option<&Url>,
+    variant_uri: &str,
+    options: &DownloadOptions,
+) -> Result<(Url, m3u8_rs::MediaPlaylist)> {
+    let hosts = HostFilter::new(&options.network);
+    let mut media_url = resolve_uri(base_url, variant_uri, "Variant")?;
+    for _ in 0..MAX_MASTER_DEPTH {
+        hosts.check(&media_url, "Variant playlist")?;
+        let (content, final_url) = download_playlist(media_url.as_str(), options).await?;
+        let master = match parse_m3u8(&content, &options.playlist_limits)? {
+            Playlist::MediaPlaylist(mp) => return Ok((final_url, mp)),
+            Playlist::MasterPlaylist(master) => master,
+        };
+        let variants = collect_variants(&master);
+        let variant = select_variant(&variants, options)?;
+        info!("{} is a nested master playlist, following {}", final_url, variant.uri);
+        let nested_base_url = playlist_base_url(final_url.as_str())?;
+        media_url = resolve_uri(nested_base_url.as_ref(), &variant.uri, "Variant")?;
+    }
+    bail!("Master playlists nested more than {} levels deep", MAX_MASTER_DEPTH)
+}
+
+/// Health check for a playlist link: resolves the media playlist, every segment URL and
+/// every key the way a download would, without fetching any segment. Unlike [`inspect`],
+/// a problem is reported as a failure rather than skipped; only an invalid `options` is an
+/// `Err`.
+#[flutter_rust_bridge::frb(ignore)]
+pub async fn validate(url: String, options: DownloadOptions) -> Result<ValidationReport> {
+    validate_decryption_override(&options)?;
+    let mut report = ValidationReport::default();
+    let (url, playlist) = match resolve_media_playlist(url, &options).await {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            report.errors.push(format!("{:#}", e));
+            return Ok(report);
+        }
+    };
+    report.media_url = Some(url.clone());
+    report.segment_count = playlist.segments.len();
+    if playlist.segments.is_empty() {
+        report.errors.push("Playlist has no segments".to_string());
+    }
+
+    let checked = async {
+        let base_url = playlist_base_url(&url)?;
+        report.encryption = encryption_info(&playlist, base_url.as_ref(), &options);
+        let client = create_http_client(&options.network, &options.http)?;
+        let keys = KeyCache::new(options.key_concurrency);
+        segment_jobs(&playlist, base_url.as_ref(), &keys, &client, &options).await
+    };
+    if let Err(e) = checked.await {
+        report.errors.push(format!("{:#}", e));
+    }
+    report.passed = report.errors.is_empty();
+    Ok(report)
+}
+
+/// Playlist URL that reads the playlist text from stdin instead, e.g. `curl ... | app -`.
+/// Relative URIs in it need `base_url`.
+pub const STDIN_PLAYLIST: &str = "-";
+
+/// `output` that streams the MP4 to stdout instead of writing a file, e.g. to pipe it into
+/// `ffplay -`.
+pub const STDOUT_OUTPUT: &str = "-";
+
+/// Adjusts `options` for writing to stdout: a pipe can't be seeked back into, so the MP4 is
+/// fragmented, and progress must stay off stdout.
+fn prepare_stdout_output(output: &str, options: &mut DownloadOptions) -> Result<()> {
+    if output != STDOUT_OUTPUT {
+        return Ok(());
+    }
+    if options.faststart {
+        bail!("faststart rewrites the finished file and can't be used when writing to stdout");
+    }
+    if options.segments_only {
+        bail!("segments_only writes files and can't be used when writing to stdout");
+    }
+    match options.progress_mode {
+        ProgressMode::Json => bail!("JSON progress is written to stdout; pick another mode"),
+        ProgressMode::Auto | ProgressMode::Bars => options.progress_mode = ProgressMode::Plain,
+        ProgressMode::Plain | ProgressMode::Quiet | ProgressMode::CallbackOnly => {}
+    }
+    options.fragmented = true;
+    Ok(())
+}
+
+fn init_logging(mode: ProgressMode) {
+    #[cfg(target_os = "android")]
+    android_logger::init_once(
+        android_logger::Config::default().with_max_level(log::LevelFilter::Info),
+    );
+
+    #[cfg(not(target_os = "android"))]
+    if mode != ProgressMode::Quiet {
+        env_logger::Builder::from_default_env()
+            .filter_level(log::LevelFilter::Info)
+            .try_init()
+            .ok();
+    }
+}
+
+/// Where the top-level playlist comes from.
+enum PlaylistSource {
+    /// Mirror URLs, tried in order.
+    Urls(Vec<String>),
+    /// Playlist text supplied by the caller, with the URL it was served from.
+    Content {
+        text: String,
+        base_url: Option<String>,
+    },
+}
+
+async fn run_pipeline(
+    sink: Option<StreamSink<ProgressUpdate>>,
+    source: PlaylistSource,
+    output: String,
+    mut options: DownloadOptions,
+    all_variants: bool,
+) -> Result<Vec<DownloadResult>> {
+    prepare_stdout_output(&output, &mut options)?;
+    if all_variants && output == STDOUT_OUTPUT {
+        bail!("Downloading all variants writes several files and can't write to stdout");
+    }
+    let reporter = Reporter::new(options.progress_mode, sink, options.on_event.clone());
+    reporter.progress("Initializing...", 0.0);
+
+    validate_transcode_options(&options)?;
+    validate_decryption_override(&options)?;
+    if options.compress_temp && !cfg!(feature = "compress-temp") {
+        bail!("compress_temp requires building with the `compress-temp` feature");
+    }
+
+    init_logging(options.progress_mode);
+
+    options.concurrency = resolve_concurrency(options.concurrency);
+
+    let multi_progress = TerminalProgress::new(options.progress_mode);
+
+    let check_pb = multi_progress.spinner("{spinner:.green} {msg}", Duration::from_millis(100))?;
+    check_pb.set_message("Selecting transcoder backend...");
+
+    reporter.progress("Selecting transcoder backend...", 0.01);
+
+    let (backend, transcoder) = select_backend(&options).await?;
+    match backend {
+        TranscoderKind::Ffmpeg(accel) => {
+            check_pb.finish_with_message(format!("Selected FFmpeg backend ({:?})", accel));
+        }
+        TranscoderKind::AndroidHardware => {
+            check_pb.finish_with_message("Selected Android MediaCodec backend");
+        }
+        TranscoderKind::Custom => {
+            check_pb.finish_with_message("Using custom transcoder");
+        }
+    }
+
+    let download_pb = multi_progress.spinner("{spinner:.blue} {msg}", Duration::from_millis(100))?;
+    download_pb.set_message("Downloading M3U8 playlist...");
+
+    reporter.progress("Downloading M3U8 playlist...", 0.02);
+
+    let source = match source {
+        PlaylistSource::Urls(urls) if urls.iter().any(|url| url == STDIN_PLAYLIST) => {
+            if urls.len() > 1 {
+                bail!("A playlist read from stdin can't have mirror URLs");
+            }
+            let text = run_blocking(|| {
+                let mut text = String::new();
+                std::io::Read::read_to_string(&mut std::io::stdin(), &mut text)
+                    .context("Failed to read the playlist from stdin")?;
+                Ok(text)
+            })
+            .await?;
+            PlaylistSource::Content {
+                text,
+                base_url: options.base_url.clone(),
+            }
+        }
+        source => source,
+    };
+    let (url, playlist) = match source {
+        PlaylistSource::Urls(urls) => {
+            let fetch_clock = reporter.phase_started(Phase::FetchPlaylist);
+            let fetched = fetch_first_playlist(&urls, &options).await?;
+            reporter.phase_ended(Phase::FetchPlaylist, fetch_clock);
+            fetched
+        }
+        PlaylistSource::Content { text, base_url } => {
+            if let Some(base) = &base_url {
+                require_http_url(base, "base_url")?;
+            }
+            let playlist = parse_m3u8(text.as_bytes(), &options.playlist_limits)?;
+            (base_url.unwrap_or_default(), playlist)
+        }
+    };
+    download_pb.finish_with_message("Parsed M3U8 playlist");
+
+    let base_url = playlist_base_url(&url)?;
+
+    // An existing directory as `output` means "put it in there", named after the playlist.
+    let output = if !options.segments_only
+        && output != STDOUT_OUTPUT
+        && Path::new(&output).is_dir()
+    {
+        let named = Path::new(&output).join(format!("{}.mp4", output_stem_from_url(&url)));
+        named.to_string_lossy().to_string()
+    } else {
+        output
+    };
+
+    let temp_dir = if cfg!(target_os = "android") {
+        #[cfg(target_os = "android")]
+        {
+            select_writable_temp_dir()?
+        }
+        #[cfg(not(target_os = "android"))]
+        {
+            unreachable!()
+        }
+    } else {
+        PathBuf::from(".")
+    };
+
+    info!("Temporary directory: {}", temp_dir.display());
+
+    if let Some(max_age) = options.clean_stale_temp {
+        let removed = clean_stale_temp(&temp_dir, max_age).await;
+        if removed > 0 {
+            info!("Removed {} stale temp entries", removed);
+        }
+    }
+
+    let ctx = RunContext {
+        client: Arc::new(create_http_client(&options.network, &options.http)?),
+        semaphore: Arc::new(Semaphore::new(options.concurrency)),
+        keys: KeyCache::new(options.key_concurrency),
+        temp_prefix: options.temp_prefix.clone().unwrap_or_else(unique_temp_prefix),
+        options,
+        progress: multi_progress,
+        reporter,
+        backend,
+        transcoder,
+    };
+
+    let results = match playlist {
+        Playlist::MasterPlaylist(master) => {
+            info!("Master Playlist found, {} variants", master.variants.len());
+            if ctx.options.decryption_key.is_none() && !master.session_key.is_empty() {
+                ctx.reporter.progress("Fetching decryption keys...", 0.02);
+                let keys_clock = ctx.reporter.phase_started(Phase::FetchKeys);
+                prefetch_session_keys(&ctx, &master, base_url.as_ref()).await;
+                ctx.reporter.phase_ended(Phase::FetchKeys, keys_clock);
+            }
+            let variants = collect_variants(&master);
+
+            if all_variants {
+                let selected: Vec<VariantInfo> = variants
+                    .into_iter()
+                    .filter(|v| v.kind == ctx.options.stream_kind)
+                    .collect();
+                if selected.is_empty() {
+                    bail!("No usable {:?} variant found", ctx.options.stream_kind);
+                }
+
+                let outputs = variant_output_paths(&output, &selected);
+                let jobs = selected.iter().zip(outputs).enumerate().map(|(idx, (variant, out))| {
+                    let job_dir = temp_dir.join(format!("variant_{:02}", idx));
+                    let ctx = &ctx;
+                    let master = &master;
+                    let base_url = base_url.as_ref();
+                    async move {
+                        let result =
+                            process_variant(ctx, master, base_url, variant, &job_dir, out)
+                                .await;
+                        if !ctx.options.keep_temp {
+                            let _ = fs::remove_dir(&job_dir).await;
+                        }
+                        result
+                    }
+                });
+                futures::future::try_join_all(jobs).await?
+            } else {
+                let best = select_variant(&variants, &ctx.options)?;
+                let base_url = base_url.as_ref();
+                vec![process_variant(&ctx, &master, base_url, &best, &temp_dir, output).await?]
+            }
+        }
+        Playlist::MediaPlaylist(mp) => {
+            info!("Media Playlist found, {} segments", mp.segments.len());
+            ctx.reporter.stream_selected(None, ctx.backend);
+            let mp = wait_for_segments(mp, &url, &ctx.options).await?;
+            let origin = PlaylistOrigin { url: url.clone(), variant: None, bandwidth: None };
+            vec![process_media_playlist(&ctx, mp, base_url, origin, &temp_dir, output).await?]
+        }
+    };
+
+    ctx.reporter.progress("All tasks completed", 1.0);
+    for result in &results {
+        ctx.reporter.result(result);
+    }
+
+    Ok(results)
+}
+
+/// State shared by every media playlist processed in one run.
+struct RunContext {
+    options: DownloadOptions,
+    client: Arc<Client>,
+    /// Global segment-download budget, shared across variants.
+    semaphore: Arc<Semaphore>,
+    progress: TerminalProgress,
+    reporter: Reporter,
+    backend: TranscoderKind,
+    transcoder: Arc<dyn Transcoder>,
+    keys: KeyCache,
+    /// Resolved [`DownloadOptions::temp_prefix`] for this run.
+    temp_prefix: String,
+}
+
+/// AES-128 keys by resolved URL, so a key shared by many segments (or preloaded through
+/// EXT-X-SESSION-KEY) is fetched once per run. Key requests have their own small pool of
+/// permits, so a slow key server never holds up segment downloads (or the reverse).
+struct KeyCache {
+    keys: Mutex<HashMap<String, Arc<tokio::sync::OnceCell<Vec<u8>>>>>,
+    permits: Semaphore,
+}
+
+impl KeyCache {
+    fn new(concurrency: usize) -> Self {
+        Self {
+            keys: Mutex::new(HashMap::new()),
+            permits: Semaphore::new(concurrency.max(1)),
+        }
+    }
+
+    async fn get_or_fetch(
+        &self,
+        client: &Client,
+        url: &Url,
+        key_format: Option<&str>,
+        options: &DownloadOptions,
+    ) -> Result<Vec<u8>> {
+        // The map lock only hands out the per-URL cell; different keys download in
+        // parallel while concurrent requests for the same key wait for one fetch.
+        let cell = self.keys.lock().await.entry(url.to_string()).or_default().clone();
+        let key = cell
+            .get_or_try_init(|| async {
+                let _permit = self
+                    .permits
+                    .acquire()
+                    .await
+                    .map_err(|_| anyhow!("Semaphore acquire failed"))?;
+                if let Some(handler) = &options.key_request_handler {
+                    let key = (handler.0)(url.clone(), key_format.map(str::to_string))
+                        .await
+                        .with_context(|| format!("Key request handler failed for {}", url))?;
+                    if key.len() != 16 {
+                        bail!("Key request handler returned {} bytes for {}", key.len(), url);
+                    }
+                    return Ok(key);
+                }
+                let request_url = UrlSigner::sign(options.url_signer.as_ref(), url.as_str())?;
+                HostFilter::new(&options.network).check(&Url::parse(&request_url)?, "Key")?;
+                let key = client
+                    .get(request_url)
+                    .send()
+                    .await
+                    .map_err(|e| request_error(e, "Key request"))?
+                    .error_for_status()?
+                    .bytes()
+                    .await?
+                    .to_vec();
+                Ok::<_, anyhow::Error>(key)
+            })
+            .await?;
+        Ok(key.clone())
+    }
+}
+
+/// Preloads EXT-X-SESSION-KEY keys of a master playlist into the key cache. Only AES-128 keys
+/// in the identity format can be used; others are reported and skipped.
+async fn prefetch_session_keys(
+    ctx: &RunContext,
+    master: &m3u8_rs::MasterPlaylist,
+    base_url: Option<&Url>,
+) {
+    for m3u8_rs::SessionKey(key) in &master.session_key {
+        // Other key formats are only usable through a handler that knows them.
+        let identity = key.keyformat.as_deref().is_none_or(|f| f == "identity");
+        let handled = identity || ctx.options.key_request_handler.is_some();
+        if key.method != m3u8_rs::KeyMethod::AES128 || !handled {
+            warn!(
+                "Skipping unsupported session key (method {}, format {:?})",
+                key.method, key.keyformat
+            );
+            continue;
+        }
+        let Some(uri) = &key.uri else {
+            continue;
+        };
+        let result = match resolve_uri(base_url, uri, "Session key") {
+            Ok(url) => ctx
+                .keys
+                .get_or_fetch(&ctx.client, &url, key.keyformat.as_deref(), &ctx.options)
+                .await
+                .map(|_| url),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(url) => info!("Preloaded session key {}", url),
+            Err(e) => warn!("Failed to preload session key {}: {}", uri, e),
+        }
+    }
+}
+
+async fn process_variant(
+    ctx: &RunContext,
+    master: &m3u8_rs::MasterPlaylist,
+    master_base_url: Option<&Url>,
+    variant: &VariantInfo,
+    temp_dir: &Path,
+    output: String,
+) -> Result<DownloadResult> {
+    info!(
+        "Selected {:?} variant: bandwidth {} , resolution {:?}",
+        variant.kind,
+        variant.bandwidth,
+        variant.resolution.map(|(w, h)| format!("{}x{}", w, h))
+    );
+    ctx.reporter.stream_selected(Some(variant), ctx.backend);
+
+    let languages = &ctx.options.preferred_languages;
+    let audio = select_rendition(
+        master,
+        m3u8_rs::AlternativeMediaType::Audio,
+        variant.audio_group.as_deref(),
+        languages,
+    );
+    let subtitles = select_rendition(
+        master,
+        m3u8_rs::AlternativeMediaType::Subtitles,
+        variant.subtitle_group.as_deref(),
+        languages,
+    );
+    for (kind, rendition) in [("audio", &audio), ("subtitle", &subtitles)] {
+        if let Some(rendition) = rendition {
+            info!(
+                "Selected {} rendition {:?} ({})",
+                kind,
+                rendition.name,
+                rendition.language.as_deref().unwrap_or("no language")
+            );
+        }
+    }
+
+    ctx.reporter.progress("Fetching media playlist...", 0.02);
+    let fetch_clock = ctx.reporter.phase_started(Phase::FetchMediaPlaylist);
+    let (final_url, mp) = fetch_media_playlist(master_base_url, &variant.uri, &ctx.options).await?;
+    ctx.reporter.phase_ended(Phase::FetchMediaPlaylist, fetch_clock);
+
+    // Keys and segments are relative to the media playlist as served, i.e. after redirects,
+    // not to the master.
+    let media_base_url = playlist_base_url(final_url.as_str())?;
+
+    let mp = wait_for_segments(mp, final_url.as_str(), &ctx.options).await?;
+    let origin = PlaylistOrigin {
+        url: final_url.to_string(),
+        variant: Some(variant.uri.clone()),
+        bandwidth: Some(variant.average_bandwidth.unwrap_or(variant.bandwidth)),
+    };
+    let mut result =
+        process_media_playlist(ctx, mp, media_base_url, origin, temp_dir, output).await?;
+    result.audio_rendition = audio;
+    result.subtitle_rendition = subtitles;
+    Ok(result)
+}
+
+/// A live playlist may be empty right after the stream starts. Without EXT-X-ENDLIST, re-fetch
+/// it every target duration up to `options.empty_playlist_retries` times; VOD fails at once.
+async fn wait_for_segments(
+    mut playlist: m3u8_rs::MediaPlaylist,
+    url: &str,
+    options: &DownloadOptions,
+) -> Result<m3u8_rs::MediaPlaylist> {
+    let mut attempt = 0;
+    while playlist.segments.is_empty() {
+        if playlist.end_list {
+            bail!("MediaPlaylist contains no segments");
+        }
+        if attempt >= options.empty_playlist_retries || !url.starts_with("http") {
+            bail!("Live playlist still has no segments after {} re-fetches", attempt);
+        }
+        attempt += 1;
+
+        let wait = Duration::from_secs(playlist.target_duration.clamp(1, 10));
+        info!(
+            "Live playlist has no segments yet, re-fetching in {:?} ({}/{})",
+            wait, attempt, options.empty_playlist_retries
+        );
+        tokio::time::sleep(wait).await;
+
+        let (content, _) = download_playlist(url, options).await?;
+        match parse_m3u8(&content, &options.playlist_limits)? {
+            Playlist::MediaPlaylist(refreshed) => playlist = refreshed,
+            Playlist::MasterPlaylist(_) => bail!("Playlist changed from media to master"),
+        }
+    }
+
+    if let Some(min) = options.min_segments {
+        let count = playlist.segments.iter().filter(|seg| !is_gap(seg)).count();
+        if count < min {
+            bail!(
+                "Playlist has {} segments ({:.1}s), fewer than the required {}; it may be \
+                 truncated or the wrong variant",
+                count,
+                playlist_duration(&playlist),
+                min
+            );
+        }
+    }
+    Ok(playlist)
+}
+
+/// Where a media playlist came from, recorded in the resume manifest.
+struct PlaylistOrigin {
+    url: String,
+    /// The variant URI when the playlist was picked from a master.
+    variant: Option<String>,
+    /// The variant's advertised bits per second, for `auto_bitrate` when the media playlist
+    /// has no EXT-X-BITRATE hints.
+    bandwidth: Option<u64>,
+}
+
+async fn process_media_playlist(
+    ctx: &RunContext,
+    playlist: m3u8_rs::MediaPlaylist,
+    base_url: Option<Url>,
+    origin: PlaylistOrigin,
+    temp_dir: &Path,
+    output: String,
+) -> Result<DownloadResult> {
+    let reporter = ctx.reporter.scoped();
+
+    let mut playlist = playlist;
+    if let Some(clip) = ctx.options.clip_duration {
+        truncate_to_duration(&mut playlist, clip);
+        info!("Clipping to the first {:.1}s ({} segments)", clip, playlist.segments.len());
+    }
+    let encryption = encryption_info(&playlist, base_url.as_ref(), &ctx.options);
+    if let Some(e) = &encryption {
+        info!(
+            "Encryption: {} with {} key(s) {:?}; IVs: {} explicit, {} from media sequence{}",
+            e.methods.join("/"),
+            e.key_uris.len(),
+            e.key_uris,
+            e.explicit_iv_segments,
+            e.sequence_iv_segments,
+            if e.key_override { " (decryption_key override)" } else { "" }
+        );
+    }
+
+    if ctx.options.segments_only {
+        let segment_dir = PathBuf::from(&output);
+        let total_duration = playlist_duration(&playlist);
+        let merged = segment_dir.join("temp_merged.ts").to_string_lossy().to_string();
+        let local_playlist = local_playlist(&playlist);
+        let stats = download_and_merge(
+            playlist,
+            base_url,
+            &origin,
+            ctx,
+            &reporter,
+            MergeTarget::File(&merged),
+            &segment_dir,
+        )
+        .await?;
+
+        let local_playlist_path = if ctx.options.compress_temp {
+            warn!("Not writing a local playlist: compressed segments aren't playable");
+            None
+        } else {
+            let path = segment_dir.join("playlist.m3u8");
+            let mut content = Vec::new();
+            local_playlist.write_to(&mut content)?;
+            fs::write(&path, content)
+                .await
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            info!("Local playlist: {}", path.display());
+            Some(path.to_string_lossy().to_string())
+        };
+
+        return Ok(DownloadResult {
+            output_path: output,
+            phase_timings: reporter.phase_timings(),
+            total_duration,
+            stats,
+            local_playlist_path,
+            encryption,
+            ..Default::default()
+        });
+    }
+
+    let output = resolve_output_path(output, ctx.options.overwrite)?;
+
+    let mut total_duration = playlist_duration(&playlist);
+    if let Some(clip) = ctx.options.clip_duration {
+        total_duration = total_duration.min(clip);
+    }
+    info!("Playlist duration: {:.1}s", total_duration);
+    let discontinuities = playlist.segments.iter().filter(|s| s.discontinuity).count();
+    if discontinuities > 0 {
+        info!("Playlist has {} discontinuities", discontinuities);
+        if !ctx.options.fix_timestamps {
+            warn!("Timestamps restart at each discontinuity; fix_timestamps would rebase them");
+        }
+    }
+    // EXT-X-BITRATE describes these very segments; BANDWIDTH is only the variant's average.
+    let bitrate_hint = bitrate_hint_totals(&playlist)
+        .map(|(bits, secs)| ((bits / secs) as u64, "EXT-X-BITRATE"))
+        .or(origin.bandwidth.map(|bandwidth| (bandwidth, "variant bandwidth")));
+
+    let pipelined = ctx.options.pipelined && ctx.transcoder.accepts_stream();
+    if ctx.options.pipelined && !pipelined {
+        warn!("{:?} can't transcode while downloading; transcoding after it", ctx.transcoder);
+    }
+
+    let (stats, transcoder, ffmpeg_log, auto_video_bitrate, merged_ts_path) = if pipelined {
+        info!("Transcoding while downloading");
+        let (tx, rx) = tokio::sync::mpsc::channel(ctx.options.merge_buffer_segments.max(1));
+        let probe_path = temp_dir.join(format!("{}probe.ts", ctx.temp_prefix));
+        let transcode_clock = reporter.phase_started(Phase::Transcode);
+        let (downloaded, transcoded) = tokio::join!(
+            download_and_merge(
+                playlist,
+                base_url,
+                &origin,
+                ctx,
+                &reporter,
+                MergeTarget::Pipe(tx),
+                temp_dir,
+            ),
+            transcode_piped(rx, &output, ctx, &origin.url, &probe_path, bitrate_hint, &reporter),
+        );
+        let stats = match downloaded {
+            Ok(stats) => stats,
+            Err(e) => {
+                // FFmpeg has finished whatever part of the stream it got.
+                if output != STDOUT_OUTPUT {
+                    let _ = fs::remove_file(&output).await;
+                }
+                // The pipe only closes early when FFmpeg failed, and its error says why.
+                return Err(match transcoded {
+                    Err(transcode_error) if e.downcast_ref::<PipeClosed>().is_some() => {
+                        transcode_error
+                    }
+                    _ => e,
+                });
+            }
+        };
+        let (transcoder, ffmpeg_log, auto_video_bitrate) = transcoded?;
+        reporter.phase_ended(Phase::Transcode, transcode_clock);
+        (stats, transcoder, ffmpeg_log, auto_video_bitrate, None)
+    } else {
+        // Merging only concatenates, so the merged file is of the segments' type.
+        let merged_name =
+            format!("{}temp_merged.{}", ctx.temp_prefix, segment_extension(&playlist));
+        let temp_ts_str = temp_dir.join(merged_name).to_string_lossy().to_string();
+        info!("Temporary merged file: {}", temp_ts_str);
+
+        let stats = download_and_merge(
+            playlist,
+            base_url,
+            &origin,
+            ctx,
+            &reporter,
+            MergeTarget::File(&temp_ts_str),
+            temp_dir,
+        )
+        .await?;
+
+        let transcode_clock = reporter.phase_started(Phase::Transcode);
+        let (transcode_options, auto_video_bitrate) =
+            with_auto_bitrate(&ctx.options, &temp_ts_str, bitrate_hint).await;
+        let transcode_options = with_source_tag(transcode_options, &origin.url);
+        let (transcoder, ffmpeg_log) = convert_to_mp4(
+            &temp_ts_str,
+            &output,
+            &transcode_options,
+            Some(total_duration).filter(|d| *d > 0.0),
+            &ctx.progress,
+            ctx.transcoder.as_ref(),
+            &reporter,
+        )
+        .await?;
+        reporter.phase_ended(Phase::Transcode, transcode_clock);
+
+        let merged_ts_path = if ctx.options.keep_temp {
+            Some(temp_ts_str)
+        } else {
+            let _ = fs::remove_file(&temp_ts_str).await;
+            None
+        };
+        (stats, transcoder, ffmpeg_log, auto_video_bitrate, merged_ts_path)
+    };
+    info!(
+        "Downloaded {} bytes in {:.1}s (avg {:.0} KB/s, peak {:.0} KB/s, {} retries)",
+        stats.total_bytes,
+        stats.download_time.as_secs_f64(),
+        stats.average_throughput / 1024.0,
+        stats.peak_throughput / 1024.0,
+        stats.retries
+    );
+
+    let (output_duration, output_resolution, output_frame_rate, output_audio) =
+        if output == STDOUT_OUTPUT {
+            (None, None, None, None)
+        } else {
+            (
+                verify_output_duration(&output, total_duration, &ctx.options).await?,
+                probe_resolution(&output).await.ok(),
+                probe_frame_rate(&output).await.ok(),
+                probe_audio(&output).await.ok(),
+            )
+        };
+    if let (Some(reduction), Some(fps)) = (ctx.options.frame_reduction, output_frame_rate) {
+        info!("Output frame rate after {:?}: {:.3} fps", reduction, fps);
+    }
+
+    Ok(DownloadResult {
+        output_path: output,
+        output_resolution,
+        output_frame_rate,
+        output_audio,
+        phase_timings: reporter.phase_timings(),
+        total_duration,
+        output_duration,
+        stats,
+        transcoder: Some(transcoder),
+        merged_ts_path,
+        ffmpeg_log,
+        auto_video_bitrate,
+        encryption,
+        ..Default::default()
+    })
+}
+
+/// Summarizes the EXT-X-KEY tags in effect for `playlist`'s segments, the way
+/// [`segment_jobs`] will apply them. `None` when no segment is encrypted.
+fn encryption_info(
+    playlist: &m3u8_rs::MediaPlaylist,
+    base_url: Option<&Url>,
+    options: &DownloadOptions,
+) -> Option<EncryptionInfo> {
+    let mut info = EncryptionInfo {
+        key_override: options.decryption_key.is_some(),
+        ..Default::default()
+    };
+    let mut current_key: Option<&m3u8_rs::Key> = None;
+    for seg in &playlist.segments {
+        if let Some(key) = &seg.key {
+            current_key = Some(key);
+        }
+        let Some(key) = current_key.filter(|k| k.method != m3u8_rs::KeyMethod::None) else {
+            continue;
+        };
+        // An override key decrypts the encrypted segments, and the playlist's keys are never
+        // fetched.
+        let method =
+            if info.key_override { "AES-128".to_string() } else { key.method.to_string() };
+        if !info.methods.contains(&method) {
+            info.methods.push(method);
+        }
+        if let Some(uri) = key.uri.as_ref().filter(|_| !info.key_override) {
+            let reported = match resolve_uri(base_url, uri, "Key") {
+                Ok(mut url) if !options.report_key_uris => {
+                    url.set_query(None);
+                    url.set_fragment(None);
+                    url.to_string()
+                }
+                Ok(url) => url.to_string(),
+                Err(_) => uri.clone(),
+            };
+            if !info.key_uris.contains(&reported) {
+                info.key_uris.push(reported);
+            }
+        }
+        if options.decryption_iv.is_some() || current_key.is_some_and(|k| k.iv.is_some()) {
+            info.explicit_iv_segments += 1;
+        } else {
+            info.sequence_iv_segments += 1;
+        }
+    }
+    (!info.methods.is_empty()).then_some(info)
+}
+
+/// A segment's EXTINF duration, or the target duration when it has no usable one.
+fn segment_duration(playlist: &m3u8_rs::MediaPlaylist, seg: &m3u8_rs::MediaSegment) -> f64 {
+    if seg.duration > 0.0 {
+        seg.duration as f64
+    } else {
+        playlist.target_duration as f64
+    }
+}
+
+/// Sum of segment durations; segments without a usable EXTINF count as the target duration.
+fn playlist_duration(playlist: &m3u8_rs::MediaPlaylist) -> f64 {
+    playlist
+        .segments
+        .iter()
+        .map(|seg| segment_duration(playlist, seg))
+        .sum()
+}
+
+/// Compares the ffprobe duration of `output` against `expected`. A missing ffprobe only
+/// skips the check; a mismatch warns or fails depending on [`DurationCheck`].
+async fn verify_output_duration(
+    output: &str,
+    expected: f64,
+    options: &DownloadOptions,
+) -> Result<Option<f64>> {
+    if options.duration_check == DurationCheck::Off || expected <= 0.0 {
+        return Ok(None);
+    }
+
+    let actual = match probe_duration(output).await {
+        Ok(actual) => actual,
+        Err(e) => {
+            warn!("Skipping duration check: {}", e);
+            return Ok(None);
+        }
+    };
+
+    let diff = (actual - expected).abs();
+    if diff > options.duration_tolerance {
+        let message = format!(
+            "Output duration {:.2}s differs from playlist duration {:.2}s by {:.2}s",
+            actual, expected, diff
+        );
+        if options.duration_check == DurationCheck::Strict {
+            bail!(message);
+        }
+        warn!("{}", message);
+    } else {
+        info!("Output duration {:.2}s matches playlist ({:.2}s)", actual, expected);
+    }
+
+    Ok(Some(actual))
+}
+
+/// Width and height of the first video stream via `ffprobe`.
+async fn probe_resolution(path: &str) -> Result<(u32, u32)> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "stream=width,height",
+            "-of",
+            "csv=s=x:p=0",
+            path,
+        ])
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Failed to run ffprobe")?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (w, h) = stdout
+        .trim()
+        .split_once('x')
+        .ok_or_else(|| anyhow!("Unexpected ffprobe resolution output: {:?}", stdout.trim()))?;
+    Ok((w.trim().parse()?, h.trim().parse()?))
+}
+
+/// Average frame rate of the first video stream via `ffprobe`.
+async fn probe_frame_rate(path: &str) -> Result<f64> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "stream=avg_frame_rate",
+            "-of",
+            "csv=p=0",
+            path,
+        ])
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Failed to run ffprobe")?;
+
+    // A ratio such as `30000/1001`; `0/0` when ffprobe couldn't tell.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (num, den) = stdout
+        .trim()
+        .split_once('/')
+        .ok_or_else(|| anyhow!("Unexpected ffprobe frame rate output: {:?}", stdout.trim()))?;
+    let (num, den): (f64, f64) = (num.trim().parse()?, den.trim().parse()?);
+    if den == 0.0 {
+        bail!("ffprobe reported no frame rate");
+    }
+    Ok(num / den)
+}
+
+/// Sample rate and channel count of the first audio stream via `ffprobe`.
+async fn probe_audio(path: &str) -> Result<(u32, u32)> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "a:0",
+            "-show_entries",
+            "stream=sample_rate,channels",
+            "-of",
+            "csv=p=0",
+            path,
+        ])
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Failed to run ffprobe")?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (rate, channels) = stdout
+        .trim()
+        .split_once(',')
+        .ok_or_else(|| anyhow!("Unexpected ffprobe audio output: {:?}", stdout.trim()))?;
+    Ok((rate.trim().parse()?, channels.trim().parse()?))
+}
+
+/// Which kinds of streams a media file contains.
+#[derive(Debug, Clone, Copy)]
+struct StreamPresence {
+    video: bool,
+    audio: bool,
+}
+
+/// Lists the stream types of `path` via `ffprobe`.
+async fn probe_streams(path: &str) -> Result<StreamPresence> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_entries", "stream=codec_type", "-of", "csv=p=0", path])
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Failed to run ffprobe")?;
+
+    if !output.status.success() {
+        bail!("ffprobe failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let has = |kind: &str| stdout.lines().any(|line| line.trim() == kind);
+    Ok(StreamPresence { video: has("video"), audio: has("audio") })
+}
+
+/// Overall bits per second of a media file via `ffprobe`.
+async fn probe_bitrate(path: &str) -> Result<u64> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=bit_rate",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+            path,
+        ])
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Failed to run ffprobe")?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .trim()
+        .parse::<u64>()
+        .with_context(|| format!("Unexpected ffprobe bit_rate output: {:?}", stdout.trim()))
+}
+
+/// Container duration in seconds via `ffprobe`.
+async fn probe_duration(path: &str) -> Result<f64> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+            path,
+        ])
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Failed to run ffprobe")?;
+
+    if !output.status.success() {
+        bail!("ffprobe failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .trim()
+        .parse::<f64>()
+        .with_context(|| format!("Unexpected ffprobe duration output: {:?}", stdout.trim()))
+}
+
+const TEMP_PREFIX_TAG: &str = "m3u8dl-";
+
+/// Default [`DownloadOptions::temp_prefix`]: `m3u8dl-` plus 16 hex digits unique to this run.
+fn unique_temp_prefix() -> String {
+    static RUNS: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let run = RUNS.fetch_add(1, Ordering::Relaxed).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let id = nanos ^ ((std::process::id() as u64) << 32) ^ run;
+    format!("{}{:016x}_", TEMP_PREFIX_TAG, id)
+}
+
+/// Recognizes this crate's temp files by their default prefix. Bare names and custom
+/// prefixes set through `temp_prefix` aren't recognized, so `clean_stale_temp` leaves
+/// those alone: on desktop the temp dir is the working directory, full of user files.
+fn is_temp_file_name(name: &str) -> bool {
+    let Some((_, name)) = name
+        .strip_prefix(TEMP_PREFIX_TAG)
+        .and_then(|rest| rest.split_once('_'))
+        .filter(|(id, _)| id.len() == 16 && id.bytes().all(|b| b.is_ascii_hexdigit()))
+    else {
+        return false;
+    };
+    if name == "probe.ts" || name == MANIFEST_FILE_NAME {
+        return true;
+    }
+    let name = name.strip_suffix(".zst").unwrap_or(name);
+    let Some((stem, ext)) = name.rsplit_once('.') else {
+        return false;
+    };
+    if !SEGMENT_EXTENSIONS.contains(&ext) {
+        return false;
+    }
+    stem == "temp_merged"
+        || stem
+            .strip_prefix("seg_")
+            .or_else(|| stem.strip_prefix("group_"))
+            .is_some_and(|idx| !idx.is_empty() && idx.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn is_job_dir_name(name: &str) -> bool {
+    name.strip_prefix("variant_")
+        .is_some_and(|idx| !idx.is_empty() && idx.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Removes this crate's temp files older than `max_age` from `dir`. Job subdirectories are
+/// emptied of matching files and removed only when that left them empty. Returns the number
+/// of entries removed.
+async fn clean_stale_temp(dir: &Path, max_age: Duration) -> usize {
+    let mut removed = 0;
+    let mut dirs = vec![(dir.to_path_buf(), false)];
+
+    while let Some((current, is_job_dir)) = dirs.pop() {
+        let removed_before = removed;
+        let Ok(mut entries) = fs::read_dir(&current).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Ok(meta) = entry.metadata().await else {
+                continue;
+            };
+            if meta.is_dir() {
+                if !is_job_dir && is_job_dir_name(&name) {
+                    dirs.push((entry.path(), true));
+                }
+                continue;
+            }
+            let stale = meta
+                .modified()
+                .ok()
+                .and_then(|m| m.elapsed().ok())
+                .is_some_and(|age| age >= max_age);
+            if stale && is_temp_file_name(&name) && fs::remove_file(entry.path()).await.is_ok() {
+                removed += 1;
+            }
+        }
+        if is_job_dir && removed > removed_before && fs::remove_dir(&current).await.is_ok() {
+            removed += 1;
+        }
+    }
+
+    removed
+}
+
+const TS_PACKET_SIZE: usize = 188;
+
+/// Decrypts an AES-128-CBC segment in place. PKCS#7 padding is stripped when the final block
+/// carries valid padding; otherwise the data is treated as unpadded.
+fn decrypt_segment(key: &[u8], iv: &[u8], data: &mut Vec<u8>) -> Result<()> {
+    let cipher = Aes128CbcDec::new_from_slices(key, iv)
+        .map_err(|_| anyhow!("Invalid AES-128 key/IV length ({}/{})", key.len(), iv.len()))?;
+    let encrypted_len = data.len();
+    let len = cipher
+        .decrypt_padded_mut::<NoPadding>(data)
+        .map_err(|_| anyhow!("Encrypted segment length {} is not block-aligned", encrypted_len))?
+        .len();
+    let pad = pkcs7_padding_len(data).unwrap_or(0);
+    if pad > 0 && is_unpadded_ts(data.first().copied(), data.len(), pad) {
+        return Ok(());
+    }
+    data.truncate(len - pad);
+    Ok(())
+}
+
+/// AES-128-CBC decryption fed a chunk at a time, so a segment never has to be held whole.
+/// The last block is kept back until [`finish`](Self::finish), which strips padding exactly
+/// like [`decrypt_segment`].
+struct CbcStreamDecryptor {
+    cipher: Aes128CbcDec,
+    pending: Vec<u8>,
+    first: Option<u8>,
+    len: usize,
+}
+
+impl CbcStreamDecryptor {
+    fn new(key: &[u8], iv: &[u8]) -> Result<Self> {
+        let cipher = Aes128CbcDec::new_from_slices(key, iv)
+            .map_err(|_| anyhow!("Invalid AES-128 key/IV length ({}/{})", key.len(), iv.len()))?;
+        Ok(Self { cipher, pending: Vec::new(), first: None, len: 0 })
+    }
+
+    /// Appends the plaintext of every block `chunk` completes to `out`, except the last.
+    fn update(&mut self, chunk: &[u8], out: &mut Vec<u8>) {
+        self.pending.extend_from_slice(chunk);
+        let ready = self.pending.len().saturating_sub(1) / 16 * 16;
+        self.decrypt_into(ready, out);
+    }
+
+    /// Appends the final block's plaintext to `out`, without its padding.
+    fn finish(mut self, out: &mut Vec<u8>) -> Result<()> {
+        let encrypted_len = self.len + self.pending.len();
+        if !self.pending.len().is_multiple_of(16) {
+            bail!("Encrypted segment length {} is not block-aligned", encrypted_len);
+        }
+        let start = out.len();
+        self.decrypt_into(self.pending.len(), out);
+        let pad = pkcs7_padding_len(&out[start..]).unwrap_or(0);
+        if pad > 0 && !is_unpadded_ts(self.first, self.len, pad) {
+            out.truncate(out.len() - pad);
+        }
+        Ok(())
+    }
+
+    fn decrypt_into(&mut self, len: usize, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend(self.pending.drain(..len));
+        for block in out[start..].chunks_exact_mut(16) {
+            self.cipher.decrypt_block_mut(aes::Block::from_mut_slice(block));
+        }
+        if self.len == 0 {
+            self.first = out.get(start).copied();
+        }
+        self.len += len;
+    }
+}
+
+/// Decrypts the AES-128 segment at `path` into `output` a chunk at a time, so memory stays
+/// bounded however large the segment is.
+async fn decrypt_file_into(
+    path: &Path,
+    key: &[u8],
+    iv: &[u8],
+    output: &mut (impl tokio::io::AsyncWrite + Unpin),
+) -> Result<()> {
+    let mut file = fs::File::open(path).await?;
+    let mut decryptor = CbcStreamDecryptor::new(key, iv)?;
+    let mut chunk = vec![0; DECRYPT_CHUNK_SIZE];
+    let mut plain = Vec::with_capacity(DECRYPT_CHUNK_SIZE + 16);
+    loop {
+        let read = file.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        decryptor.update(&chunk[..read], &mut plain);
+        output.write_all(&plain).await?;
+        plain.clear();
+    }
+    decryptor.finish(&mut plain)?;
+    output.write_all(&plain).await?;
+    Ok(())
+}
+
+const DECRYPT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// An unpadded MPEG-TS segment can end in bytes that happen to look like PKCS#7 padding
+/// (e.g. a trailing `0x01`). Keep the data as-is when it is whole TS packets (starting with
+/// `first`, `len` bytes long) and stripping the "padding" would break that.
+fn is_unpadded_ts(first: Option<u8>, len: usize, pad: usize) -> bool {
+    first == Some(0x47)
+        && len.is_multiple_of(TS_PACKET_SIZE)
+        && !(len - pad).is_multiple_of(TS_PACKET_SIZE)
+}
+
+/// Length of valid PKCS#7 padding at the end of `data`, if any.
+fn pkcs7_padding_len(data: &[u8]) -> Option<usize> {
+    let pad = *data.last()? as usize;
+    if pad == 0 || pad > 16 || pad > data.len() {
+        return None;
+    }
+    data[data.len() - pad..]
+        .iter()
+        .all(|&b| b as usize == pad)
+        .then_some(pad)
+}
+
+/// File name for a playlist URL's output: the last path component without its extension,
+/// reduced to characters that are safe on every filesystem.
+fn output_stem_from_url(url: &str) -> String {
+    let last = Url::parse(url)
+        .ok()
+        .and_then(|u| {
+            u.path_segments()
+                .and_then(|mut segs| segs.rfind(|s| !s.is_empty()).map(str::to_string))
+        })
+        .unwrap_or_default();
+    let stem = Path::new(&last)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let safe: String = stem
+        .chars()
+        .map(|c| if c.is_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+        .collect();
+    if safe.trim_matches(['_', '.']).is_empty() {
+        "output".to_string()
+    } else {
+        safe
+    }
+}
+
+/// Applies the [`OverwritePolicy`] to an output path before any work is done for it.
+fn resolve_output_path(output: String, policy: OverwritePolicy) -> Result<String> {
+    let path = Path::new(&output);
+    if output == STDOUT_OUTPUT || !path.exists() {
+        return Ok(output);
+    }
+    match policy {
+        OverwritePolicy::Overwrite => Ok(output),
+        OverwritePolicy::Fail => bail!("Output file already exists: {}", output),
+        OverwritePolicy::Rename => {
+            let stem = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_else(|| "output".to_string());
+            let ext = path
+                .extension()
+                .map(|e| e.to_string_lossy().to_string())
+                .unwrap_or_else(|| "mp4".to_string());
+            (1..)
+                .map(|n| path.with_file_name(format!("{}_{}.{}", stem, n, ext)))
+                .find(|candidate| !candidate.exists())
+                .map(|candidate| candidate.to_string_lossy().to_string())
+                .ok_or_else(|| anyhow!("No free file name for {}", output))
+        }
+    }
+}
+
+/// Classifies a playlist URL in at most two requests: the URL itself and, for a master
+/// playlist, the media playlist of the variant `options` would pick (more if masters are
+/// nested). Requests use `options.network`.
+#[flutter_rust_bridge::frb(ignore)]
+pub async fn inspect(url: String, options: DownloadOptions) -> Result<PlaylistInspection> {
+    let (content, final_url) = download_playlist(&url, &options).await?;
+    let playlist = parse_m3u8(&content, &options.playlist_limits)?;
+
+    let (mut inspection, media) = match playlist {
+        Playlist::MediaPlaylist(media) => (PlaylistInspection::default(), media),
+        Playlist::MasterPlaylist(master) => {
+            let variants = collect_variants(&master);
+            let best = select_variant(&variants, &options)?;
+            let media_base_url = playlist_base_url(final_url.as_str())?;
+            let (_, media) =
+                fetch_media_playlist(media_base_url.as_ref(), &best.uri, &options).await?;
+            let inspection = PlaylistInspection {
+                kind: PlaylistKind::Master,
+                is_encrypted: !master.session_key.is_empty(),
+                variants,
+                ..Default::default()
+            };
+            (inspection, media)
+        }
+    };
+
+    inspection.is_encrypted |= media.segments.iter().any(|seg| {
+        seg.key
+            .as_ref()
+            .is_some_and(|key| key.method != m3u8_rs::KeyMethod::None)
+    });
+    inspection.is_live = !media.end_list;
+    inspection.segment_count = media.segments.len();
+    inspection.total_duration = playlist_duration(&media);
+    inspection.estimated_size = bitrate_hint_totals(&media).map(|(bits, _)| (bits / 8.0) as u64);
+    Ok(inspection)
+}
+
+/// VOD playlist pointing at the `seg_NNNNN.ts` files written for `playlist`, keeping
+/// durations and discontinuities. Keys are dropped since the saved segments are decrypted.
+fn local_playlist(playlist: &m3u8_rs::MediaPlaylist) -> m3u8_rs::MediaPlaylist {
+    let extension = segment_extension(playlist);
+    let segments: Vec<m3u8_rs::MediaSegment> = playlist
+        .segments
+        .iter()
+        .enumerate()
+        .map(|(idx, seg)| m3u8_rs::MediaSegment {
+            uri: segment_file_name("", idx, extension, false),
+            duration: seg.duration,
+            title: seg.title.clone(),
+            discontinuity: seg.discontinuity,
+            unknown_tags: seg
+                .unknown_tags
+                .iter()
+                .filter(|tag| tag.tag == "X-GAP")
+                .cloned()
+                .collect(),
+            ..Default::default()
+        })
+        .collect();
+    let longest = segments.iter().map(|s| s.duration.ceil() as u64).max().unwrap_or(0);
+
+    m3u8_rs::MediaPlaylist {
+        version: Some(3),
+        target_duration: longest.max(playlist.target_duration),
+        playlist_type: Some(m3u8_rs::MediaPlaylistType::Vod),
+        end_list: true,
+        segments,
+        ..Default::default()
+    }
+}
+
+/// Drops trailing segments not needed to cover the first `seconds` of the playlist.
+fn truncate_to_duration(playlist: &mut m3u8_rs::MediaPlaylist, seconds: f64) {
+    let target = playlist.target_duration as f64;
+    let mut covered = 0.0;
+    let keep = playlist
+        .segments
+        .iter()
+        .take_while(|seg| {
+            let before = covered;
+            covered += if seg.duration > 0.0 { seg.duration as f64 } else { target };
+            before < seconds
+        })
+        .count();
+    playlist.segments.truncate(keep.max(1));
+}
+
+/// Per-variant output names: `out.mp4` becomes `out_1920x1080.mp4` (or `out_800000bps.mp4`).
+fn variant_output_paths(output: &str, variants: &[VariantInfo]) -> Vec<String> {
+    let path = Path::new(output);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "output".to_string());
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_else(|| "mp4".to_string());
+
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    variants
+        .iter()
+        .map(|v| {
+            let mut suffix = match v.resolution {
+                Some((w, h)) => format!("{}x{}", w, h),
+                None => format!("{}bps", v.bandwidth),
+            };
+            let count = seen.entry(suffix.clone()).or_insert(0);
+            *count += 1;
+            if *count > 1 {
+                suffix = format!("{}_{}", suffix, count);
+            }
+            path.with_file_name(format!("{}_{}.{}", stem, suffix, ext))
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect()
+}
+
+/// Fetches a playlist and lists its variants, including I-frame and image streams. The
+/// request uses `options.network` and `options.playlist_limits`.
+#[flutter_rust_bridge::frb(ignore)]
+pub async fn probe_playlist(url: String, options: DownloadOptions) -> Result<PlaylistProbe> {
+    let (content, _) = download_playlist(&url, &options).await?;
+    let playlist = parse_m3u8(&content, &options.playlist_limits)?;
+
+    let master = match playlist {
+        Playlist::MasterPlaylist(master) => master,
+        Playlist::MediaPlaylist(_) => return Ok(PlaylistProbe::default()),
+    };
+
+    let mut probe = PlaylistProbe {
+        is_master: true,
+        ..Default::default()
+    };
+    for variant in collect_variants(&master) {
+        match variant.kind {
+            StreamKind::Main => probe.variants.push(variant),
+            StreamKind::IFrame => probe.i_frame_streams.push(variant),
+            StreamKind::Image => probe.image_streams.push(variant),
+        }
+    }
+    Ok(probe)
+}
+
+fn collect_variants(master: &m3u8_rs::MasterPlaylist) -> Vec<VariantInfo> {
+    let mut variants: Vec<VariantInfo> = master
+        .variants
+        .iter()
+        .map(|v| VariantInfo {
+            kind: if v.is_i_frame {
+                StreamKind::IFrame
+            } else {
+                StreamKind::Main
+            },
+            uri: v.uri.clone(),
+            bandwidth: v.bandwidth,
+            average_bandwidth: v.average_bandwidth,
+            resolution: v.resolution.as_ref().map(|r| (r.width, r.height)),
+            codecs: v.codecs.clone(),
+            frame_rate: v.frame_rate,
+            audio_group: v.audio.clone(),
+            subtitle_group: v.subtitles.clone(),
+        })
+        .collect();
+
+    // m3u8_rs doesn't know EXT-X-IMAGE-STREAM-INF, so pick it out of the unknown tags.
+    for tag in &master.unknown_tags {
+        if tag.tag != "X-IMAGE-STREAM-INF" {
+            continue;
+        }
+        let attrs = parse_attribute_list(tag.rest.as_deref().unwrap_or_default());
+        let Some(uri) = attrs.get("URI") else {
+            warn!("Skipping EXT-X-IMAGE-STREAM-INF without URI");
+            continue;
+        };
+        variants.push(VariantInfo {
+            kind: StreamKind::Image,
+            uri: uri.clone(),
+            bandwidth: attrs
+                .get("BANDWIDTH")
+                .and_then(|b| b.parse().ok())
+                .unwrap_or(0),
+            average_bandwidth: attrs
+                .get("AVERAGE-BANDWIDTH")
+                .and_then(|b| b.parse().ok()),
+            resolution: attrs.get("RESOLUTION").and_then(|r| {
+                let (w, h) = r.split_once('x')?;
+                Some((w.parse().ok()?, h.parse().ok()?))
+            }),
+            codecs: attrs.get("CODECS").cloned(),
+            frame_rate: None,
+            audio_group: None,
+            subtitle_group: None,
+        });
+    }
+
+    variants
+}
+
+/// The rendition of `media_type` in `group` best matching `languages`: the first preference
+/// with a matching rendition wins, else the DEFAULT, then an AUTOSELECT one.
+fn select_rendition(
+    master: &m3u8_rs::MasterPlaylist,
+    media_type: m3u8_rs::AlternativeMediaType,
+    group: Option<&str>,
+    languages: &[String],
+) -> Option<RenditionInfo> {
+    let group = group?;
+    let candidates: Vec<&m3u8_rs::AlternativeMedia> = master
+        .alternatives
+        .iter()
+        .filter(|media| media.media_type == media_type && media.group_id == group)
+        .collect();
+    let matches = |media: &&m3u8_rs::AlternativeMedia, wanted: &str| {
+        media.language.as_deref().is_some_and(|language| {
+            language.eq_ignore_ascii_case(wanted)
+                || (language.as_bytes().get(wanted.len()) == Some(&b'-')
+                    && language[..wanted.len()].eq_ignore_ascii_case(wanted))
+        })
+    };
+    let picked = languages
+        .iter()
+        .find_map(|wanted| candidates.iter().find(|media| matches(media, wanted)))
+        .or_else(|| candidates.iter().find(|media| media.default))
+        .or_else(|| candidates.iter().find(|media| media.autoselect))?;
+    Some(RenditionInfo {
+        group_id: picked.group_id.clone(),
+        name: picked.name.clone(),
+        language: picked.language.clone(),
+        uri: picked.uri.clone(),
+        default: picked.default,
+    })
+}
+
+fn select_variant(variants: &[VariantInfo], options: &DownloadOptions) -> Result<VariantInfo> {
+    let kind = options.stream_kind;
+    if kind == StreamKind::Image {
+        bail!("Image streams can be listed with probe_playlist but not downloaded");
+    }
+
+    let candidates = variants.iter().filter(|v| v.kind == kind);
+    let resolution_score = |v: &VariantInfo| v.resolution.map(|(w, h)| w * h).unwrap_or(0);
+    // Lower is better; ties keep playlist order since `min_by_key` returns the first minimum.
+    let codec_rank = |v: &VariantInfo| codec_rank(v.codecs.as_deref(), &options.preferred_codecs);
+    let selected = match options.variant_choice {
+        VariantChoice::Best => candidates.min_by_key(|v| {
+            (Reverse(resolution_score(v)), Reverse(v.bandwidth), codec_rank(v))
+        }),
+        VariantChoice::Lowest => {
+            candidates.min_by_key(|v| (v.bandwidth, resolution_score(v), codec_rank(v)))
+        }
+    };
+    selected
+        .cloned()
+        .ok_or_else(|| anyhow!("No usable {:?} variant found", kind))
+}
+
+/// Position in `preferred` of the first prefix matching one of `codecs` (a CODECS attribute
+/// such as `avc1.64001f,mp4a.40.2`); `preferred.len()` when none does.
+fn codec_rank(codecs: Option<&str>, preferred: &[String]) -> usize {
+    let codecs: Vec<String> = codecs
+        .unwrap_or_default()
+        .split(',')
+        .map(|codec| codec.trim().to_ascii_lowercase())
+        .collect();
+    preferred
+        .iter()
+        .position(|prefix| {
+            let prefix = prefix.to_ascii_lowercase();
+            codecs.iter().any(|codec| codec.starts_with(&prefix))
+        })
+        .unwrap_or(preferred.len())
+}
+
+/// Splits an HLS attribute list (`KEY=VALUE,KEY="quoted,value"`) into a map.
+fn parse_attribute_list(input: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = input.trim();
+    while !rest.is_empty() {
+        let Some((key, after)) = rest.split_once('=') else {
+            break;
+        };
+        let (value, remaining) = if let Some(quoted) = after.strip_prefix('"') {
+            match quoted.split_once('"') {
+                Some((value, remaining)) => (value, remaining),
+                None => (quoted, ""),
+            }
+        } else {
+            after.split_once(',').map_or((after, ""), |(v, r)| (v, r))
+        };
+        attrs.insert(key.trim().to_string(), value.trim().to_string());
+        rest = remaining.trim_start_matches(',').trim_start();
+    }
+    attrs
+}
+
+/// Resolves a URI from a playlist. Relative URIs need the playlist's base URL, which only
+/// exists for playlists fetched over http(s) or given a `base_url` with their content.
+fn resolve_uri(base: Option<&Url>, uri: &str, what: &str) -> Result<Url> {
+    if let Some(base) = base {
+        return base
+            .join(uri)
+            .with_context(|| format!("{} URI {:?} can't be resolved against {}", what, uri, base));
+    }
+    match Url::parse(uri) {
+        Ok(url) => Ok(url),
+        Err(url::ParseError::RelativeUrlWithoutBase) => bail!(
+            "{} URI {:?} is relative but the playlist has no base URL; \
+             fetch it over http(s) or pass base_url",
+            what,
+            uri
+        ),
+        Err(e) => Err(e).with_context(|| format!("Invalid {} URI {:?}", what.to_lowercase(), uri)),
+    }
+}
+
+/// Copies the playlist's query onto `url` when it came from a relative `uri` without a query
+/// of its own, for CDNs that expect the playlist token on every request.
+fn inherit_query(url: &mut Url, uri: &str, base: Option<&Url>) {
+    let Some(query) = base.and_then(|base| base.query()) else {
+        return;
+    };
+    let relative = matches!(Url::parse(uri), Err(url::ParseError::RelativeUrlWithoutBase));
+    if relative && url.query().is_none() {
+        url.set_query(Some(query));
+    }
+}
+
+/// Absolute http(s) URL check for playlist URLs and caller-supplied base URLs.
+fn require_http_url(url: &str, what: &str) -> Result<Url> {
+    let parsed =
+        Url::parse(url).with_context(|| format!("{} must be an absolute URL: {}", what, url))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        bail!("{} must be an http(s) URL: {}", what, url);
+    }
+    Ok(parsed)
+}
+
+/// Directory of a playlist URL, used to resolve the relative URIs it contains. The query is
+/// kept for `inherit_query`; joining a relative path replaces it anyway. A root-level or
+/// path-less URL (`https://host:8080`) has `/` as its directory, a trailing slash keeps the
+/// whole path, and a fragment is dropped. Anything but http(s) has no base.
+fn playlist_base_url(url: &str) -> Result<Option<Url>> {
+    let mut parsed_url = match Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => parsed,
+        // Local paths and playlist content without a URL.
+        Ok(_) | Err(url::ParseError::RelativeUrlWithoutBase) => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Invalid playlist URL {}", url)),
+    };
+
+    parsed_url.set_fragment(None);
+    let path = parsed_url.path();
+    let dir = path[..path.rfind('/').map_or(0, |pos| pos + 1)].to_string();
+    parsed_url.set_path(if dir.is_empty() { "/" } else { &dir });
+    Ok(Some(parsed_url))
+}
+
+/// Turns a requested concurrency of 0 into a network-bound default: a few downloads per
+/// available CPU, kept within a range most CDNs tolerate.
+fn resolve_concurrency(requested: usize) -> usize {
+    if requested > 0 {
+        return requested;
+    }
+    let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(2);
+    let chosen = (cpus * 4).clamp(4, 32);
+    info!("Auto concurrency: {} ({} CPUs)", chosen, cpus);
+    chosen
+}
+
+/// Downloads and parses the first mirror that works, retrying each one `options.retries`
+/// times before falling through to the next.
+async fn fetch_first_playlist(
+    urls: &[String],
+    options: &DownloadOptions,
+) -> Result<(String, Playlist)> {
+    if urls.is_empty() {
+        bail!("No playlist URL given");
+    }
+
+    for url in urls {
+        require_http_url(url, "Playlist URL")?;
+    }
+
+    let attempts = options.retries.max(1);
+    let mut last_error = None;
+    for (idx, url) in urls.iter().enumerate() {
+        info!("M3U8 URL: {}", url);
+        for attempt in 1..=attempts {
+            let result = download_playlist(url, options).await.and_then(|(content, final_url)| {
+                Ok((final_url, parse_m3u8(&content, &options.playlist_limits)?))
+            });
+            match result {
+                Ok((final_url, playlist)) => {
+                    if urls.len() > 1 {
+                        info!("Using mirror {}/{}: {}", idx + 1, urls.len(), url);
+                    }
+                    // Relative URIs resolve against where the playlist was actually served.
+                    return Ok((final_url.to_string(), playlist));
+                }
+                Err(e) => {
+                    warn!("Playlist {} attempt {}/{} failed: {}", url, attempt, attempts, e);
+                    last_error = Some(e);
+                }
+            }
+            if attempt < attempts {
+                tokio::time::sleep(Duration::from_millis(2000)).await;
+            }
+        }
+    }
+
+    let e = last_error.unwrap_or_else(|| anyhow!("No playlist URL given"));
+    Err(e.context(format!("All {} playlist URL(s) failed", urls.len())))
+}
+
+/// First `max` characters of `bytes` as lossy text, for error messages.
+fn snippet(bytes: &[u8], max: usize) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    let mut out: String = text.chars().take(max).collect();
+    if text.chars().count() > max {
+        out.push('…');
+    }
+    out
+}
+
+/// Markup where a playlist was expected, typically a login or error page served with 200.
+fn looks_like_html(text: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&text[..text.len().min(512)]).to_ascii_lowercase();
+    head.starts_with("<!doctype html") || head.starts_with("<html") || head.contains("<head")
+}
+
+/// Parses playlist text, telling "not a playlist at all" apart from a partial parse and
+/// logging leftover input and unknown tags instead of dropping them.
+fn parse_m3u8(content: &[u8], limits: &PlaylistLimits) -> Result<Playlist> {
+    check_playlist_limits(content, limits)?;
+    let text = content.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(content);
+    let trimmed = text.trim_ascii_start();
+    if trimmed.is_empty() {
+        bail!("Playlist is empty");
+    }
+    if !trimmed.starts_with(b"#EXTM3U") {
+        if looks_like_html(trimmed) {
+            let first_line = trimmed.split(|b| *b == b'\n').next().unwrap_or_default();
+            bail!(
+                "Server returned non-playlist content (looks like HTML, e.g. an expired link's \
+                 error page); first line: {:?}",
+                snippet(first_line.trim_ascii(), 120)
+            );
+        }
+        bail!("Not an M3U8 playlist (starts with {:?})", snippet(trimmed, 80));
+    }
+
+    // The parser is given untrusted input; a panic in it is a parse error like any other.
+    let parsed = std::panic::catch_unwind(|| parse_playlist(text).map_err(|_| ()));
+    let (rest, mut playlist) = parsed.ok().and_then(Result::ok).ok_or_else(|| {
+        anyhow!(
+            "Failed to parse M3U8 ({} bytes, starts with {:?})",
+            content.len(),
+            snippet(trimmed, 200)
+        )
+    })?;
+
+    if !rest.trim_ascii().is_empty() {
+        warn!(
+            "Playlist parse stopped with {} bytes left: {:?}",
+            rest.len(),
+            snippet(rest, 200)
+        );
+    }
+
+    // m3u8-rs rejects EXT-X-KEY:METHOD=NONE without an IV and keeps it as an unknown tag,
+    // which would leave the previous key in effect for the clear segments that follow.
+    if let Playlist::MediaPlaylist(media) = &mut playlist {
+        for seg in &mut media.segments {
+            let tags = seg.unknown_tags.len();
+            seg.unknown_tags.retain(|t| !is_method_none_key(t));
+            if seg.unknown_tags.len() != tags && seg.key.is_none() {
+                seg.key = Some(m3u8_rs::Key {
+                    method: m3u8_rs::KeyMethod::None,
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    let unknown: Vec<&m3u8_rs::ExtTag> = match &playlist {
+        Playlist::MasterPlaylist(master) => master
+            .unknown_tags
+            .iter()
+            .filter(|t| t.tag != "X-IMAGE-STREAM-INF")
+            .collect(),
+        Playlist::MediaPlaylist(media) => media
+            .unknown_tags
+            .iter()
+            .chain(media.segments.iter().flat_map(|seg| seg.unknown_tags.iter()))
+            .filter(|t| t.tag != "X-GAP" && t.tag != "X-BITRATE")
+            .collect(),
+    };
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for tag in unknown {
+        match counts.iter_mut().find(|(name, _)| *name == tag.tag) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((&tag.tag, 1)),
+        }
+    }
+    for (name, n) in counts {
+        warn!("Ignoring unknown tag #EXT-{} ({} occurrences)", name, n);
+    }
+
+    Ok(playlist)
+}
+
+/// Whether `tag` is an EXT-X-KEY with METHOD=NONE.
+fn is_method_none_key(tag: &m3u8_rs::ExtTag) -> bool {
+    tag.tag == "X-KEY"
+        && tag
+            .rest
+            .as_deref()
+            .is_some_and(|rest| rest.split(',').any(|attr| attr.trim() == "METHOD=NONE"))
+}
+
+/// Rejects playlist text beyond `limits` before it's parsed, counting the tags that make
+/// the parser allocate rather than trusting anything the playlist claims.
+fn check_playlist_limits(content: &[u8], limits: &PlaylistLimits) -> Result<()> {
+    if content.len() > limits.max_size {
+        bail!("Playlist is {} bytes, over the {}-byte limit", content.len(), limits.max_size);
+    }
+    let (mut segments, mut variants) = (0, 0);
+    // Lines may end in CR LF, LF or a lone CR.
+    for line in content.split(|b| *b == b'\n' || *b == b'\r') {
+        if line.len() > limits.max_line_length {
+            bail!(
+                "Playlist has a {}-byte line, over the {}-byte limit",
+                line.len(),
+                limits.max_line_length
+            );
+        }
+        if line.starts_with(b"#EXTINF") {
+            segments += 1;
+        } else if line.starts_with(b"#EXT-X-STREAM-INF")
+            || line.starts_with(b"#EXT-X-I-FRAME-STREAM-INF")
+        {
+            variants += 1;
+        }
+    }
+    if segments > limits.max_segments {
+        bail!("Playlist has {} segments, over the limit of {}", segments, limits.max_segments);
+    }
+    if variants > limits.max_variants {
+        bail!("Playlist has {} variants, over the limit of {}", variants, limits.max_variants);
+    }
+    Ok(())
+}
+
+/// Fetches a playlist, returning its body and the URL it was served from after redirects.
+async fn download_playlist(url: &str, options: &DownloadOptions) -> Result<(Vec<u8>, Url)> {
+    let network = &options.network;
+    let mut headers = header::HeaderMap::new();
+    headers.insert(
+        header::USER_AGENT,
+        header::HeaderValue::from_static(
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36",
+        ),
+    );
+    headers.insert(header::ACCEPT, header::HeaderValue::from_static("*/*"));
+    headers.insert(
+        header::ACCEPT_LANGUAGE,
+        header::HeaderValue::from_static("en-US,en;q=0.9"),
+    );
+
+    match &network.referer {
+        RefererPolicy::Auto => {
+            if let Ok(parsed_url) = Url::parse(url) {
+                if let Some(domain) = parsed_url.domain() {
+                    let referer = format!("https://{}/", domain);
+                    headers.insert(header::REFERER, header::HeaderValue::from_str(&referer)?);
+                }
+            }
+        }
+        RefererPolicy::Omit => {}
+        RefererPolicy::Custom(referer) => {
+            let value = header::HeaderValue::from_str(referer)
+                .with_context(|| format!("Invalid Referer {:?}", referer))?;
+            headers.insert(header::REFERER, value);
+        }
+    }
+
+    let client = apply_network_options(
+        Client::builder()
+            .default_headers(headers)
+            .timeout(Duration::from_secs(30))
+            .http1_only(),
+        network,
+    )?
+    .build()?;
+
+    let parsed = Url::parse(url).with_context(|| format!("Invalid playlist URL {}", url))?;
+    HostFilter::new(network).check(&parsed, "Playlist")?;
+    let mut response =
+        client.get(url).send().await.map_err(|e| request_error(e, "Playlist request"))?;
+    if response.status().is_redirection() {
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("<none>");
+        bail!(
+            "Playlist request redirected to {} (HTTP {}) beyond the redirect limit",
+            location,
+            response.status()
+        );
+    }
+    if !response.status().is_success() {
+        bail!("Failed to download playlist: HTTP {}", response.status());
+    }
+
+    let final_url = response.url().clone();
+    if final_url.as_str() != url {
+        info!("Playlist redirected to {}", final_url);
+    }
+
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    // Read in chunks so an oversized body is cut off rather than buffered whole.
+    let max_size = options.playlist_limits.max_size;
+    if let Some(len) = response.content_length().filter(|len| *len > max_size as u64) {
+        bail!("Playlist is {} bytes, over the {}-byte limit", len, max_size);
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > max_size {
+            bail!("Playlist is over the {}-byte limit", max_size);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    if let Some(content_type) = content_type.filter(|ct| ct.contains("html")) {
+        let text = body.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&body).trim_ascii_start();
+        if !text.starts_with(b"#EXTM3U") {
+            bail!(
+                "Server returned non-playlist content (Content-Type {}, starts with {:?})",
+                content_type,
+                snippet(text, 80)
+            );
+        }
+    }
+
+    Ok((body, final_url))
+}
+
+/// Oldest FFmpeg the generated command lines are known to work with.
+const MIN_FFMPEG_VERSION: (u32, u32) = (4, 0);
+
+/// What the installed FFmpeg offers, from `ffmpeg -version`, `-encoders` and `-bsfs`.
+struct FfmpegBuild {
+    /// `(major, minor)`; `None` for git snapshots (`N-12345-g...`), assumed to be recent.
+    version: Option<(u32, u32)>,
+    encoders: Vec<String>,
+    bitstream_filters: Vec<String>,
+}
+
+impl FfmpegBuild {
+    /// `None` when there is no working `ffmpeg` on the PATH.
+    async fn probe() -> Option<Self> {
+        let output = Command::new("ffmpeg").arg("-version").output().await.ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let version = parse_ffmpeg_version(&String::from_utf8_lossy(&output.stdout));
+        Some(Self {
+            version,
+            encoders: ffmpeg_list("-encoders", 1).await,
+            bitstream_filters: ffmpeg_list("-bsfs", 0).await,
+        })
+    }
+
+    fn has_encoder(&self, name: &str) -> bool {
+        self.encoders.iter().any(|encoder| encoder == name)
+    }
+
+    fn acceleration(&self) -> AccelType {
+        if self.has_encoder("h264_nvenc") {
+            AccelType::Nvidia
+        } else if self.has_encoder("h264_amf") {
+            AccelType::AMD
+        } else {
+            AccelType::CPU
+        }
+    }
+
+    /// Fails with what is missing when this build can't run the pipeline `options` ask for,
+    /// instead of FFmpeg failing halfway through the transcode.
+    fn check(&self, options: &DownloadOptions) -> Result<()> {
+        let version = self.version.unwrap_or((u32::MAX, 0));
+        if version < MIN_FFMPEG_VERSION {
+            bail!(
+                "FFmpeg {}.{} is too old; version {}.{} or newer is needed",
+                version.0,
+                version.1,
+                MIN_FFMPEG_VERSION.0,
+                MIN_FFMPEG_VERSION.1
+            );
+        }
+        if is_remux(options) {
+            if !self.bitstream_filters.iter().any(|bsf| bsf == "aac_adtstoasc") {
+                bail!("FFmpeg is missing the aac_adtstoasc bitstream filter; install a full build");
+            }
+            return Ok(());
+        }
+        if self.acceleration() == AccelType::CPU && !self.has_encoder("libx264") {
+            bail!(
+                "FFmpeg has no H.264 encoder (libx264, h264_nvenc or h264_amf); install a build \
+                 with libx264 or set stream_copy"
+            );
+        }
+        if !self.has_encoder("aac") {
+            bail!("FFmpeg is missing the aac encoder; install a full build or set stream_copy");
+        }
+        if options.frame_reduction == Some(FrameReduction::KeyframesOnly) && version < (5, 1) {
+            bail!("Keyframe-only output needs FFmpeg 5.1 or newer (for -fps_mode)");
+        }
+        Ok(())
+    }
+}
+
+/// `(major, minor)` from the `ffmpeg version 6.1.1-...` banner line; release builds may
+/// prefix an `n`.
+fn parse_ffmpeg_version(banner: &str) -> Option<(u32, u32)> {
+    let version = banner.lines().next()?.strip_prefix("ffmpeg version ")?;
+    let version = version.strip_prefix('n').unwrap_or(version);
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().and_then(|minor| minor.parse().ok()).unwrap_or(0);
+    Some((major, minor))
+}
+
+/// Names from one of FFmpeg's listings (`-encoders`, `-bsfs`): the `column`th word of every
+/// line after the header. Empty when FFmpeg can't be run.
+async fn ffmpeg_list(flag: &str, column: usize) -> Vec<String> {
+    let Ok(output) = Command::new("ffmpeg").args(["-hide_banner", flag]).output().await else {
+        return Vec::new();
+    };
+    let list = String::from_utf8_lossy(&output.stdout);
+    // `-encoders` ends its legend with a ` ------` line; `-bsfs` starts with a title line.
+    let body = list.split_once(" ------\n").map_or(list.as_ref(), |(_, body)| body);
+    body.lines()
+        .filter_map(|line| line.split_whitespace().nth(column))
+        .map(str::to_string)
+        .collect()
+}
 
-    Ok(PathBuf::from(path_jstring.to_string_lossy().to_string()))
+async fn select_transcoder_backend(options: &DownloadOptions) -> Result<TranscoderKind> {
+    if let Some(build) = FfmpegBuild::probe().await {
+        match build.check(options) {
+            Ok(()) => return Ok(TranscoderKind::Ffmpeg(build.acceleration())),
+            Err(e) if !cfg!(target_os = "android") => return Err(e),
+            Err(e) => warn!("{:#}; trying the Android hardware backend instead", e),
+        }
+    }
+
+    if cfg!(target_os = "android") {
+        #[cfg(target_os = "android")]
+        {
+            if ANDROID_HW_TRANSCODER.get().is_none() {
+                bail!(
+                    "Android MediaCodec transcoder not registered. Ensure System.loadLibrary(\"rust_lib_m3u8_downloader\") 
+                    is called in your Android app before using this library."
+                );
+            }
+        }
+        return Ok(TranscoderKind::AndroidHardware);
+    }
+
+    bail!("FFmpeg not found and not running on Android; no available transcoder");
 }
 
-#[cfg(target_os = "android")]
-pub fn get_external_files_dir() -> Result<PathBuf> {
-    let ctx_data = get_android_context()?;
-    let mut env = ctx_data
-        .jvm
-        .attach_current_thread()
-        .map_err(|e| anyhow!("JNI attach thread failed: {}", e))?;
+/// Where [`download_and_merge`] puts the downloaded segments.
+enum MergeTarget<'a> {
+    /// Segment files in the temp dir, merged into this TS file unless `segments_only`.
+    File(&'a str),
+    /// Sent in playlist order to a pipelined transcode; nothing is written to disk.
+    Pipe(tokio::sync::mpsc::Sender<Vec<u8>>),
+}
 
-    let context_obj = ctx_data.app_context.as_obj();
+/// The pipelined transcoder hung up before every segment was sent to it.
+#[derive(Debug)]
+struct PipeClosed;
 
-    let ext_files_call = env.call_method(
-        context_obj,
-        "getExternalFilesDir",
-        "(Ljava/lang/String;)Ljava/io/File;",
-        &[JValue::Object(&JObject::null())],
-    );
+impl std::fmt::Display for PipeClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FFmpeg stopped reading its input")
+    }
+}
 
-    let ext_files_obj = match ext_files_call {
-        Ok(v) => v
-            .l()
-            .map_err(|e| anyhow!("JNI getExternalFilesDir returned invalid object: {}", e))?,
+impl std::error::Error for PipeClosed {}
+
+async fn download_and_merge(
+    playlist: m3u8_rs::MediaPlaylist,
+    base_url: Option<Url>,
+    origin: &PlaylistOrigin,
+    ctx: &RunContext,
+    reporter: &Reporter,
+    target: MergeTarget<'_>,
+    temp_dir: &Path,
+) -> Result<DownloadStats> {
+    let options = &ctx.options;
+    let multi_progress = &ctx.progress;
+    let resume = options.resume && matches!(target, MergeTarget::File(_));
+
+    // 纭繚涓存椂鐩綍瀛樺湪涓斿彲鍐�
+    if !temp_dir.exists() {
+        fs::create_dir_all(temp_dir)
+            .await
+            .with_context(|| format!("Failed to create temp dir: {}", temp_dir.display()))?;
+    }
+
+    if playlist.segments.is_empty() {
+        bail!("MediaPlaylist contains no segments");
+    }
+    // EXT-X-GAP segments are intentionally missing: they are neither requested nor merged,
+    // so the output simply has a hole in its timestamps there.
+    let gaps: BTreeSet<usize> = playlist
+        .segments
+        .iter()
+        .enumerate()
+        .filter(|(_, seg)| is_gap(seg))
+        .map(|(idx, _)| idx)
+        .collect();
+    if gaps.len() == playlist.segments.len() {
+        bail!("Every segment of the playlist is marked EXT-X-GAP");
+    }
+    if !gaps.is_empty() {
+        info!("Skipping {} EXT-X-GAP segments", gaps.len());
+    }
+    let total = playlist.segments.len() - gaps.len();
+
+    let download_clock = reporter.phase_started(Phase::Download);
+    let space_guard = options.min_free_space.map(|min_free| SpaceGuard {
+        dir: temp_dir.to_path_buf(),
+        min_free,
+        timeout: options.low_space_timeout,
+        low: AtomicBool::new(false),
+    });
+
+    let mut byte_progress = match options.progress_unit {
+        ProgressUnit::Segments => None,
+        ProgressUnit::Auto => estimated_size(&playlist).map(|size| ByteProgress::new(Some(size))),
+        ProgressUnit::Bytes => Some(ByteProgress::new(estimated_size(&playlist))),
+    };
+    if let Some(progress) = &mut byte_progress {
+        progress.total_secs = (0..playlist.segments.len())
+            .filter(|idx| !gaps.contains(idx))
+            .map(|idx| segment_duration(&playlist, &playlist.segments[idx]))
+            .sum();
+    }
+    let download_pb = match byte_progress {
+        Some(_) => multi_progress.bar(
+            0,
+            "{msg} [{elapsed_precise}] {bar:40.cyan/blue} {bytes:>10}/{total_bytes:10} \
+             ({bytes_per_sec}) {eta}",
+        )?,
+        None => multi_progress.bar(
+            total as u64,
+            "{msg} [{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} ({percent}%) {eta}",
+        )?,
+    };
+    download_pb.set_message("Downloading segments");
+
+    let encrypted = playlist.segments.iter().any(|seg| {
+        seg.key.as_ref().is_some_and(|key| key.method != m3u8_rs::KeyMethod::None)
+    });
+    let keys_clock = (encrypted && options.decryption_key.is_none()).then(|| {
+        reporter.progress("Fetching decryption keys...", 0.02);
+        reporter.phase_started(Phase::FetchKeys)
+    });
+    let mut jobs =
+        segment_jobs(&playlist, base_url.as_ref(), &ctx.keys, &ctx.client, options)
+            .await?;
+    if let Some(clock) = keys_clock {
+        reporter.phase_ended(Phase::FetchKeys, clock);
+    }
+
+    let compress = options.compress_temp;
+    let extension = segment_extension(&playlist);
+    let manifest_path = temp_dir.join(MANIFEST_FILE_NAME);
+    // `segments_only` output is the user's own directory and keeps the documented names.
+    let prefix = if options.segments_only { "" } else { ctx.temp_prefix.as_str() };
+    let group_size = options.segment_group_size.filter(|_| !options.segments_only);
+    let group_size = group_size.map(|size| size.max(1));
+    let rebaser = TimestampRebaser::new(&playlist)
+        .filter(|_| options.fix_timestamps && !options.segments_only);
+    let mut manifest = DownloadManifest::new(origin, &playlist, compress, prefix, group_size);
+    if rebaser.is_some() {
+        info!("Rebasing timestamps at each discontinuity");
+        manifest.timestamp_offsets = Some(BTreeMap::new());
+    }
+    if resume {
+        resume_from_manifest(&manifest_path, &mut manifest, temp_dir, extension).await;
+        if !manifest.completed.is_empty() {
+            info!(
+                "Resuming: {} of {} segments already downloaded",
+                manifest.completed.len(),
+                total
+            );
+            jobs.retain(|job| !manifest.completed.contains(&job.idx));
+        }
+        manifest.save(&manifest_path).await?;
+    }
+    match &mut byte_progress {
+        Some(progress) => {
+            let mut counted = BTreeSet::new();
+            for &idx in &manifest.completed {
+                let name = manifest.file_name(idx, extension);
+                let size = match counted.insert(name.clone()) {
+                    true => fs::metadata(temp_dir.join(name)).await.map_or(0, |meta| meta.len()),
+                    false => 0,
+                };
+                progress.add(segment_duration(&playlist, &playlist.segments[idx]), size);
+            }
+            progress.update(&download_pb);
+        }
+        None => download_pb.set_position(manifest.completed.len() as u64),
+    }
+
+    let fetcher = Arc::new(SegmentFetcher::new(
+        ctx.client.clone(),
+        ctx.semaphore.clone(),
+        options,
+        space_guard,
+        reporter.clone(),
+        download_pb.clone(),
+    ));
+
+    // Group files are appended to, and rebasing takes a group's offset from its first
+    // segment, which both need the segments in order.
+    let ordered =
+        matches!(target, MergeTarget::Pipe(_)) || group_size.is_some() || rebaser.is_some();
+    let urls: HashMap<usize, String> = jobs.iter().map(|job| (job.idx, job.url.clone())).collect();
+    let mut segments = fetch_segments(fetcher.clone(), jobs, options, ordered);
+    let mut last_save = Instant::now();
+    let mut group: Option<OpenGroup> = None;
+    let mut done = manifest.completed.len();
+    while let Some(segment) = segments.next().await {
+        let (idx, mut buf) = segment?;
+        if let (Some(rebaser), Some(offsets)) = (&rebaser, &mut manifest.timestamp_offsets) {
+            rebaser.rebase(idx, &mut buf, offsets);
+        }
+        if let Some(progress) = &mut byte_progress {
+            progress.add(segment_duration(&playlist, &playlist.segments[idx]), buf.len() as u64);
+        }
+
+        // Only fully written segments are marked; saving is throttled since the manifest is
+        // rewritten whole, and an unmarked segment is merely downloaded again.
+        if let MergeTarget::Pipe(tx) = &target {
+            tx.send(buf).await.map_err(|_| PipeClosed)?;
+            manifest.completed.insert(idx);
+        } else {
+            // 鉁� 鍏抽敭淇锛氬垎鐗囧啓鍏� temp_dir 涓�
+            let tmp_path = temp_dir.join(manifest.file_name(idx, extension));
+            let buf =
+                if compress { run_blocking(move || compress_segment(buf)).await? } else { buf };
+            if let Some(size) = group_size {
+                // A group is complete once the first segment of the next one arrives.
+                if let Some(closed) = group.take_if(|open| open.group != idx / size) {
+                    manifest.completed.extend(closed.close(options.fsync).await?);
+                }
+                if group.is_none() {
+                    group = Some(OpenGroup::create(idx / size, tmp_path).await?);
+                }
+                if let Some(open) = &mut group {
+                    open.append(idx, &buf).await?;
+                }
+            } else {
+                write_file(&tmp_path, &buf, options.fsync)
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Failed to write segment: {} (url: {})",
+                            tmp_path.display(),
+                            urls.get(&idx).map_or("?", String::as_str)
+                        )
+                    })?;
+                manifest.completed.insert(idx);
+            }
+        }
+        done += 1;
+
+        if resume && last_save.elapsed() >= Duration::from_secs(1) {
+            manifest.save(&manifest_path).await?;
+            last_save = Instant::now();
+        }
+
+        let count = done;
+        let fraction = match &byte_progress {
+            Some(progress) => progress.update(&download_pb),
+            None => {
+                download_pb.set_position(count as u64);
+                (count as f64) / (total as f64)
+            }
+        };
+        download_pb.set_message(format!("Downloading segments [{}/{}]", count, total));
+        reporter.progress(format!("Downloading segments [{}/{}]", count, total), fraction * 0.9);
+    }
+
+    if let Some(open) = group {
+        manifest.completed.extend(open.close(options.fsync).await?);
+    }
+    download_pb.finish_with_message("All segments downloaded");
+    let mut stats = fetcher.collector.finish(download_clock.1.elapsed());
+    stats.gap_segments = gaps.len() as u64;
+    reporter.phase_ended(Phase::Download, download_clock);
+    if resume {
+        manifest.save(&manifest_path).await?;
+    }
+
+    // Dropping the sender ends FFmpeg's input.
+    let MergeTarget::File(output_file) = target else {
+        return Ok(stats);
+    };
+    if options.segments_only {
+        let _ = fs::remove_file(&manifest_path).await;
+        return Ok(stats);
+    }
+
+    // Segment and group files are named by playlist index, so this is the merge order.
+    let mut written: Vec<PathBuf> = (0..playlist.segments.len())
+        .filter(|idx| !gaps.contains(idx))
+        .map(|idx| temp_dir.join(manifest.file_name(idx, extension)))
+        .collect();
+    written.dedup();
+    let merge_clock = reporter.phase_started(Phase::Merge);
+    merge_segments(written, output_file, None, true, options, multi_progress).await?;
+    reporter.phase_ended(Phase::Merge, merge_clock);
+    let _ = fs::remove_file(&manifest_path).await;
+    Ok(stats)
+}
+
+/// The group file segments are being appended to with `segment_group_size`.
+struct OpenGroup {
+    group: usize,
+    path: PathBuf,
+    file: fs::File,
+    members: Vec<usize>,
+}
+
+impl OpenGroup {
+    async fn create(group: usize, path: PathBuf) -> Result<Self> {
+        let file = fs::File::create(&path)
+            .await
+            .with_context(|| format!("Failed to create segment group: {}", path.display()))?;
+        Ok(Self { group, path, file, members: Vec::new() })
+    }
+
+    async fn append(&mut self, idx: usize, data: &[u8]) -> Result<()> {
+        self.file
+            .write_all(data)
+            .await
+            .with_context(|| format!("Failed to write segment group: {}", self.path.display()))?;
+        self.members.push(idx);
+        Ok(())
+    }
+
+    /// Finishes the file and returns the segments it holds.
+    async fn close(mut self, sync: bool) -> Result<Vec<usize>> {
+        let written = async {
+            self.file.flush().await?;
+            if sync {
+                self.file.sync_all().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        written
+            .await
+            .with_context(|| format!("Failed to write segment group: {}", self.path.display()))?;
+        Ok(self.members)
+    }
+}
+
+const MANIFEST_FILE_NAME: &str = "download_manifest.json";
+const MANIFEST_VERSION: u32 = 1;
+
+/// Resume state kept next to the segments with `resume`: which playlist the segment files
+/// belong to and which of them are complete.
+#[derive(Debug, Serialize, Deserialize)]
+struct DownloadManifest {
+    version: u32,
+    playlist_url: String,
+    variant: Option<String>,
+    segment_count: usize,
+    /// Hash of the segment URIs, byte ranges and keys, to notice a changed playlist.
+    fingerprint: String,
+    /// `METHOD uri` of every distinct key, for reference.
+    keys: Vec<String>,
+    compressed: bool,
+    /// Prefix of the segment file names; absent in manifests written before it existed.
+    #[serde(default)]
+    temp_prefix: String,
+    /// `segment_group_size` the files were written with.
+    #[serde(default)]
+    group_size: Option<usize>,
+    /// Offsets a [`TimestampRebaser`] applied, by discontinuity group; `None` when the
+    /// segments were written as downloaded.
+    #[serde(default)]
+    timestamp_offsets: Option<BTreeMap<usize, i64>>,
+    completed: BTreeSet<usize>,
+}
+
+impl DownloadManifest {
+    fn new(
+        origin: &PlaylistOrigin,
+        playlist: &m3u8_rs::MediaPlaylist,
+        compressed: bool,
+        temp_prefix: &str,
+        group_size: Option<usize>,
+    ) -> Self {
+        let mut keys: Vec<String> = Vec::new();
+        for key in playlist.segments.iter().filter_map(|seg| seg.key.as_ref()) {
+            let desc = format!("{} {}", key.method, key.uri.as_deref().unwrap_or("-"));
+            if !keys.contains(&desc) {
+                keys.push(desc);
+            }
+        }
+        Self {
+            version: MANIFEST_VERSION,
+            playlist_url: origin.url.clone(),
+            variant: origin.variant.clone(),
+            segment_count: playlist.segments.len(),
+            fingerprint: playlist_fingerprint(playlist),
+            keys,
+            compressed,
+            temp_prefix: temp_prefix.to_string(),
+            group_size,
+            timestamp_offsets: None,
+            completed: BTreeSet::new(),
+        }
+    }
+
+    /// Temp file holding segment `idx`: its own, or its group's with a `group_size`.
+    fn file_name(&self, idx: usize, extension: &str) -> String {
+        match self.group_size {
+            Some(size) => {
+                group_file_name(&self.temp_prefix, idx / size, extension, self.compressed)
+            }
+            None => segment_file_name(&self.temp_prefix, idx, extension, self.compressed),
+        }
+    }
+
+    /// Whether segment files written for `self` are valid for `other`. URLs aren't compared:
+    /// signed playlist URLs change between runs while the segments stay the same.
+    fn same_segments(&self, other: &DownloadManifest) -> bool {
+        self.segment_count == other.segment_count
+            && self.fingerprint == other.fingerprint
+            && self.compressed == other.compressed
+            && self.group_size == other.group_size
+            && self.timestamp_offsets.is_some() == other.timestamp_offsets.is_some()
+    }
+
+    /// Writes the manifest through a temp file so a crash never leaves it half-written.
+    async fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)
+            .await
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Marks in `manifest` the segments a previous run's manifest recorded as complete and whose
+/// files still exist, taking over that run's file prefix. Any mismatch (other playlist,
+/// manifest version, compression) resumes nothing.
+async fn resume_from_manifest(
+    path: &Path,
+    manifest: &mut DownloadManifest,
+    temp_dir: &Path,
+    extension: &str,
+) {
+    let Ok(content) = fs::read(path).await else {
+        return;
+    };
+    let saved: DownloadManifest = match serde_json::from_slice(&content) {
+        Ok(saved) => saved,
         Err(e) => {
-            warn!("JNI getExternalFilesDir call failed: {}", e);
-            return get_app_files_dir();
+            warn!("Ignoring unreadable download manifest {}: {}", path.display(), e);
+            return;
+        }
+    };
+    if saved.version != MANIFEST_VERSION {
+        warn!("Ignoring download manifest version {}", saved.version);
+        return;
+    }
+    if !saved.same_segments(manifest) {
+        warn!("Playlist changed since the previous run; downloading all segments again");
+        return;
+    }
+
+    let mut completed = BTreeSet::new();
+    for &idx in saved.completed.iter().filter(|idx| **idx < manifest.segment_count) {
+        let name = saved.file_name(idx, extension);
+        if fs::metadata(temp_dir.join(name)).await.is_ok_and(|meta| meta.len() > 0) {
+            completed.insert(idx);
+        }
+    }
+    if !completed.is_empty() {
+        manifest.temp_prefix = saved.temp_prefix;
+        manifest.timestamp_offsets = saved.timestamp_offsets;
+        manifest.completed = completed;
+    }
+}
+
+/// 64-bit FNV-1a over everything that determines the segment files' contents.
+fn playlist_fingerprint(playlist: &m3u8_rs::MediaPlaylist) -> String {
+    let mut hash = Fingerprint::default();
+    for seg in &playlist.segments {
+        hash.feed(seg.uri.as_bytes());
+        if let Some(range) = &seg.byte_range {
+            hash.feed(format!("{}@{:?}", range.length, range.offset).as_bytes());
+        }
+        if let Some(key) = &seg.key {
+            let iv = key.iv.as_deref().unwrap_or("");
+            hash.feed(format!("{} {:?} {}", key.method, key.uri, iv).as_bytes());
+        }
+    }
+    hash.finish()
+}
+
+/// FNV-1a over a sequence of fields, each terminated so `["ab", "c"]` and `["a", "bc"]`
+/// differ. Stable across runs and builds, unlike `DefaultHasher`.
+struct Fingerprint(u64);
+
+impl Default for Fingerprint {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fingerprint {
+    fn feed(&mut self, bytes: &[u8]) {
+        for b in bytes.iter().chain([&0xff]) {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+/// One segment request: its playlist index, resolved URL, byte range and AES-128 key/IV.
+struct SegmentJob {
+    idx: usize,
+    url: String,
+    range: Option<(u64, u64)>,
+    key: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Resolves the URL, byte range and key of every segment, fetching keys as needed.
+async fn segment_jobs(
+    playlist: &m3u8_rs::MediaPlaylist,
+    base_url: Option<&Url>,
+    keys: &KeyCache,
+    client: &Client,
+    options: &DownloadOptions,
+) -> Result<Vec<SegmentJob>> {
+    let segments = &playlist.segments;
+    let total = segments.len();
+
+    // 澶勭悊 AES-128 鍔犲瘑
+    // EXT-X-KEY applies to every following segment until the next EXT-X-KEY.
+    // A caller-supplied key replaces the playlist's keys for the whole stream.
+    let override_key = options.decryption_key.clone();
+    let mut segment_keys: Vec<Option<(Vec<u8>, Vec<u8>)>> = Vec::with_capacity(total);
+    let mut current_key: Option<&m3u8_rs::Key> = None;
+    for (idx, seg) in segments.iter().enumerate() {
+        if let Some(key_def) = &seg.key {
+            current_key = Some(key_def);
+        }
+        // Without an IV attribute the IV is the segment's media sequence number, which counts
+        // from EXT-X-MEDIA-SEQUENCE rather than from the start of this playlist.
+        let sequence = playlist.media_sequence + idx as u64;
+        // METHOD=NONE switches the following segments back to clear text.
+        let Some(key_def) = current_key.filter(|k| k.method != m3u8_rs::KeyMethod::None) else {
+            segment_keys.push(None);
+            continue;
+        };
+        if let Some(key_bytes) = &override_key {
+            let iv_bytes = match (&options.decryption_iv, &key_def.iv) {
+                (Some(iv), _) => iv.clone(),
+                (None, Some(iv_hex)) => parse_iv(iv_hex)?,
+                (None, None) => sequence_iv(sequence),
+            };
+            segment_keys.push(Some((key_bytes.clone(), iv_bytes)));
+            continue;
+        }
+        if key_def.method != m3u8_rs::KeyMethod::AES128 {
+            bail!("Unsupported encryption method {}", key_def.method);
+        }
+        let key_uri = key_def
+            .uri
+            .as_deref()
+            .ok_or_else(|| anyhow!("Found encrypted stream but key.uri is empty"))?;
+        let mut key_url = resolve_uri(base_url, key_uri, "Key")?;
+        if options.inherit_query {
+            inherit_query(&mut key_url, key_uri, base_url);
+        }
+        let key_format = key_def.keyformat.as_deref();
+        let key_bytes = keys.get_or_fetch(client, &key_url, key_format, options).await?;
+
+        let iv_bytes = match &key_def.iv {
+            Some(iv_hex) => parse_iv(iv_hex)?,
+            None => sequence_iv(sequence),
+        };
+
+        segment_keys.push(Some((key_bytes, iv_bytes)));
+    }
+
+    // EXT-X-BYTERANGE without an offset continues where the previous range of the same URI ended.
+    let mut next_offsets: HashMap<String, u64> = HashMap::new();
+    let ranges: Vec<Option<(u64, u64)>> = segments
+        .iter()
+        .map(|seg| {
+            seg.byte_range.as_ref().map(|r| {
+                let start = r
+                    .offset
+                    .unwrap_or_else(|| next_offsets.get(&seg.uri).copied().unwrap_or(0));
+                next_offsets.insert(seg.uri.clone(), start + r.length);
+                (start, start + r.length.saturating_sub(1))
+            })
+        })
+        .collect();
+
+    let seg_urls = segments
+        .iter()
+        .map(|seg| {
+            let mut url = resolve_uri(base_url, &seg.uri, "Segment")?;
+            if options.inherit_query {
+                inherit_query(&mut url, &seg.uri, base_url);
+            }
+            Ok(String::from(url))
+        })
+        .collect::<Result<Vec<String>>>()?;
+
+    Ok(seg_urls
+        .into_iter()
+        .zip(ranges)
+        .zip(segment_keys)
+        .enumerate()
+        .filter(|(idx, _)| !is_gap(&segments[*idx]))
+        .map(|(idx, ((url, range), key))| SegmentJob { idx, url, range, key })
+        .collect())
+}
+
+/// Whether a segment is tagged EXT-X-GAP, i.e. deliberately unavailable.
+fn is_gap(segment: &m3u8_rs::MediaSegment) -> bool {
+    segment.unknown_tags.iter().any(|tag| tag.tag == "X-GAP")
+}
+
+/// Each segment's bits per second from EXT-X-BITRATE (m3u8-rs leaves it among the unknown
+/// tags). A hint is in kbps and applies until the next one, but not to byte-range segments.
+fn segment_bitrate_hints(playlist: &m3u8_rs::MediaPlaylist) -> Vec<Option<u64>> {
+    let mut current = None;
+    playlist
+        .segments
+        .iter()
+        .map(|seg| {
+            if let Some(tag) = seg.unknown_tags.iter().rfind(|tag| tag.tag == "X-BITRATE") {
+                current = tag
+                    .rest
+                    .as_deref()
+                    .and_then(|kbps| kbps.trim().parse::<u64>().ok())
+                    .map(|kbps| kbps * 1000);
+            }
+            current.filter(|_| seg.byte_range.is_none())
+        })
+        .collect()
+}
+
+/// Total bits and seconds of the non-gap segments according to their EXT-X-BITRATE hints, or
+/// `None` unless every one of them has a hint.
+fn bitrate_hint_totals(playlist: &m3u8_rs::MediaPlaylist) -> Option<(f64, f64)> {
+    let mut totals = (0.0, 0.0);
+    for (seg, bps) in playlist.segments.iter().zip(segment_bitrate_hints(playlist)) {
+        if is_gap(seg) {
+            continue;
+        }
+        let secs = segment_duration(playlist, seg);
+        totals.0 += bps? as f64 * secs;
+        totals.1 += secs;
+    }
+    Some(totals).filter(|(_, secs)| *secs > 0.0)
+}
+
+/// Bytes the non-gap segments add up to: exact when every one has a byte range, otherwise
+/// estimated from EXT-X-BITRATE hints.
+fn estimated_size(playlist: &m3u8_rs::MediaPlaylist) -> Option<u64> {
+    let ranged: Option<u64> = playlist
+        .segments
+        .iter()
+        .filter(|seg| !is_gap(seg))
+        .map(|seg| seg.byte_range.as_ref().map(|range| range.length))
+        .sum();
+    ranged.or_else(|| bitrate_hint_totals(playlist).map(|(bits, _)| (bits / 8.0) as u64))
+}
+
+/// Download progress in bytes against the playlist's [`estimated_size`] or, lacking one,
+/// against the size extrapolated from the media duration downloaded so far.
+struct ByteProgress {
+    estimate: Option<u64>,
+    total_secs: f64,
+    done_secs: f64,
+    bytes: u64,
+}
+
+impl ByteProgress {
+    fn new(estimate: Option<u64>) -> Self {
+        Self { estimate, total_secs: 0.0, done_secs: 0.0, bytes: 0 }
+    }
+
+    fn add(&mut self, secs: f64, bytes: u64) {
+        self.done_secs += secs;
+        self.bytes += bytes;
+    }
+
+    /// Best guess of the total, never below what has already arrived.
+    fn total(&self) -> u64 {
+        let guess = match self.estimate {
+            Some(estimate) => estimate,
+            None if self.done_secs > 0.0 => {
+                (self.bytes as f64 * self.total_secs / self.done_secs) as u64
+            }
+            None => 0,
+        };
+        guess.max(self.bytes)
+    }
+
+    /// Moves `pb` to the current byte counts and returns the completed fraction.
+    fn update(&self, pb: &ProgressBar) -> f64 {
+        let total = self.total();
+        pb.set_length(total);
+        pb.set_position(self.bytes);
+        if total > 0 {
+            self.bytes as f64 / total as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Downloads single segments with retries, decryption and stats, shared by all of a
+/// playlist's segment tasks.
+struct SegmentFetcher {
+    client: Arc<Client>,
+    semaphore: Arc<Semaphore>,
+    retries: u8,
+    retry_predicate: Option<RetryPredicate>,
+    url_signer: Option<UrlSigner>,
+    hosts: HostFilter,
+    split_threshold: Option<u64>,
+    split_parts: usize,
+    validation: SegmentValidation,
+    /// Set once the server definitively turned down range requests (`Accept-Ranges: none`
+    /// or a 200 to a range request), to stop probing with HEAD.
+    ranges_unsupported: AtomicBool,
+    space_guard: Option<SpaceGuard>,
+    collector: StatsCollector,
+    reporter: Reporter,
+    progress: ProgressBar,
+}
+
+/// Why one download attempt failed.
+enum AttemptError {
+    /// Worth another attempt (network error, retryable status, truncated body).
+    Retry(anyhow::Error),
+    /// The retry predicate gave up on this response.
+    Fatal(anyhow::Error),
+}
+
+impl SegmentFetcher {
+    fn new(
+        client: Arc<Client>,
+        semaphore: Arc<Semaphore>,
+        options: &DownloadOptions,
+        space_guard: Option<SpaceGuard>,
+        reporter: Reporter,
+        progress: ProgressBar,
+    ) -> Self {
+        Self {
+            client,
+            semaphore,
+            // Like the playlist fetch, 0 still makes one attempt.
+            retries: options.retries.max(1),
+            retry_predicate: options.retry_predicate.clone(),
+            url_signer: options.url_signer.clone(),
+            hosts: HostFilter::new(&options.network),
+            split_threshold: options.split_threshold,
+            split_parts: options.split_parts,
+            validation: options.segment_validation,
+            ranges_unsupported: AtomicBool::new(false),
+            space_guard,
+            collector: StatsCollector::default(),
+            reporter,
+            progress,
+        }
+    }
+
+    /// Downloads and decrypts one segment, holding it whole in memory; only segment files
+    /// merged by [`merge_and_convert`] are decrypted a chunk at a time.
+    async fn fetch(&self, job: SegmentJob) -> Result<Vec<u8>> {
+        let SegmentJob { url: seg_url, range, key, .. } = job;
+        let retries = self.retries;
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .map_err(|_| anyhow!("Semaphore acquire failed"))?;
+
+        if let Some(guard) = &self.space_guard {
+            guard.wait(&self.reporter).await?;
+        }
+
+        for attempt in 1..=retries {
+            let request_started = Instant::now();
+            let request_url = UrlSigner::sign(self.url_signer.as_ref(), &seg_url)?;
+            self.hosts.check(&Url::parse(&request_url)?, "Segment")?;
+            // A truncated body shows up as a read or decrypt error; fetching the segment
+            // again usually fixes it, so both are retryable.
+            let result = match self.download(&request_url, range).await {
+                Ok(data) => {
+                    let key = key.clone();
+                    run_blocking(move || {
+                        let mut buf = data;
+                        if let Some((k, iv)) = key {
+                            decrypt_segment(&k, &iv, &mut buf)?;
+                        }
+                        Ok(buf)
+                    })
+                    .await
+                }
+                Err(AttemptError::Fatal(e)) => {
+                    bail!("Segment request failed permanently: {} {:#}", seg_url, e);
+                }
+                Err(AttemptError::Retry(e)) => Err(e),
+            };
+
+            match result {
+                Ok(buf) => {
+                    self.collector.record_segment(buf.len(), request_started.elapsed(), attempt);
+                    return Ok(buf);
+                }
+                Err(e) => {
+                    self.collector.retries.fetch_add(1, Ordering::Relaxed);
+                    self.progress.set_message(format!("Retrying... ({}/{})", attempt, retries));
+                    warn!("Attempt {} failed: {} - {:#}", attempt, seg_url, e);
+                }
+            }
+
+            if attempt < retries {
+                tokio::time::sleep(Duration::from_millis(2000)).await;
+            }
+        }
+
+        bail!("Failed after {} attempts: {}", retries, seg_url)
+    }
+
+    /// One attempt at a segment's (still encrypted) bytes.
+    async fn download(
+        &self,
+        url: &str,
+        range: Option<(u64, u64)>,
+    ) -> std::result::Result<Vec<u8>, AttemptError> {
+        if let (Some(threshold), None) = (self.split_threshold, range) {
+            if !self.ranges_unsupported.load(Ordering::Relaxed) {
+                match self.download_split(url, threshold).await {
+                    Ok(Some(data)) => return self.validate(url, None, data),
+                    Ok(None) => {}
+                    Err(e) => warn!("Split download of {} failed, using one request: {:#}", url, e),
+                }
+            }
+        }
+
+        // Redirects, relative ones included, are followed by the client's redirect policy.
+        let mut request = self.client.get(url);
+        if let Some((start, end)) = range {
+            request = request.header(header::RANGE, format!("bytes={}-{}", start, end));
+        }
+        let resp = request
+            .send()
+            .await
+            .map_err(|e| AttemptError::Retry(request_error(e, "Segment request")))?;
+        let status = resp.status();
+        match RetryPredicate::decide(self.retry_predicate.as_ref(), &resp) {
+            RetryDecision::Success => {
+                let content_type = resp
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+                // A server that ignores Range answers 200 with the whole resource.
+                let whole = range.is_some() && status == reqwest::StatusCode::OK;
+                let body_range = if whole { None } else { range };
+                let data = self.read_body(resp, body_range).await.map_err(AttemptError::Retry)?;
+                let data = match range.filter(|_| whole) {
+                    Some(range) => slice_range(data, range).map_err(AttemptError::Retry)?,
+                    None => data,
+                };
+                self.validate(url, content_type.as_deref(), data)
+            }
+            RetryDecision::Fatal => Err(AttemptError::Fatal(anyhow!("HTTP {}", status))),
+            RetryDecision::Retry => Err(AttemptError::Retry(anyhow!("HTTP {}", status))),
+        }
+    }
+
+    /// Applies `segment_validation` to a downloaded body.
+    fn validate(
+        &self,
+        url: &str,
+        content_type: Option<&str>,
+        data: Vec<u8>,
+    ) -> std::result::Result<Vec<u8>, AttemptError> {
+        if self.validation == SegmentValidation::Off {
+            return Ok(data);
         }
-    };
+        if let Some(problem) = segment_problem(content_type, &data) {
+            if self.validation == SegmentValidation::Strict {
+                return Err(AttemptError::Retry(anyhow!("Bad segment: {}", problem)));
+            }
+            warn!("Suspicious segment {}: {}", url, problem);
+        }
+        Ok(data)
+    }
 
-    if ext_files_obj.is_null() {
-        warn!("getExternalFilesDir returned null, falling back to app files dir");
-        return get_app_files_dir();
+    /// Reads a segment body. When the connection drops midway and the server does ranges, the
+    /// rest is requested with a Range header (a few times) instead of starting over; a body
+    /// that still comes up short is an error, never a truncated segment.
+    async fn read_body(
+        &self,
+        mut resp: reqwest::Response,
+        range: Option<(u64, u64)>,
+    ) -> Result<Vec<u8>> {
+        // Without Content-Length (e.g. a body reqwest decompressed) offsets can't be trusted.
+        let expected = resp.content_length();
+        let resumable = expected.is_some()
+            && resp
+                .headers()
+                .get(header::ACCEPT_RANGES)
+                .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"bytes"));
+        let target = resp.url().clone();
+        let mut data = Vec::with_capacity(expected.unwrap_or(0) as usize);
+        let mut resumes = 0;
+        loop {
+            let read = async {
+                while let Some(chunk) = resp.chunk().await? {
+                    data.extend_from_slice(&chunk);
+                }
+                Ok::<_, reqwest::Error>(())
+            };
+            match read.await {
+                Ok(()) => break,
+                Err(e) if resumable && !data.is_empty() && resumes < 3 => {
+                    resumes += 1;
+                    let start = range.map_or(0, |(start, _)| start) + data.len() as u64;
+                    let end = range.map(|(_, end)| end.to_string()).unwrap_or_default();
+                    warn!(
+                        "Body of {} cut off after {} bytes ({}), resuming from there",
+                        target,
+                        data.len(),
+                        e
+                    );
+                    resp = self
+                        .client
+                        .get(target.clone())
+                        .header(header::RANGE, format!("bytes={}-{}", start, end))
+                        .send()
+                        .await?;
+                    let resumed_at = resp
+                        .headers()
+                        .get(header::CONTENT_RANGE)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.strip_prefix("bytes "))
+                        .and_then(|v| v.split('-').next())
+                        .and_then(|v| v.parse::<u64>().ok());
+                    if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT
+                        || resumed_at != Some(start)
+                    {
+                        bail!(
+                            "Server didn't resume the body at byte {} (HTTP {})",
+                            start,
+                            resp.status()
+                        );
+                    }
+                }
+                Err(e) => return Err(anyhow!(e).context("Failed to read body")),
+            }
+        }
+        if let Some(expected) = expected.filter(|len| data.len() as u64 != *len) {
+            bail!("Body truncated: got {} of {} bytes", data.len(), expected);
+        }
+        Ok(data)
     }
 
-    let path_str = env
-        .call_method(
-            &ext_files_obj,
-            "getAbsolutePath",
-            "()Ljava/lang/String;",
-            &[],
-        )
-        .map_err(|e| anyhow!("JNI getAbsolutePath call failed: {}", e))?
-        .l()
-        .map_err(|e| anyhow!("JNI getAbsolutePath returned invalid object: {}", e))?;
+    /// Fetches a segment of at least `threshold` bytes as `split_parts` parallel byte ranges.
+    /// `None` when the segment is smaller or the server doesn't do ranges.
+    async fn download_split(&self, url: &str, threshold: u64) -> Result<Option<Vec<u8>>> {
+        let head = self.client.head(url).send().await?;
+        let accept_ranges = head.headers().get(header::ACCEPT_RANGES).map(|v| v.as_bytes());
+        if accept_ranges.is_some_and(|v| v.eq_ignore_ascii_case(b"none")) {
+            self.ranges_unsupported.store(true, Ordering::Relaxed);
+            return Ok(None);
+        }
+        // A failed HEAD or a missing header may be a one-off; the next segment asks again.
+        if !head.status().is_success()
+            || !accept_ranges.is_some_and(|v| v.eq_ignore_ascii_case(b"bytes"))
+        {
+            return Ok(None);
+        }
+        // `content_length()` describes the (empty) HEAD body, so read the header itself.
+        let len = head
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        let Some(len) = len.filter(|len| *len >= threshold.max(1)) else {
+            return Ok(None);
+        };
+
+        let target = head.url().clone();
+        let part_len = len.div_ceil(self.split_parts.max(2) as u64);
+        let parts = (0..len).step_by(part_len as usize).map(|start| {
+            let end = (start + part_len).min(len) - 1;
+            let request = self
+                .client
+                .get(target.clone())
+                .header(header::RANGE, format!("bytes={}-{}", start, end));
+            async move {
+                let resp = request.send().await?;
+                if resp.status() == reqwest::StatusCode::OK {
+                    self.ranges_unsupported.store(true, Ordering::Relaxed);
+                }
+                if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+                    bail!("Range request answered with HTTP {}", resp.status());
+                }
+                let bytes = resp.bytes().await?;
+                if bytes.len() as u64 != end - start + 1 {
+                    bail!("Range {}-{} returned {} bytes", start, end, bytes.len());
+                }
+                Ok(bytes)
+            }
+        });
+        let parts = futures::future::try_join_all(parts).await?;
 
-    let path_jstring = env
-        .get_string((&path_str).into())
-        .map_err(|e| anyhow!("JNI string conversion failed: {}", e))?;
+        let mut data = Vec::with_capacity(len as usize);
+        for part in parts {
+            data.extend_from_slice(&part);
+        }
+        Ok(Some(data))
+    }
+}
 
-    Ok(PathBuf::from(path_jstring.to_string_lossy().to_string()))
+/// Why a successful segment response looks like an error page rather than media, if it does.
+fn segment_problem(content_type: Option<&str>, data: &[u8]) -> Option<String> {
+    if data.is_empty() {
+        return Some("empty body".to_string());
+    }
+    if let Some(content_type) = content_type {
+        let lower = content_type.to_ascii_lowercase();
+        if lower.contains("html") || lower.contains("json") {
+            return Some(format!("Content-Type {}", content_type));
+        }
+    }
+    if looks_like_html(data) || data.starts_with(b"{\"") {
+        return Some(format!("body starts with {:?}", snippet(&data[..data.len().min(40)], 40)));
+    }
+    None
 }
 
-#[cfg(target_os = "android")]
-fn select_writable_temp_dir() -> Result<PathBuf> {
-    info!("Selecting writable temporary directory");
+/// Runs `jobs` with up to `concurrency` in flight and yields each segment's decrypted bytes,
+/// in job order when `ordered` or `download_order` asks for it, otherwise as they finish.
+fn fetch_segments(
+    fetcher: Arc<SegmentFetcher>,
+    jobs: Vec<SegmentJob>,
+    options: &DownloadOptions,
+    ordered: bool,
+) -> stream::BoxStream<'static, Result<(usize, Vec<u8>)>> {
+    let fetches = stream::iter(jobs)
+        .map(move |job| {
+            let fetcher = fetcher.clone();
+            async move {
+                let idx = job.idx;
+                let data = tokio::spawn(async move { fetcher.fetch(job).await })
+                    .await
+                    .context("Segment task panicked")??;
+                Ok((idx, data))
+            }
+        });
+    if ordered || options.download_order == DownloadOrder::Sequential {
+        fetches.buffered(options.look_ahead.unwrap_or(options.concurrency).max(1)).boxed()
+    } else {
+        fetches.buffer_unordered(options.concurrency.max(1)).boxed()
+    }
+}
 
-    let candidates = vec![
-        ("app_cache", get_app_cache_dir()),
-        ("app_files", get_app_files_dir()),
-        ("external_files", get_external_files_dir()),
-    ];
+/// Holds back new segment downloads while free space in `dir` is below `min_free`.
+struct SpaceGuard {
+    dir: PathBuf,
+    min_free: u64,
+    timeout: Duration,
+    low: AtomicBool,
+}
 
-    for (name, result) in candidates {
-        match result {
-            Ok(dir) => {
-                info!("Trying candidate [{}]: {}", name, dir.display());
-                if verify_directory_writable(&dir) {
-                    info!("Selected writable temporary directory: {}", dir.display());
-                    return Ok(dir);
-                } else {
-                    warn!("Directory not writable: {} ({})", dir.display(), name);
+impl SpaceGuard {
+    async fn wait(&self, reporter: &Reporter) -> Result<()> {
+        let started = Instant::now();
+        loop {
+            let Some(available) = available_space(&self.dir) else {
+                return Ok(());
+            };
+            if available >= self.min_free {
+                if self.low.swap(false, Ordering::Relaxed) {
+                    info!("Free space recovered ({} bytes), resuming downloads", available);
+                    reporter.event(DownloadEvent::StorageRecovered { available });
                 }
+                return Ok(());
             }
-            Err(e) => {
-                warn!("Failed to get {}: {}", name, e);
+            if !self.low.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Low storage: {} bytes free, need {}; pausing downloads",
+                    available, self.min_free
+                );
+                reporter.event(DownloadEvent::LowStorage {
+                    available,
+                    required: self.min_free,
+                });
+            }
+            if started.elapsed() >= self.timeout {
+                bail!(
+                    "Free space stayed below {} bytes for {:?}",
+                    self.min_free,
+                    self.timeout
+                );
             }
+            tokio::time::sleep(Duration::from_secs(2)).await;
         }
     }
+}
 
-    let env_temp = env::temp_dir();
-    info!("Trying env::temp_dir(): {}", env_temp.display());
-    if verify_directory_writable(&env_temp) {
-        return Ok(env_temp);
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: `c_path` is NUL-terminated and `stat` is a valid out-pointer for statvfs.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
     }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
 
-    let data_local_tmp = PathBuf::from("/data/local/tmp");
-    info!("Trying /data/local/tmp");
-    if verify_directory_writable(&data_local_tmp) {
-        return Ok(data_local_tmp);
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Bytes `start..=end` of a whole resource sent by a server that ignored the Range header.
+fn slice_range(mut data: Vec<u8>, (start, end): (u64, u64)) -> Result<Vec<u8>> {
+    if end >= data.len() as u64 {
+        bail!("Byte range {}-{} lies beyond the {}-byte resource", start, end, data.len());
     }
+    data.truncate(end as usize + 1);
+    data.drain(..start as usize);
+    Ok(data)
+}
 
-    let current = PathBuf::from(".");
-    info!("Trying current directory");
-    if verify_directory_writable(&current) {
-        return Ok(current);
+/// Runs CPU-bound segment work (decryption, (de)compression) on the blocking pool so the
+/// async workers stay free for I/O and progress updates.
+async fn run_blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .context("Blocking segment task failed")?
+}
+
+/// Writes `data` to `path`; with `sync` it is on disk, not just in the page cache, on return.
+async fn write_file(path: &Path, data: &[u8], sync: bool) -> std::io::Result<()> {
+    let mut file = fs::File::create(path).await?;
+    file.write_all(data).await?;
+    if sync {
+        file.sync_all().await
+    } else {
+        file.flush().await
     }
+}
 
-    bail!(
-        "No writable temporary directory found. Ensure init_android_context() was called with an Application context and that storage is configured."
-    )
+fn segment_file_name(prefix: &str, idx: usize, extension: &str, compressed: bool) -> String {
+    if compressed {
+        format!("{}seg_{:05}.{}.zst", prefix, idx, extension)
+    } else {
+        format!("{}seg_{:05}.{}", prefix, idx, extension)
+    }
 }
 
-#[flutter_rust_bridge::frb()]
-pub async fn hls2mp4_run(
-    sink: StreamSink<ProgressUpdate>,
-    url: String,
-    concurrency: i32,
-    output: String,
-    retries: i32,
-    video_bitrate: i32,
-    audio_bitrate: i32,
-    keep_temp: bool,
-) -> Result<()> {
-    let _ = sink.add(ProgressUpdate {
-        message: "Initializing...".to_string(),
-        progress: 0.0,
-    });
+/// Temp file that `segment_group_size` consecutive segments are appended to.
+fn group_file_name(prefix: &str, group: usize, extension: &str, compressed: bool) -> String {
+    if compressed {
+        format!("{}group_{:05}.{}.zst", prefix, group, extension)
+    } else {
+        format!("{}group_{:05}.{}", prefix, group, extension)
+    }
+}
 
-    #[cfg(target_os = "android")]
-    android_logger::init_once(
-        android_logger::Config::default().with_max_level(log::LevelFilter::Info),
-    );
+/// Media extensions kept for segment files; anything else is saved as `.ts`.
+const SEGMENT_EXTENSIONS: &[&str] =
+    &["ts", "m4s", "mp4", "m4a", "m4v", "aac", "mp3", "ac3", "ec3", "cmfv", "cmfa", "vtt"];
 
-    #[cfg(not(target_os = "android"))]
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Info)
-        .try_init()
-        .ok();
-
-    let concurrency = concurrency.max(1) as usize;
-    let retries = retries.max(1) as u8;
-    let video_bitrate = video_bitrate.max(0) as u32;
-    let audio_bitrate = audio_bitrate.max(0) as u32;
-    let multi_progress = MultiProgress::new();
-
-    let check_pb = multi_progress.add(ProgressBar::new_spinner());
-    check_pb.set_style(
-        ProgressStyle::with_template("{spinner:.green} {msg}")?
-            .tick_strings(&["-", "\\", "|", "/"]),
-    );
-    check_pb.set_message("Selecting transcoder backend...");
-    check_pb.enable_steady_tick(Duration::from_millis(100));
+/// Extension for a playlist's segment files, taken from the first segment URI's path. `ts`
+/// when that has none or an unrecognized one (`seg.php?id=1`, `/segment/1`).
+fn segment_extension(playlist: &m3u8_rs::MediaPlaylist) -> &'static str {
+    let Some(seg) = playlist.segments.iter().find(|seg| !is_gap(seg)) else {
+        return "ts";
+    };
+    let path = seg.uri.split(['?', '#']).next().unwrap_or_default();
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+    let ext = file_name.rsplit_once('.').map_or("", |(_, ext)| ext);
+    SEGMENT_EXTENSIONS
+        .iter()
+        .find(|known| known.eq_ignore_ascii_case(ext))
+        .copied()
+        .unwrap_or("ts")
+}
 
-    let _ = sink.add(ProgressUpdate {
-        message: "Selecting transcoder backend...".to_string(),
-        progress: 0.01,
-    });
+/// 90 kHz time every discontinuity group is rebased relative to, i.e. where playlist time
+/// zero lands. The margin keeps DTS, which can precede PTS, from going negative.
+const REBASE_ORIGIN: i64 = 10 * 90_000;
+
+/// Rewrites the PTS, DTS and PCR of MPEG-TS segments so that each EXT-X-DISCONTINUITY group
+/// starts at its time in the playlist instead of wherever the encoder's clock restarted.
+/// A group's offset is taken from its first segment, so segments must come in order.
+struct TimestampRebaser {
+    /// For each segment, the index of the first segment of its discontinuity group.
+    groups: Vec<usize>,
+    /// For each segment, its start in seconds from the start of the playlist.
+    starts: Vec<f64>,
+}
 
-    let backend = select_transcoder_backend().await?;
-    match backend {
-        TranscoderKind::Ffmpeg(accel) => {
-            check_pb.finish_with_message(format!("Selected FFmpeg backend ({:?})", accel));
+impl TimestampRebaser {
+    /// `None` when there is nothing to rebase: no discontinuity, or segments other than TS.
+    fn new(playlist: &m3u8_rs::MediaPlaylist) -> Option<Self> {
+        let discontinuous = playlist.segments.iter().skip(1).any(|seg| seg.discontinuity);
+        if !discontinuous || segment_extension(playlist) != "ts" {
+            return None;
         }
-        TranscoderKind::AndroidHardware => {
-            check_pb.finish_with_message("Selected Android MediaCodec backend");
+        let mut groups = Vec::with_capacity(playlist.segments.len());
+        let mut starts = Vec::with_capacity(playlist.segments.len());
+        let (mut group, mut start) = (0, 0.0);
+        for (idx, seg) in playlist.segments.iter().enumerate() {
+            if seg.discontinuity {
+                group = idx;
+            }
+            groups.push(group);
+            starts.push(start);
+            start += segment_duration(playlist, seg);
         }
+        Some(Self { groups, starts })
     }
 
-    info!("M3U8 URL: {}", url);
-
-    let download_pb = multi_progress.add(ProgressBar::new_spinner());
-    download_pb.set_style(
-        ProgressStyle::with_template("{spinner:.blue} {msg}")?.tick_strings(&["-", "\\", "|", "/"]),
-    );
-    download_pb.set_message("Downloading M3U8 playlist...");
-    download_pb.enable_steady_tick(Duration::from_millis(100));
-
-    let _ = sink.add(ProgressUpdate {
-        message: "Downloading M3U8 playlist...".to_string(),
-        progress: 0.02,
-    });
+    /// Rebases segment `idx` in place. `offsets` holds each group's offset in 90 kHz ticks,
+    /// and gets the group's once its first segment with a PTS comes by.
+    fn rebase(&self, idx: usize, data: &mut [u8], offsets: &mut BTreeMap<usize, i64>) {
+        let fields = ts_timestamp_fields(data);
+        let group = self.groups[idx];
+        let offset = match offsets.get(&group) {
+            Some(offset) => *offset,
+            None => {
+                let first = fields
+                    .iter()
+                    .filter(|(_, clock)| *clock == TsClock::Pes)
+                    .map(|(at, _)| read_pes_timestamp(&data[*at..]))
+                    .min();
+                let Some(first) = first else {
+                    return;
+                };
+                let start = (self.starts[idx] * 90_000.0).round() as i64;
+                let offset = REBASE_ORIGIN + start - first as i64;
+                offsets.insert(group, offset);
+                offset
+            }
+        };
+        let shift = |ts: u64| (ts as i64 + offset).rem_euclid(1 << 33) as u64;
+        for (at, clock) in fields {
+            let field = &mut data[at..];
+            match clock {
+                TsClock::Pes => write_pes_timestamp(field, shift(read_pes_timestamp(field))),
+                TsClock::Pcr => write_pcr_base(field, shift(read_pcr_base(field))),
+            }
+        }
+    }
+}
 
-    let m3u8_content = download_playlist(&url).await?;
-    let (_, playlist) =
-        parse_playlist(&m3u8_content).map_err(|e| anyhow!("Failed to parse M3U8: {:?}", e))?;
-    download_pb.finish_with_message("Parsed M3U8 playlist");
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TsClock {
+    /// A PES header's PTS or DTS.
+    Pes,
+    /// The 90 kHz base of an adaptation field's PCR.
+    Pcr,
+}
 
-    let base_url = if url.starts_with("http") {
-        let mut parsed_url = Url::parse(&url)?;
-        parsed_url.set_query(None);
-        let mut path = parsed_url.path().to_string();
-        if let Some(pos) = path.rfind('/') {
-            path.truncate(pos + 1);
-            parsed_url.set_path(&path);
-            Some(parsed_url)
-        } else {
-            None
+/// Byte offsets of every PTS, DTS and PCR in the MPEG-TS `data`, up to the first packet
+/// that is out of sync.
+fn ts_timestamp_fields(data: &[u8]) -> Vec<(usize, TsClock)> {
+    let mut fields = Vec::new();
+    for (n, packet) in data.chunks_exact(TS_PACKET_SIZE).enumerate() {
+        if packet[0] != 0x47 {
+            break;
         }
-    } else {
-        None
-    };
-
-    let temp_dir = if cfg!(target_os = "android") {
-        #[cfg(target_os = "android")]
-        {
-            select_writable_temp_dir()?
+        let base = n * TS_PACKET_SIZE;
+        let unit_start = packet[1] & 0x40 != 0;
+        let control = packet[3] >> 4 & 0x3;
+        let mut payload = 4;
+        if control & 0x2 != 0 {
+            let len = packet[4] as usize;
+            if len >= 7 && packet[5] & 0x10 != 0 {
+                fields.push((base + 6, TsClock::Pcr));
+            }
+            payload = 5 + len;
         }
-        #[cfg(not(target_os = "android"))]
-        {
-            unreachable!()
+        if control & 0x1 == 0 || !unit_start || payload + 14 > TS_PACKET_SIZE {
+            continue;
         }
-    } else {
-        PathBuf::from(".")
-    };
+        let pes = &packet[payload..];
+        // Streams whose PES packets have no optional header.
+        let headerless = matches!(pes[3], 0xBC | 0xBE | 0xBF | 0xF0 | 0xF1 | 0xF2 | 0xF8 | 0xFF);
+        if pes[..3] != [0, 0, 1] || headerless {
+            continue;
+        }
+        let flags = pes[7] >> 6;
+        if flags & 0x2 != 0 {
+            fields.push((base + payload + 9, TsClock::Pes));
+        }
+        if flags == 0x3 && payload + 19 <= TS_PACKET_SIZE {
+            fields.push((base + payload + 14, TsClock::Pes));
+        }
+    }
+    fields
+}
 
-    let temp_ts = temp_dir.join("temp_merged.ts");
-    let temp_ts_str = temp_ts.to_string_lossy().to_string();
+/// The 33-bit timestamp in the 5 bytes of a PES PTS or DTS field.
+fn read_pes_timestamp(b: &[u8]) -> u64 {
+    (b[0] as u64 >> 1 & 0x7) << 30
+        | (b[1] as u64) << 22
+        | (b[2] as u64 >> 1) << 15
+        | (b[3] as u64) << 7
+        | b[4] as u64 >> 1
+}
 
-    info!("Temporary directory: {}", temp_dir.display());
-    info!("Temporary TS file: {}", temp_ts_str);
+/// Stores `ts` in a PES PTS or DTS field, keeping its prefix and marker bits.
+fn write_pes_timestamp(b: &mut [u8], ts: u64) {
+    b[0] = (b[0] & 0xF0) | ((ts >> 30 & 0x7) as u8) << 1 | 1;
+    b[1] = (ts >> 22) as u8;
+    b[2] = ((ts >> 15 & 0x7F) as u8) << 1 | 1;
+    b[3] = (ts >> 7) as u8;
+    b[4] = ((ts & 0x7F) as u8) << 1 | 1;
+}
 
-    match playlist {
-        Playlist::MasterPlaylist(master) => {
-            info!("Master Playlist found, {} variants", master.variants.len());
+/// The 33-bit base of the PCR starting at `b`.
+fn read_pcr_base(b: &[u8]) -> u64 {
+    (b[0] as u64) << 25 | (b[1] as u64) << 17 | (b[2] as u64) << 9 | (b[3] as u64) << 1
+        | b[4] as u64 >> 7
+}
 
-            let best = master
-                .variants
-                .iter()
-                .max_by_key(|v| {
-                    let resolution_score = v
-                        .resolution
-                        .as_ref()
-                        .map(|r| r.width * r.height)
-                        .unwrap_or(0);
-                    (resolution_score, v.bandwidth)
-                })
-                .ok_or_else(|| anyhow!("No usable variant found"))?;
+/// Stores `base` in the PCR starting at `b`, keeping its extension.
+fn write_pcr_base(b: &mut [u8], base: u64) {
+    b[0] = (base >> 25) as u8;
+    b[1] = (base >> 17) as u8;
+    b[2] = (base >> 9) as u8;
+    b[3] = (base >> 1) as u8;
+    b[4] = (b[4] & 0x7F) | ((base & 1) as u8) << 7;
+}
 
-            info!(
-                "Selected variant: bandwidth {} , resolution {:?}",
-                best.bandwidth,
-                best.resolution
-                    .as_ref()
-                    .map(|r| format!("{}x{}", r.width, r.height))
-            );
+#[cfg(feature = "compress-temp")]
+fn compress_segment(data: Vec<u8>) -> Result<Vec<u8>> {
+    zstd::encode_all(data.as_slice(), 3).context("Failed to compress segment")
+}
 
-            let media_url = if let Some(base) = &base_url {
-                base.join(&best.uri)?
-            } else {
-                bail!("Master playlist missing URL");
-            };
+#[cfg(not(feature = "compress-temp"))]
+fn compress_segment(data: Vec<u8>) -> Result<Vec<u8>> {
+    Ok(data)
+}
 
-            let media_content = download_playlist(media_url.as_str()).await?;
-            let (_, media_pl) = parse_playlist(&media_content)
-                .map_err(|e| anyhow!("Failed to parse m3u8: {:?}", e))?;
-
-            if let Playlist::MediaPlaylist(mp) = media_pl {
-                download_and_merge(
-                    mp,
-                    base_url,
-                    concurrency,
-                    retries,
-                    &temp_ts_str,
-                    &temp_dir,
-                    &multi_progress,
-                    sink.clone(),
-                )
+#[cfg(feature = "compress-temp")]
+fn decompress_segment(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(zstd::decode_all(data)?)
+}
+
+#[cfg(not(feature = "compress-temp"))]
+fn decompress_segment(_data: &[u8]) -> Result<Vec<u8>> {
+    bail!("Reading .zst segments requires the `compress-temp` feature")
+}
+
+/// Concatenates `paths` in order into `output_file`, reading up to
+/// `options.merge_buffer_segments` files concurrently. `key` decrypts each file as an
+/// AES-128 segment; `remove_merged` deletes inputs once written.
+async fn merge_segments(
+    paths: Vec<PathBuf>,
+    output_file: &str,
+    key: Option<(Vec<u8>, Vec<u8>)>,
+    remove_merged: bool,
+    options: &DownloadOptions,
+    multi_progress: &TerminalProgress,
+) -> Result<()> {
+    let total = paths.len();
+    let merge_pb = multi_progress.bar(
+        total as u64,
+        "{msg} [{elapsed_precise}] {bar:40.green} {pos:>7}/{len:7} ({percent}%)",
+    )?;
+    merge_pb.set_message("Merging segments");
+
+    let file = fs::File::create(output_file)
+        .await
+        .with_context(|| format!("Failed to create output TS file: {}", output_file))?;
+    let mut output = tokio::io::BufWriter::with_capacity(options.merge_write_buffer.max(1), file);
+
+    let mut reads = stream::iter(paths)
+        .map(|tmp_path| {
+            let key = key.clone();
+            async move {
+                let compressed = tmp_path.extension().is_some_and(|ext| ext == "zst");
+                // Plain encrypted files are decrypted in chunks while being written instead.
+                if !compressed && key.is_some() {
+                    return Ok::<_, anyhow::Error>((tmp_path, None));
+                }
+                let data = fs::read(&tmp_path)
+                    .await
+                    .with_context(|| format!("Failed to read segment: {}", tmp_path.display()))?;
+                if !compressed {
+                    return Ok((tmp_path, Some(data)));
+                }
+                let path = tmp_path.clone();
+                let data = run_blocking(move || {
+                    let mut data = decompress_segment(&data)
+                        .with_context(|| format!("Failed to decompress {}", path.display()))?;
+                    if let Some((k, iv)) = key {
+                        decrypt_segment(&k, &iv, &mut data)
+                            .with_context(|| format!("Failed to decrypt {}", path.display()))?;
+                    }
+                    Ok(data)
+                })
                 .await?;
-            } else {
-                bail!("Master playlist's referenced playlist is not a media playlist");
+                Ok((tmp_path, Some(data)))
             }
+        })
+        .buffered(options.merge_buffer_segments.max(1));
+
+    let mut i = 0;
+    while let Some(read) = reads.next().await {
+        let (tmp_path, data) = read?;
+
+        match (data, &key) {
+            (Some(data), _) => output
+                .write_all(&data)
+                .await
+                .with_context(|| format!("Failed to write to output TS: {}", output_file))?,
+            (None, Some((k, iv))) => decrypt_file_into(&tmp_path, k, iv, &mut output)
+                .await
+                .with_context(|| format!("Failed to decrypt {}", tmp_path.display()))?,
+            (None, None) => {}
         }
-        Playlist::MediaPlaylist(mp) => {
-            info!("Media Playlist found, {} segments", mp.segments.len());
-            download_and_merge(
-                mp,
-                base_url,
-                concurrency,
-                retries,
-                &temp_ts_str,
-                &temp_dir,
-                &multi_progress,
-                sink.clone(),
-            )
-            .await?;
+
+        if remove_merged {
+            let _ = fs::remove_file(&tmp_path).await;
         }
+        i += 1;
+        merge_pb.inc(1);
+        merge_pb.set_message(format!("Merging segments [{}/{}]", i, total));
+    }
+    output
+        .flush()
+        .await
+        .with_context(|| format!("Failed to write to output TS: {}", output_file))?;
+    if options.fsync {
+        output
+            .get_ref()
+            .sync_all()
+            .await
+            .with_context(|| format!("Failed to sync output TS: {}", output_file))?;
     }
 
-    convert_to_mp4(
-        &temp_ts_str,
-        &output,
-        video_bitrate,
-        audio_bitrate,
-        &multi_progress,
-        backend,
-        sink.clone(),
-    )
-    .await?;
+    merge_pb.finish_with_message("Merge complete");
+    Ok(())
+}
 
-    if !keep_temp {
-        let _ = fs::remove_file(&temp_ts_str).await;
+/// Files in `dir` matching `pattern` (a name with at most one `*`), ordered by the number
+/// the wildcard covers, so `seg_2.ts` sorts before `seg_10.ts`.
+async fn list_segment_files(dir: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let (prefix, suffix) = pattern.split_once('*').unwrap_or((pattern, ""));
+    let mut found: Vec<(Option<u64>, String, PathBuf)> = Vec::new();
+
+    let mut entries = fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Failed to read segment directory: {}", dir.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(middle) = name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+        else {
+            continue;
+        };
+        if !pattern.contains('*') && !middle.is_empty() {
+            continue;
+        }
+        // The output of an earlier merge into this directory, e.g. one that was interrupted.
+        let stem = Path::new(&name).file_stem().unwrap_or_default().to_string_lossy();
+        if stem.ends_with("temp_merged") {
+            continue;
+        }
+        if entry.file_type().await?.is_file() {
+            found.push((middle.parse().ok(), name.clone(), entry.path()));
+        }
     }
 
-    let _ = sink.add(ProgressUpdate {
-        message: "All tasks completed".to_string(),
-        progress: 1.0,
-    });
+    found.sort();
+    Ok(found.into_iter().map(|(_, _, path)| path).collect())
+}
 
-    Ok(())
+/// The default IV of the segment with media sequence number `sequence`: the number as a
+/// 128-bit big-endian integer.
+fn sequence_iv(sequence: u64) -> Vec<u8> {
+    (sequence as u128).to_be_bytes().to_vec()
+}
+
+/// Parses an EXT-X-KEY IV: 32 hex digits (16 bytes) with optional `0x`/`0X` prefix,
+/// possibly quoted.
+fn parse_iv(raw: &str) -> Result<Vec<u8>> {
+    let trimmed = raw.trim().trim_matches(|c| c == '"' || c == '\'').trim();
+    let digits = trimmed
+        .strip_prefix("0x")
+        .or_else(|| trimmed.strip_prefix("0X"))
+        .unwrap_or(trimmed);
+
+    if digits.is_empty() {
+        bail!("IV is empty: {:?}", raw);
+    }
+    if digits.len() != 32 {
+        bail!("IV {:?} is {} hex digits; expected 32 (16 bytes)", raw, digits.len());
+    }
+
+    hex::decode(digits).with_context(|| format!("IV {:?} is not valid hex", raw))
+}
+
+/// Spells out a request error's whole source chain, since reqwest's own message hides the
+/// underlying protocol error, with a hint at [`HttpVersion`] when that looks like the cause.
+fn request_error(e: reqwest::Error, what: &str) -> anyhow::Error {
+    let mut detail = e.to_string();
+    let mut source = std::error::Error::source(&e);
+    while let Some(cause) = source {
+        detail = format!("{}: {}", detail, cause);
+        source = cause.source();
+    }
+    let lower = detail.to_ascii_lowercase();
+    let protocol = ["http2", "h2 ", "alpn", "protocol", "goaway", "frame", "http/1"]
+        .iter()
+        .any(|needle| lower.contains(needle));
+    if protocol {
+        anyhow!(
+            "{} failed: {} (HTTP version negotiation may be the problem; try \
+             NetworkOptions::http_version)",
+            what,
+            detail
+        )
+    } else {
+        anyhow!("{} failed: {}", what, detail)
+    }
 }
 
-async fn download_playlist(url: &str) -> Result<Vec<u8>> {
+fn create_http_client(network: &NetworkOptions, http: &HttpTuning) -> Result<Client> {
+    if network.http_version == HttpVersion::Http1Only && (http.http2 || http.http2_prior_knowledge)
+    {
+        bail!("http_version Http1Only conflicts with the HTTP/2 settings in HttpTuning");
+    }
     let mut headers = header::HeaderMap::new();
     headers.insert(
         header::USER_AGENT,
@@ -620,410 +5632,885 @@ async fn download_playlist(url: &str) -> Result<Vec<u8>> {
         ),
     );
     headers.insert(header::ACCEPT, header::HeaderValue::from_static("*/*"));
-    headers.insert(
-        header::ACCEPT_LANGUAGE,
-        header::HeaderValue::from_static("en-US,en;q=0.9"),
-    );
-
-    if let Ok(parsed_url) = Url::parse(url) {
-        if let Some(domain) = parsed_url.domain() {
-            let referer = format!("https://{}/", domain);
-            headers.insert(header::REFERER, header::HeaderValue::from_str(&referer)?);
-        }
+    if let RefererPolicy::Custom(referer) = &network.referer {
+        let value = header::HeaderValue::from_str(referer)
+            .with_context(|| format!("Invalid Referer {:?}", referer))?;
+        headers.insert(header::REFERER, value);
     }
 
-    let client = Client::builder()
+    let mut builder = Client::builder()
         .default_headers(headers)
-        .timeout(Duration::from_secs(30))
-        .build()?;
+        .timeout(Duration::from_secs(30));
 
-    let response = client.get(url).send().await?;
-    if !response.status().is_success() {
-        bail!("Failed to download playlist: HTTP {}", response.status());
+    if http.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    } else if !http.http2 {
+        builder = builder.http1_only();
+    }
+    if http.http2_adaptive_window {
+        builder = builder.http2_adaptive_window(true);
+    }
+    if let Some(max_idle) = http.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(timeout) = http.pool_idle_timeout {
+        builder = builder.pool_idle_timeout(timeout);
+    }
+    if let Some(interval) = http.tcp_keepalive {
+        builder = builder.tcp_keepalive(interval);
     }
 
-    Ok(response.bytes().await?.to_vec())
+    Ok(apply_network_options(builder, network)?.build()?)
 }
 
-async fn check_ffmpeg() -> bool {
-    match Command::new("ffmpeg").arg("-version").output().await {
-        Ok(output) => output.status.success(),
-        Err(_) => false,
+/// Picks the backend and the [`Transcoder`] that implements it: the caller's when
+/// [`DownloadOptions::transcoder`] is set, otherwise FFmpeg or Android MediaCodec.
+async fn select_backend(
+    options: &DownloadOptions,
+) -> Result<(TranscoderKind, Arc<dyn Transcoder>)> {
+    if let Some(custom) = &options.transcoder {
+        return Ok((TranscoderKind::Custom, custom.clone()));
     }
+    let backend = select_transcoder_backend(options).await?;
+    let transcoder: Arc<dyn Transcoder> = match backend {
+        TranscoderKind::Ffmpeg(accel) => Arc::new(FfmpegTranscoder(accel)),
+        TranscoderKind::AndroidHardware => Arc::new(AndroidTranscoder),
+        TranscoderKind::Custom => unreachable!("only chosen through options.transcoder"),
+    };
+    Ok((backend, transcoder))
 }
 
-async fn select_transcoder_backend() -> Result<TranscoderKind> {
-    if check_ffmpeg().await {
-        let accel = detect_acceleration().await.unwrap_or(AccelType::CPU);
-        return Ok(TranscoderKind::Ffmpeg(accel));
-    }
+async fn convert_to_mp4(
+    input_ts: &str,
+    output_path: &str,
+    options: &DownloadOptions,
+    expected_duration: Option<f64>,
+    multi_progress: &TerminalProgress,
+    transcoder: &dyn Transcoder,
+    reporter: &Reporter,
+) -> Result<(TranscoderKind, Option<String>)> {
+    let convert_pb =
+        multi_progress.spinner("{spinner:.yellow} {msg}", Duration::from_millis(120))?;
+    convert_pb.set_message("Converting to MP4...");
 
-    if cfg!(target_os = "android") {
-        #[cfg(target_os = "android")]
-        {
-            if ANDROID_HW_TRANSCODER.get().is_none() {
-                bail!(
-                    "Android MediaCodec transcoder not registered. Ensure System.loadLibrary(\"rust_lib_m3u8_downloader\") 
-                    is called in your Android app before using this library."
-                );
-            }
-        }
-        return Ok(TranscoderKind::AndroidHardware);
-    }
+    reporter.progress("Converting to MP4...", 0.9);
 
-    bail!("FFmpeg not found and not running on Android; no available transcoder");
+    info!("Transcoding with {:?}", transcoder);
+    let progress = TranscodeProgress { expected_duration, pb: &convert_pb, reporter };
+    let outcome = match transcoder
+        .transcode(TranscodeInput::File(input_ts), output_path, options, &progress)
+        .await
+    {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            convert_pb.finish_with_message("MP4 transcode failed");
+            return Err(e);
+        }
+    };
+    convert_pb.finish_with_message("MP4 transcode complete");
+    info!("Output file: {}", output_path);
+    Ok((outcome.kind, outcome.log))
 }
 
-async fn download_and_merge(
-    playlist: m3u8_rs::MediaPlaylist,
-    base_url: Option<Url>,
-    concurrency: usize,
-    retries: u8,
-    output_file: &str,
-    temp_dir: &Path,
-    multi_progress: &MultiProgress,
-    sink: StreamSink<ProgressUpdate>,
-) -> Result<()> {
-    // 纭繚涓存椂鐩綍瀛樺湪涓斿彲鍐�
-    if !temp_dir.exists() {
-        std::fs::create_dir_all(temp_dir)
-            .with_context(|| format!("Failed to create temp dir: {}", temp_dir.display()))?;
-    }
+/// The FFmpeg backend with the encoder family found by [`FfmpegBuild::acceleration`]. A
+/// failed GPU encode of a file is retried once on the CPU.
+#[derive(Debug)]
+struct FfmpegTranscoder(AccelType);
 
-    let segments = playlist.segments;
-    let total = segments.len();
-    if total == 0 {
-        bail!("MediaPlaylist contains no segments");
+impl Transcoder for FfmpegTranscoder {
+    fn accepts_stream(&self) -> bool {
+        true
     }
 
-    let download_pb = multi_progress.add(ProgressBar::new(total as u64));
-    download_pb.set_style(
-        ProgressStyle::with_template(
-            "{msg} [{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} ({percent}%) {eta}",
-        )?
-        .progress_chars("##-"),
-    );
-    download_pb.set_message("Downloading segments");
-
-    // 澶勭悊 AES-128 鍔犲瘑
-    let key: Option<(Vec<u8>, Vec<u8>)> = {
-        if let Some(first_seg) = segments.first() {
-            if let Some(ref key_def) = first_seg.key {
-                let key_uri = key_def
-                    .uri
-                    .clone()
-                    .ok_or_else(|| anyhow!("Found encrypted stream but key.uri is empty"))?;
-                let key_url = if let Some(base) = &base_url {
-                    base.join(&key_uri)?
-                } else {
-                    Url::parse(&key_uri)?
-                };
-                let client = create_http_client()?;
-                let resp = client.get(key_url).send().await?.error_for_status()?;
-                let key_bytes = resp.bytes().await?.to_vec();
-
-                let iv_bytes = if let Some(iv_hex) = &key_def.iv {
-                    hex::decode(iv_hex.trim_start_matches("0x")).context("IV hex decode failed")?
-                } else {
-                    bail!("AES-128 encrypted stream but IV not provided");
-                };
+    fn transcode<'a>(
+        &'a self,
+        input: TranscodeInput<'a>,
+        output: &'a str,
+        options: &'a DownloadOptions,
+        progress: &'a TranscodeProgress<'a>,
+    ) -> BoxFuture<'a, Result<TranscodeOutcome>> {
+        Box::pin(async move {
+            match input {
+                TranscodeInput::File(input_ts) => {
+                    self.transcode_file(input_ts, output, options, progress).await
+                }
+                TranscodeInput::Stream { probe, segments } => {
+                    self.transcode_stream(probe, segments, output, options, progress).await
+                }
+            }
+        })
+    }
+}
 
-                Some((key_bytes, iv_bytes))
-            } else {
-                None
+impl FfmpegTranscoder {
+    async fn transcode_file(
+        &self,
+        input_ts: &str,
+        output_path: &str,
+        options: &DownloadOptions,
+        progress: &TranscodeProgress<'_>,
+    ) -> Result<TranscodeOutcome> {
+        let accel = self.0;
+        info!("Using FFmpeg backend: {:?}", accel);
+        let streams = match probe_streams(input_ts).await {
+            Ok(streams) => streams,
+            Err(e) => {
+                warn!("Could not probe streams, assuming audio and video: {}", e);
+                StreamPresence { video: true, audio: true }
             }
+        };
+        if !streams.video && !streams.audio {
+            bail!("Merged stream contains neither audio nor video");
+        }
+        let to_stdout = output_path == STDOUT_OUTPUT;
+        let parts = if options.resume && !is_remux(options) && !to_stdout {
+            let identity = TranscodeParts::identity(input_ts, options, accel, streams).await?;
+            Some(TranscodeParts::load(output_path, &identity).await?)
         } else {
             None
+        };
+        let expected_duration = progress.expected_duration;
+        let (target, expected_duration) = match &parts {
+            Some(parts) if parts.is_complete(expected_duration) => {
+                parts.join(output_path, options).await?;
+                return Ok(TranscodeOutcome { kind: TranscoderKind::Ffmpeg(accel), log: None });
+            }
+            Some(parts) => (parts.next_path(), parts.remaining(expected_duration)),
+            None => (output_path.to_string(), expected_duration),
+        };
+        let build = |accel| match &parts {
+            Some(parts) => parts.ffmpeg_args(input_ts, options, accel, streams),
+            None => build_ffmpeg_args(input_ts, &target, options, accel, streams),
+        };
+        let (mut status, mut stderr) = run_ffmpeg_with_progress(
+            &build(accel),
+            to_stdout,
+            None,
+            expected_duration,
+            progress,
+        )
+        .await?;
+
+        // A GPU encoder can fail on driver issues or unusual input; don't throw away
+        // the download, retry once on the CPU.
+        let mut used = accel;
+        let remux = is_remux(options);
+        // Part of the MP4 may already be in the pipe, so stdout gets no second attempt.
+        if !status.success() && accel != AccelType::CPU && !remux && !to_stdout {
+            warn!("{:?} transcode failed, falling back to libx264", accel);
+            warn!("FFmpeg stderr:\n{}", stderr);
+            progress.pb.set_message("Hardware transcode failed, retrying on CPU...");
+            used = AccelType::CPU;
+            let gpu_stderr = stderr;
+            (status, stderr) = run_ffmpeg_with_progress(
+                &build(AccelType::CPU),
+                false,
+                None,
+                expected_duration,
+                progress,
+            )
+            .await?;
+            stderr = format!("{}\n--- libx264 fallback ---\n{}", gpu_stderr, stderr);
         }
-    };
 
-    let sem = Arc::new(Semaphore::new(concurrency));
-    let client = Arc::new(create_http_client()?);
-    let completed = Arc::new(Mutex::new(0u64));
+        if !status.success() {
+            error!("FFmpeg stderr:\n{}", stderr);
+            bail!("MP4 transcode failed");
+        }
+        if let Some(mut parts) = parts {
+            parts.paths.push(PathBuf::from(target));
+            parts.join(output_path, options).await?;
+        }
 
-    // 鉁� 鍏抽敭淇锛氫紶閫� temp_dir 鍒板紓姝ヤ换鍔�
-    let temp_dir = temp_dir.to_path_buf();
+        let log = options.capture_ffmpeg_log.then_some(stderr);
+        Ok(TranscodeOutcome { kind: TranscoderKind::Ffmpeg(used), log })
+    }
 
-    let tasks = stream::iter(segments.into_iter().enumerate())
-        .map(|(idx, seg)| {
-            let seg_url = if let Some(base) = &base_url {
-                base.join(&seg.uri).unwrap().to_string()
-            } else {
-                seg.uri.clone()
-            };
+    /// Transcodes `segments` fed to FFmpeg's stdin, with the streams found in `probe`.
+    async fn transcode_stream(
+        &self,
+        probe: &str,
+        segments: stream::BoxStream<'static, Vec<u8>>,
+        output_path: &str,
+        options: &DownloadOptions,
+        progress: &TranscodeProgress<'_>,
+    ) -> Result<TranscodeOutcome> {
+        let accel = self.0;
+        let streams = match probe_streams(probe).await {
+            Ok(streams) => streams,
+            Err(e) => {
+                warn!("Could not probe streams, assuming audio and video: {}", e);
+                StreamPresence { video: true, audio: true }
+            }
+        };
+        if !streams.video && !streams.audio {
+            bail!("First segment contains neither audio nor video");
+        }
 
-            let client = client.clone();
-            let sem = sem.clone();
-            let key = key.clone();
-            let pb = download_pb.clone();
-            let completed = completed.clone();
-            let sink = sink.clone();
-            let temp_dir = temp_dir.clone(); // 鉁� 鍏嬮殕鍒颁换鍔�
+        let args = build_ffmpeg_args("pipe:0", output_path, options, accel, streams);
+        // Progress is the download's; FFmpeg can't run ahead of it.
+        let (status, stderr) = run_ffmpeg_with_progress(
+            &args,
+            output_path == STDOUT_OUTPUT,
+            Some(segments),
+            None,
+            progress,
+        )
+        .await?;
+        if !status.success() {
+            error!("FFmpeg stderr:\n{}", stderr);
+            bail!("MP4 transcode failed");
+        }
+        let log = options.capture_ffmpeg_log.then_some(stderr);
+        Ok(TranscodeOutcome { kind: TranscoderKind::Ffmpeg(accel), log })
+    }
+}
 
-            tokio::spawn(async move {
-                let _permit = sem
-                    .acquire()
-                    .await
-                    .map_err(|_| anyhow!("Semaphore acquire failed"))?;
+/// The MediaCodec backend registered by [`register_android_mediacodec_transcoder`].
+#[derive(Debug)]
+struct AndroidTranscoder;
+
+impl Transcoder for AndroidTranscoder {
+    fn transcode<'a>(
+        &'a self,
+        input: TranscodeInput<'a>,
+        output_path: &'a str,
+        options: &'a DownloadOptions,
+        progress: &'a TranscodeProgress<'a>,
+    ) -> BoxFuture<'a, Result<TranscodeOutcome>> {
+        Box::pin(async move {
+            info!("Using Android MediaCodec hardware transcoder");
+            let TranscodeInput::File(input_ts) = input else {
+                bail!("The Android hardware backend can only transcode a merged file");
+            };
+            if output_path == STDOUT_OUTPUT {
+                bail!("The Android hardware backend can't write to stdout");
+            }
+            if !options.extra_ffmpeg_args.is_empty() {
+                warn!("Extra FFmpeg arguments are ignored by the Android hardware backend");
+            }
+            if options.scale.is_some() {
+                warn!("Scaling is not supported by the Android hardware backend");
+            }
+            if options.frame_reduction.is_some() {
+                warn!("Frame reduction is not supported by the Android hardware backend");
+            }
+            if options.audio_sample_rate.is_some() || options.audio_channels.is_some() {
+                warn!("Audio resampling is not supported by the Android hardware backend");
+            }
+            if options.faststart || options.fragmented {
+                warn!("MP4 layout options are ignored by the Android hardware backend");
+            }
+            if !options.metadata.is_empty() {
+                warn!("Metadata tags are not written by the Android hardware backend");
+            }
+            android_hardware_transcode(
+                input_ts,
+                output_path,
+                options.video_bitrate,
+                options.audio_bitrate,
+                progress.pb,
+            )
+            .await?;
+            Ok(TranscodeOutcome { kind: TranscoderKind::AndroidHardware, log: None })
+        })
+    }
+}
 
-                for attempt in 1..=retries {
-                    match client.get(&seg_url).send().await {
-                        Ok(resp) if resp.status().is_success() => {
-                            let data = resp.bytes().await?;
-                            let buf = if let Some((ref k, ref iv)) = key {
-                                if iv.len() != 16 {
-                                    bail!("IV length is not 16 bytes");
-                                }
-                                let cipher = Aes128Cbc::new_from_slices(k, iv)?;
-                                cipher.decrypt_vec(&data)?
-                            } else {
-                                data.to_vec()
-                            };
-
-                            // 鉁� 鍏抽敭淇锛氬垎鐗囧啓鍏� temp_dir 涓�
-                            let file_name = format!("seg_{:05}.ts", idx);
-                            let tmp_path = temp_dir.join(file_name);
-                            fs::write(&tmp_path, &buf).await.with_context(|| {
-                                format!(
-                                    "Failed to write segment: {} (url: {})",
-                                    tmp_path.display(),
-                                    seg_url
-                                )
-                            })?;
-
-                            let mut count = completed.lock().await;
-                            *count += 1;
-                            pb.set_position(*count);
-                            pb.set_message(format!("Downloading segments [{}/{}]", *count, total));
-                            let _ = sink.add(ProgressUpdate {
-                                message: format!("Downloading segments [{}/{}]", *count, total),
-                                progress: (*count as f64) / (total as f64) * 0.9,
-                            });
-
-                            return Ok::<(), anyhow::Error>(());
-                        }
+/// A re-encode with `resume`, written as fragmented MP4 parts next to the output
+/// (`out.mp4.part0.mp4`, ...) that stay readable when the transcode is interrupted. A rerun
+/// keeps the parts, probes how much they cover and encodes only the rest, seeking the input
+/// to the keyframe before that point; the seam is approximate and can repeat or skip a
+/// fraction of a second. The parts are joined into the output without re-encoding.
+struct TranscodeParts {
+    output: String,
+    paths: Vec<PathBuf>,
+    /// Seconds of media in `paths`.
+    done: f64,
+}
 
-                        Ok(r) => {
-                            pb.set_message(format!("Retrying... ({}/{})", attempt, retries));
-                            warn!(
-                                "Attempt {} failed: {} HTTP {}",
-                                attempt,
-                                seg_url,
-                                r.status()
-                            );
-                        }
+impl TranscodeParts {
+    fn part_path(output: &str, idx: usize) -> PathBuf {
+        PathBuf::from(format!("{}.part{}.mp4", output, idx))
+    }
 
-                        Err(e) => {
-                            pb.set_message(format!("Retrying... ({}/{})", attempt, retries));
-                            warn!("Attempt {} request error: {} - {}", attempt, seg_url, e);
-                        }
-                    }
+    /// Where the identity of the run that wrote the parts is kept.
+    fn identity_path(output: &str) -> PathBuf {
+        PathBuf::from(format!("{}.parts.id", output))
+    }
 
-                    if attempt < retries {
-                        tokio::time::sleep(Duration::from_millis(2000)).await;
-                    }
+    /// What the parts of `output` depend on: the encode arguments, apart from the input and
+    /// output paths, and the input's length and first and last bytes.
+    async fn identity(
+        input_ts: &str,
+        options: &DownloadOptions,
+        accel: AccelType,
+        streams: StreamPresence,
+    ) -> Result<String> {
+        let part_options =
+            DownloadOptions { faststart: false, fragmented: true, ..options.clone() };
+        let mut hash = Fingerprint::default();
+        for arg in build_ffmpeg_args("<input>", "<output>", &part_options, accel, streams) {
+            hash.feed(arg.as_bytes());
+        }
+        let mut file = fs::File::open(input_ts).await?;
+        let len = file.metadata().await?.len();
+        hash.feed(&len.to_le_bytes());
+        let mut sample = vec![0u8; len.min(64 * 1024) as usize];
+        file.read_exact(&mut sample).await?;
+        hash.feed(&sample);
+        file.seek(std::io::SeekFrom::Start(len - sample.len() as u64)).await?;
+        file.read_exact(&mut sample).await?;
+        hash.feed(&sample);
+        Ok(hash.finish())
+    }
+
+    /// Picks up the parts of an interrupted run with the same `identity`; parts written for
+    /// another input or other encode settings are deleted. So is an unreadable part (killed
+    /// before its first fragment) and everything after it.
+    async fn load(output: &str, identity: &str) -> Result<Self> {
+        let mut parts = Self { output: output.to_string(), paths: Vec::new(), done: 0.0 };
+        let identity_path = Self::identity_path(output);
+        let matches = fs::read_to_string(&identity_path).await.is_ok_and(|id| id == identity);
+        if !matches {
+            let mut idx = 0;
+            while fs::remove_file(Self::part_path(output, idx)).await.is_ok() {
+                idx += 1;
+            }
+            if idx > 0 {
+                info!("Discarding {} partial outputs of a different transcode", idx);
+            }
+            fs::write(&identity_path, identity)
+                .await
+                .with_context(|| format!("Failed to write {}", identity_path.display()))?;
+            return Ok(parts);
+        }
+        let mut idx = 0;
+        let mut readable = true;
+        loop {
+            let path = Self::part_path(output, idx);
+            if !path.exists() {
+                break;
+            }
+            let duration = match readable {
+                true => probe_duration(&path.to_string_lossy()).await.ok(),
+                false => None,
+            };
+            match duration.filter(|secs| *secs > 0.0) {
+                Some(secs) => {
+                    parts.paths.push(path);
+                    parts.done += secs;
                 }
+                None => {
+                    readable = false;
+                    let _ = fs::remove_file(&path).await;
+                }
+            }
+            idx += 1;
+        }
+        if !parts.paths.is_empty() {
+            info!(
+                "Resuming the transcode at {:.1}s from {} partial outputs",
+                parts.done,
+                parts.paths.len()
+            );
+        }
+        Ok(parts)
+    }
 
-                bail!("Failed after {} attempts: {}", retries, seg_url)
-            })
-        })
-        .buffer_unordered(concurrency)
-        .collect::<Vec<_>>()
-        .await;
+    fn is_complete(&self, expected_duration: Option<f64>) -> bool {
+        expected_duration.is_some_and(|total| self.done > 0.0 && self.done >= total - 0.5)
+    }
 
-    for task in tasks {
-        task??;
+    fn remaining(&self, expected_duration: Option<f64>) -> Option<f64> {
+        expected_duration.map(|total| (total - self.done).max(0.0))
     }
 
-    download_pb.finish_with_message("All segments downloaded");
+    fn next_path(&self) -> String {
+        Self::part_path(&self.output, self.paths.len()).to_string_lossy().to_string()
+    }
 
-    let merge_pb = multi_progress.add(ProgressBar::new(total as u64));
-    merge_pb.set_style(
-        ProgressStyle::with_template(
-            "{msg} [{elapsed_precise}] {bar:40.green} {pos:>7}/{len:7} ({percent}%)",
-        )?
-        .progress_chars("##-"),
-    );
-    merge_pb.set_message("Merging segments");
+    /// Arguments encoding the not yet covered rest of `input_ts` into the next part.
+    fn ffmpeg_args(
+        &self,
+        input_ts: &str,
+        options: &DownloadOptions,
+        accel: AccelType,
+        streams: StreamPresence,
+    ) -> Vec<String> {
+        let part_options = DownloadOptions {
+            faststart: false,
+            fragmented: true,
+            clip_duration: options.clip_duration.map(|clip| (clip - self.done).max(0.0)),
+            ..options.clone()
+        };
+        let target = self.next_path();
+        let mut args = build_ffmpeg_args(input_ts, &target, &part_options, accel, streams);
+        if self.done > 0.0 {
+            let input = args.iter().position(|arg| arg == "-i").unwrap_or(args.len());
+            args.splice(input..input, ["-ss".to_string(), format!("{:.3}", self.done)]);
+        }
+        args
+    }
 
-    let mut output = fs::File::create(output_file)
-        .await
-        .with_context(|| format!("Failed to create output TS file: {}", output_file))?;
+    /// Concatenates the parts into `output` with the MP4 layout `options` ask for, then
+    /// deletes them.
+    async fn join(&self, output: &str, options: &DownloadOptions) -> Result<()> {
+        let list_path = format!("{}.parts.txt", output);
+        let list: String = self
+            .paths
+            .iter()
+            .map(|path| format!("file '{}'\n", path.to_string_lossy().replace('\'', "'\\''")))
+            .collect();
+        fs::write(&list_path, list)
+            .await
+            .with_context(|| format!("Failed to write {}", list_path))?;
+
+        let mut args = vec!["-hide_banner", "-y", "-loglevel", "error", "-f", "concat"];
+        args.extend(["-safe", "0", "-i", &list_path, "-c", "copy"]);
+        if options.faststart {
+            args.extend(["-movflags", "+faststart"]);
+        } else if options.fragmented {
+            args.extend(["-movflags", "+frag_keyframe+empty_moov"]);
+        }
+        // The concat demuxer doesn't carry the parts' tags over.
+        let tags: Vec<String> =
+            options.metadata.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        for tag in &tags {
+            args.extend(["-metadata", tag]);
+        }
+        args.push(output);
+        let result = Command::new("ffmpeg").args(&args).kill_on_drop(true).output().await;
+        let _ = fs::remove_file(&list_path).await;
+        let result = result.context("Failed to run ffmpeg")?;
+        if !result.status.success() {
+            bail!(
+                "Joining the transcoded parts failed: {}",
+                String::from_utf8_lossy(&result.stderr).trim()
+            );
+        }
+        for path in &self.paths {
+            let _ = fs::remove_file(path).await;
+        }
+        let _ = fs::remove_file(Self::identity_path(&self.output)).await;
+        Ok(())
+    }
+}
 
-    for i in 0..total {
-        let file_name = format!("seg_{:05}.ts", i);
-        let tmp_path = temp_dir.join(&file_name);
+/// With `auto_bitrate`, a re-encode without an explicit video bitrate gets the source's: the
+/// playlist's hint (see [`PlaylistOrigin::bandwidth`]), or else the merged TS as measured by
+/// ffprobe, less the audio bitrate. Returns the options to transcode with and the picked kbps.
+async fn with_auto_bitrate<'a>(
+    options: &'a DownloadOptions,
+    input_ts: &str,
+    hint: Option<(u64, &'static str)>,
+) -> (Cow<'a, DownloadOptions>, Option<u32>) {
+    if !options.auto_bitrate || options.video_bitrate > 0 || is_remux(options) {
+        return (Cow::Borrowed(options), None);
+    }
+    let source = match hint {
+        Some(hint) => Some(hint),
+        None => match probe_bitrate(input_ts).await {
+            Ok(measured) => Some((measured, "measured")),
+            Err(e) => {
+                warn!("Could not measure the source bitrate: {}", e);
+                None
+            }
+        },
+    };
+    let Some((bps, from)) = source else {
+        return (Cow::Borrowed(options), None);
+    };
 
-        let mut segment = fs::File::open(&tmp_path)
-            .await
-            .with_context(|| format!("Failed to read segment: {}", tmp_path.display()))?;
-        
-        tokio::io::copy(&mut segment, &mut output)
-            .await
-            .with_context(|| format!("Failed to write to output TS: {}", output_file))?;
+    let audio_kbps = if options.audio_bitrate > 0 { options.audio_bitrate } else { 256 };
+    let kbps = (bps / 1000).saturating_sub(audio_kbps as u64).max(100) as u32;
+    info!("Auto video bitrate: {}k ({} {} bps)", kbps, from, bps);
+    let options = DownloadOptions { video_bitrate: kbps, ..options.clone() };
+    (Cow::Owned(options), Some(kbps))
+}
 
-        let _ = fs::remove_file(&tmp_path).await;
-        merge_pb.inc(1);
-        merge_pb.set_message(format!("Merging segments [{}/{}]", i + 1, total));
+/// With `tag_source`, adds the `comment` tag naming `source_url` and today's date.
+fn with_source_tag<'a>(
+    options: Cow<'a, DownloadOptions>,
+    source_url: &str,
+) -> Cow<'a, DownloadOptions> {
+    let has_comment = options.metadata.iter().any(|(key, _)| key.eq_ignore_ascii_case("comment"));
+    if !options.tag_source || has_comment {
+        return options;
     }
+    // Query strings often carry access tokens, which don't belong in a shareable file.
+    let source = match Url::parse(source_url) {
+        Ok(mut url) => {
+            url.set_query(None);
+            url.set_fragment(None);
+            url.to_string()
+        }
+        Err(_) => source_url.to_string(),
+    };
+    let comment = format!("Source: {} (downloaded {})", source, utc_date(SystemTime::now()));
+    let mut options = options.into_owned();
+    options.metadata.push(("comment".to_string(), comment));
+    Cow::Owned(options)
+}
 
-    merge_pb.finish_with_message("Merge complete");
+/// Whether conversion copies streams: when asked to, or when nothing calls for re-encoding.
+fn is_remux(options: &DownloadOptions) -> bool {
+    options.stream_copy
+        || (options.video_bitrate == 0
+            && options.audio_bitrate == 0
+            && options.scale.is_none()
+            && options.frame_reduction.is_none()
+            && options.audio_sample_rate.is_none()
+            && options.audio_channels.is_none())
+}
+
+fn validate_transcode_options(options: &DownloadOptions) -> Result<()> {
+    validate_extra_ffmpeg_args(&options.extra_ffmpeg_args)?;
+    if options.faststart && options.fragmented {
+        bail!("faststart and fragmented are mutually exclusive");
+    }
+    if let Some((w, h)) = options.scale {
+        if options.stream_copy {
+            bail!("Scaling needs a re-encode and can't be combined with stream_copy");
+        }
+        let valid = |d: i32| d == -1 || d > 0;
+        if !valid(w) || !valid(h) || (w == -1 && h == -1) {
+            bail!("Invalid scale {}x{}: use positive sizes, or -1 for one of them", w, h);
+        }
+    }
+    let audio_resampled = options.audio_sample_rate.is_some() || options.audio_channels.is_some();
+    if audio_resampled && options.stream_copy {
+        bail!("Changing the audio sample rate or channels needs a re-encode, not stream_copy");
+    }
+    if options.audio_sample_rate == Some(0) || options.audio_channels == Some(0) {
+        bail!("audio_sample_rate and audio_channels must be positive");
+    }
+    for (key, value) in &options.metadata {
+        let valid_key = !key.is_empty()
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_key {
+            bail!("Invalid metadata key {:?}: use letters, digits, '_' and '-'", key);
+        }
+        // A process argument can't hold NUL.
+        if value.contains('\0') {
+            bail!("Metadata value for {} contains a NUL character", key);
+        }
+    }
+    if let Some(reduction) = options.frame_reduction {
+        if options.stream_copy {
+            bail!("Frame reduction needs a re-encode and can't be combined with stream_copy");
+        }
+        if let FrameReduction::Rate(fps) = reduction {
+            if !fps.is_finite() || fps <= 0.0 {
+                bail!("Invalid frame rate {}: must be positive", fps);
+            }
+        }
+    }
     Ok(())
 }
 
-fn create_http_client() -> Result<Client> {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(
-        header::USER_AGENT,
-        header::HeaderValue::from_static(
-            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36",
-        ),
-    );
-    headers.insert(header::ACCEPT, header::HeaderValue::from_static("*/*"));
+fn validate_decryption_override(options: &DownloadOptions) -> Result<()> {
+    match (&options.decryption_key, &options.decryption_iv) {
+        (None, Some(_)) => bail!("decryption_iv requires decryption_key"),
+        (Some(key), _) if key.len() != 16 => {
+            bail!("decryption_key must be 16 bytes, got {}", key.len())
+        }
+        (_, Some(iv)) if iv.len() != 16 => {
+            bail!("decryption_iv must be 16 bytes, got {}", iv.len())
+        }
+        _ => Ok(()),
+    }
+}
 
-    Ok(Client::builder()
-        .default_headers(headers)
-        .timeout(Duration::from_secs(30))
-        .build()?)
+/// Rejects extra FFmpeg arguments that would add inputs or break progress parsing.
+fn validate_extra_ffmpeg_args(args: &[String]) -> Result<()> {
+    const RESERVED: [&str; 4] = ["-i", "-progress", "-nostats", "-stats"];
+    if let Some(arg) = args.iter().find(|arg| RESERVED.contains(&arg.as_str())) {
+        bail!("Extra FFmpeg argument {} is managed by the downloader", arg);
+    }
+    Ok(())
 }
 
-async fn detect_acceleration() -> Result<AccelType> {
-    let output = Command::new("ffmpeg")
-        .args(&["-hide_banner", "-encoders"])
-        .output()
+/// Pipelined counterpart of [`convert_to_mp4`]: the transcoder gets the segments received on
+/// `segments` while the rest are still downloading. The first segment, written to
+/// `probe_path`, stands in for the merged TS when probing streams and bitrate. `source_url`
+/// is the playlist's, for `tag_source`. Returns the backend used, the captured log and the
+/// `auto_bitrate` pick.
+async fn transcode_piped(
+    mut segments: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    output_path: &str,
+    ctx: &RunContext,
+    source_url: &str,
+    probe_path: &Path,
+    bitrate_hint: Option<(u64, &'static str)>,
+    reporter: &Reporter,
+) -> Result<(TranscoderKind, Option<String>, Option<u32>)> {
+    let convert_pb = ctx.progress.spinner("{spinner:.yellow} {msg}", Duration::from_millis(120))?;
+    convert_pb.set_message("Converting to MP4 while downloading...");
+
+    let Some(first) = segments.recv().await else {
+        bail!("No segments arrived to transcode");
+    };
+    write_file(probe_path, &first, false)
         .await
-        .context("Failed to run ffmpeg")?;
+        .with_context(|| format!("Failed to write {}", probe_path.display()))?;
+    let probe_str = probe_path.to_string_lossy().to_string();
+    let (options, auto_video_bitrate) =
+        with_auto_bitrate(&ctx.options, &probe_str, bitrate_hint).await;
+    let options = with_source_tag(options, source_url);
+
+    let rest = stream::unfold(segments, |mut rx| async move { rx.recv().await.map(|b| (b, rx)) });
+    let input = TranscodeInput::Stream {
+        probe: &probe_str,
+        segments: stream::once(async { first }).chain(rest).boxed(),
+    };
+    info!("Transcoding with {:?}", ctx.transcoder);
+    let progress = TranscodeProgress { expected_duration: None, pb: &convert_pb, reporter };
+    let transcoded = ctx.transcoder.transcode(input, output_path, &options, &progress).await;
+    let _ = fs::remove_file(probe_path).await;
+    let outcome = match transcoded {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            convert_pb.finish_with_message("MP4 transcode failed");
+            return Err(e);
+        }
+    };
 
-    let list = String::from_utf8_lossy(&output.stdout);
-    if list.contains("h264_nvenc") {
-        Ok(AccelType::Nvidia)
-    } else if list.contains("h264_amf") {
-        Ok(AccelType::AMD)
-    } else {
-        Ok(AccelType::CPU)
-    }
+    convert_pb.finish_with_message("MP4 transcode complete");
+    info!("Output file: {}", output_path);
+    Ok((outcome.kind, outcome.log, auto_video_bitrate))
 }
 
-async fn convert_to_mp4(
+/// FFmpeg arguments for converting `input_ts` with the given encoder family.
+fn build_ffmpeg_args(
     input_ts: &str,
     output_path: &str,
-    video_bitrate: u32,
-    audio_bitrate: u32,
-    multi_progress: &MultiProgress,
-    backend: TranscoderKind,
-    sink: StreamSink<ProgressUpdate>,
-) -> Result<()> {
-    let convert_pb = multi_progress.add(ProgressBar::new_spinner());
-    convert_pb.set_style(
-        ProgressStyle::with_template("{spinner:.yellow} {msg}")?
-            .tick_strings(&["-", "\\", "|", "/"]),
-    );
-    convert_pb.set_message("Converting to MP4...");
-    convert_pb.enable_steady_tick(Duration::from_millis(120));
+    options: &DownloadOptions,
+    accel: AccelType,
+    streams: StreamPresence,
+) -> Vec<String> {
+    let video_bitrate = options.video_bitrate;
+    let audio_bitrate = options.audio_bitrate;
+    let extra_args = &options.extra_ffmpeg_args;
+    let fix_timestamps = options.fix_timestamps;
+
+    let mut ffmpeg_args: Vec<String> = vec![
+        "-hide_banner".to_string(),
+        "-y".to_string(),
+        "-loglevel".to_string(),
+        options.ffmpeg_log_level.as_arg().to_string(),
+    ];
 
-    let _ = sink.add(ProgressUpdate {
-        message: "Converting to MP4...".to_string(),
-        progress: 0.95,
-    });
+    if fix_timestamps {
+        ffmpeg_args.extend(["-fflags".to_string(), "+genpts+igndts".to_string()]);
+    }
 
-    match backend {
-        TranscoderKind::Ffmpeg(accel) => {
-            info!("Using FFmpeg backend: {:?}", accel);
-            let mut ffmpeg_args: Vec<String> = vec![
-                "-hide_banner".to_string(),
-                "-loglevel".to_string(),
-                "info".to_string(),
-            ];
-
-            if video_bitrate == 0 && audio_bitrate == 0 {
-                info!("Bitrates are 0, attempting to remux (copy streams) for high efficiency");
-                ffmpeg_args.extend([
-                    "-i".to_string(),
-                    input_ts.to_string(),
-                    "-c".to_string(),
-                    "copy".to_string(),
-                    "-bsf:a".to_string(),
-                    "aac_adtstoasc".to_string(),
-                ]);
-            } else {
-                match accel {
-                    AccelType::Nvidia => {
-                        info!("Detected NVIDIA GPU, using NVENC");
+    if !streams.video {
+        info!("Input has no video stream, writing audio only");
+    } else if !streams.audio {
+        info!("Input has no audio stream, writing video only");
+    }
+
+    if is_remux(options) {
+        info!("Remuxing (copying streams) without re-encoding");
+        ffmpeg_args.extend([
+            "-i".to_string(),
+            input_ts.to_string(),
+            "-c".to_string(),
+            "copy".to_string(),
+        ]);
+        if streams.audio {
+            ffmpeg_args.extend(["-bsf:a".to_string(), "aac_adtstoasc".to_string()]);
+        }
+    } else {
+        if streams.video {
+            match accel {
+                AccelType::Nvidia => {
+                    info!("Detected NVIDIA GPU, using NVENC");
+                    if options.hw_decode {
                         ffmpeg_args.extend([
                             "-hwaccel".to_string(), "cuda".to_string(),
                             "-hwaccel_output_format".to_string(), "cuda".to_string(),
                             "-c:v".to_string(), "h264_cuvid".to_string(),
-                            "-i".to_string(), input_ts.to_string(),
-                            "-c:a".to_string(), "aac".to_string(), "-b:a".to_string(), "320k".to_string(),
-                            "-c:v".to_string(), "h264_nvenc".to_string(), "-preset".to_string(), "p3".to_string(), "-rc".to_string(), "vbr".to_string(),
                         ]);
                     }
-                    AccelType::AMD => {
-                        info!("Detected AMD GPU, using AMF");
-                        ffmpeg_args.extend([
-                            "-i".to_string(), input_ts.to_string(),
-                            "-c:a".to_string(), "aac".to_string(), "-b:a".to_string(), "320k".to_string(),
-                            "-c:v".to_string(), "h264_amf".to_string(), "-rc".to_string(), "vbr".to_string(),
-                        ]);
-                    }
-                    AccelType::CPU => {
-                        info!("No supported GPU found, using CPU (libx264)");
-                        ffmpeg_args.extend([
-                            "-i".to_string(), input_ts.to_string(),
-                            "-c:a".to_string(), "aac".to_string(),
-                            "-c:v".to_string(), "libx264".to_string(), "-preset".to_string(), "medium".to_string(),
-                        ]);
+                    ffmpeg_args.extend([
+                        "-i".to_string(), input_ts.to_string(),
+                        "-c:v".to_string(), "h264_nvenc".to_string(),
+                        "-preset".to_string(), "p3".to_string(),
+                        "-rc".to_string(), "vbr".to_string(),
+                    ]);
+                }
+                AccelType::AMD => {
+                    info!("Detected AMD GPU, using AMF");
+                    if options.hw_decode {
+                        ffmpeg_args.extend(["-hwaccel".to_string(), "auto".to_string()]);
                     }
+                    ffmpeg_args.extend([
+                        "-i".to_string(), input_ts.to_string(),
+                        "-c:v".to_string(), "h264_amf".to_string(),
+                        "-rc".to_string(), "vbr".to_string(),
+                    ]);
                 }
+                AccelType::CPU => {
+                    info!("No supported GPU found, using CPU (libx264)");
+                    ffmpeg_args.extend([
+                        "-i".to_string(), input_ts.to_string(),
+                        "-c:v".to_string(), "libx264".to_string(),
+                        "-preset".to_string(), "medium".to_string(),
+                    ]);
+                }
+            }
+
+            if video_bitrate > 0 {
+                ffmpeg_args.push("-b:v".to_string());
+                ffmpeg_args.push(format!("{}k", video_bitrate));
+            }
+        } else {
+            ffmpeg_args.extend(["-i".to_string(), input_ts.to_string(), "-vn".to_string()]);
+        }
+
+        if streams.audio {
+            ffmpeg_args.extend(["-c:a".to_string(), "aac".to_string()]);
+            let audio_bitrate =
+                if audio_bitrate > 0 { format!("{}k", audio_bitrate) } else { "256k".to_string() };
+            ffmpeg_args.extend(["-b:a".to_string(), audio_bitrate]);
+            if let Some(rate) = options.audio_sample_rate {
+                ffmpeg_args.extend(["-ar".to_string(), rate.to_string()]);
+            }
+            if let Some(channels) = options.audio_channels {
+                ffmpeg_args.extend(["-ac".to_string(), channels.to_string()]);
+            }
+        } else {
+            ffmpeg_args.push("-an".to_string());
+        }
+
+        let mut filters = Vec::new();
+        match options.frame_reduction {
+            Some(FrameReduction::Rate(fps)) => filters.push(format!("fps={}", fps)),
+            Some(FrameReduction::KeyframesOnly) => {
+                filters.push("select='eq(pict_type,I)'".to_string());
+                // Without this the dropped frames are filled back in with duplicates.
+                ffmpeg_args.extend(["-fps_mode".to_string(), "vfr".to_string()]);
+            }
+            None => {}
+        }
+        if let Some((w, h)) = options.scale {
+            // Frames stay on the GPU when NVENC also decodes, so scale them there.
+            let filter = if accel == AccelType::Nvidia && options.hw_decode {
+                "scale_cuda"
+            } else {
+                "scale"
+            };
+            filters.push(format!("{}={}:{}", filter, w, h));
+        }
+        if streams.video && !filters.is_empty() {
+            ffmpeg_args.extend(["-vf".to_string(), filters.join(",")]);
+        }
+    }
+
+    if fix_timestamps {
+        ffmpeg_args.extend(["-avoid_negative_ts".to_string(), "make_zero".to_string()]);
+    }
+
+    if let Some(clip) = options.clip_duration {
+        ffmpeg_args.extend(["-t".to_string(), format!("{:.3}", clip)]);
+    }
 
-                if video_bitrate > 0 {
-                    ffmpeg_args.push("-b:v".to_string());
-                    ffmpeg_args.push(format!("{}k", video_bitrate));
-                }
+    if options.faststart {
+        ffmpeg_args.extend(["-movflags".to_string(), "+faststart".to_string()]);
+    } else if options.fragmented {
+        ffmpeg_args.extend(["-movflags".to_string(), "+frag_keyframe+empty_moov".to_string()]);
+    }
 
-                if audio_bitrate > 0 {
-                    ffmpeg_args.push("-b:a".to_string());
-                    ffmpeg_args.push(format!("{}k", audio_bitrate));
-                } else {
-                    ffmpeg_args.push("-b:a".to_string());
-                    ffmpeg_args.push("256k".to_string());
-                }
-            }
+    for (key, value) in &options.metadata {
+        ffmpeg_args.extend(["-metadata".to_string(), format!("{}={}", key, value)]);
+    }
 
-            ffmpeg_args.push(output_path.to_string());
+    ffmpeg_args.extend(extra_args.iter().cloned());
+    if output_path == STDOUT_OUTPUT {
+        // There's no extension to infer the container from.
+        ffmpeg_args.extend(["-f".to_string(), "mp4".to_string(), "pipe:1".to_string()]);
+    } else {
+        ffmpeg_args.push(output_path.to_string());
+    }
 
-            let output = Command::new("ffmpeg")
-                .args(&ffmpeg_args)
-                .output()
-                .await
-                .context("FFmpeg transcode failed")?;
+    ffmpeg_args
+}
 
-            if !output.status.success() {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                convert_pb.finish_with_message("MP4 transcode failed");
-                error!("FFmpeg stderr:\n{}", stderr);
-                bail!("MP4 transcode failed");
-            }
+/// Runs FFmpeg with `-progress pipe:1`, mapping `out_time_us` against `expected_duration`
+/// onto the 0.9..1.0 range of the overall progress. Returns the exit status and stderr.
+/// With `to_stdout` FFmpeg's stdout is ours and carries the MP4, so there is no progress.
+/// `input` is written to FFmpeg's stdin, for an `-i pipe:0` input.
+async fn run_ffmpeg_with_progress(
+    args: &[String],
+    to_stdout: bool,
+    input: Option<stream::BoxStream<'static, Vec<u8>>>,
+    expected_duration: Option<f64>,
+    progress: &TranscodeProgress<'_>,
+) -> Result<(std::process::ExitStatus, String)> {
+    let mut command = Command::new("ffmpeg");
+    if to_stdout {
+        command.arg("-nostats").stdout(Stdio::inherit());
+    } else {
+        command.args(["-progress", "pipe:1", "-nostats"]).stdout(Stdio::piped());
+    }
+    command.stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() });
+    // Dropping the download future (e.g. the Dart side abandoning it) must not leave an
+    // orphaned FFmpeg running.
+    let mut child = command
+        .args(args)
+        .kill_on_drop(true)
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("FFmpeg transcode failed")?;
+
+    let stderr = child.stderr.take();
+    let stderr_task = tokio::spawn(async move {
+        let mut buf = Vec::new();
+        if let Some(mut stderr) = stderr {
+            let _ = stderr.read_to_end(&mut buf).await;
+        }
+        buf
+    });
 
-            convert_pb.finish_with_message("MP4 transcode complete");
-            info!("Output file: {}", output_path);
-            Ok(())
+    let stdin = child.stdin.take();
+    let feed = async move {
+        let (Some(mut input), Some(mut stdin)) = (input, stdin) else {
+            return;
+        };
+        while let Some(chunk) = input.next().await {
+            // Writes only fail once FFmpeg has exited, and its status says why.
+            if stdin.write_all(&chunk).await.is_err() {
+                return;
+            }
         }
-        TranscoderKind::AndroidHardware => {
-            info!("Using Android MediaCodec hardware transcoder");
-            android_hardware_transcode(
-                input_ts,
-                output_path,
-                video_bitrate,
-                audio_bitrate,
-                &convert_pb,
-            )
-            .await?;
-            convert_pb.finish_with_message("Android hardware transcode complete");
-            info!("Output file: {}", output_path);
-            Ok(())
+        // Dropping stdin here is FFmpeg's end of input.
+    };
+    let stdout = child.stdout.take();
+    let report = async {
+        let Some(stdout) = stdout else {
+            return Ok(());
+        };
+        let mut lines = BufReader::new(stdout).lines();
+        while let Some(line) = lines.next_line().await? {
+            let (Some(total), Some(us)) = (expected_duration, line.strip_prefix("out_time_us="))
+            else {
+                continue;
+            };
+            let Ok(us) = us.trim().parse::<i64>() else {
+                continue;
+            };
+            progress.update(us.max(0) as f64 / 1_000_000.0 / total);
         }
-    }
+        Ok::<_, anyhow::Error>(())
+    };
+    let ((), reported) = tokio::join!(feed, report);
+    reported?;
+
+    let status = child.wait().await.context("FFmpeg transcode failed")?;
+    let stderr = stderr_task.await.unwrap_or_default();
+    Ok((status, String::from_utf8_lossy(&stderr).to_string()))
 }
 
 async fn android_hardware_transcode(
@@ -1172,4 +6659,1232 @@ pub fn init_android_context_from_dart(jvm_ptr: i64, context_ptr: i64) -> Result<
     info!("鉁� Android Context initialized from Dart");
     Ok(())
 }
-*/
\ No newline at end of file
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::block_padding::Pkcs7;
+    use aes::cipher::BlockEncryptMut;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::net::TcpListener;
+
+    /// A request as seen by [`MockServer`].
+    #[derive(Clone, Debug)]
+    struct MockRequest {
+        method: String,
+        path: String,
+        /// Header names are lowercase.
+        headers: HashMap<String, String>,
+    }
+
+    #[derive(Clone, Debug, Default)]
+    struct MockResponse {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+        /// Close the connection after this many body bytes, though the full length was
+        /// announced.
+        cut_after: Option<usize>,
+        /// Keep the connection open for another request instead of closing it.
+        keep_alive: bool,
+    }
+
+    impl MockResponse {
+        fn ok(body: impl Into<Vec<u8>>) -> Self {
+            Self { status: 200, body: body.into(), ..Default::default() }
+        }
+
+        fn not_found() -> Self {
+            Self { status: 404, ..Default::default() }
+        }
+
+        fn redirect(location: &str) -> Self {
+            Self { status: 302, ..Default::default() }.header("Location", location)
+        }
+
+        fn header(mut self, name: &str, value: &str) -> Self {
+            self.headers.push((name.to_string(), value.to_string()));
+            self
+        }
+    }
+
+    type MockHandler = dyn Fn(&MockRequest) -> MockResponse + Send + Sync;
+
+    /// HTTP/1.1 server on a loopback port answering every request with `handler`, one
+    /// request per connection.
+    struct MockServer {
+        base: String,
+        requests: Arc<std::sync::Mutex<Vec<MockRequest>>>,
+        connections: Arc<AtomicUsize>,
+    }
+
+    impl MockServer {
+        async fn start(
+            handler: impl Fn(&MockRequest) -> MockResponse + Send + Sync + 'static,
+        ) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let handler: Arc<MockHandler> = Arc::new(handler);
+            let seen = requests.clone();
+            let connections = Arc::new(AtomicUsize::new(0));
+            let accepted = connections.clone();
+            tokio::spawn(async move {
+                while let Ok((socket, _)) = listener.accept().await {
+                    accepted.fetch_add(1, Ordering::SeqCst);
+                    let handler = handler.clone();
+                    let seen = seen.clone();
+                    tokio::spawn(async move {
+                        let _ = Self::serve(socket, handler.as_ref(), &seen).await;
+                    });
+                }
+            });
+            Self { base, requests, connections }
+        }
+
+        /// Serves `files` by path with byte-range support, and 404 for anything else.
+        async fn files(files: Vec<(&str, Vec<u8>)>) -> Self {
+            let files: HashMap<String, Vec<u8>> =
+                files.into_iter().map(|(path, body)| (path.to_string(), body)).collect();
+            Self::start(move |request| match files.get(&request.path) {
+                Some(body) => ranged(request, body),
+                None => MockResponse::not_found(),
+            })
+            .await
+        }
+
+        async fn serve(
+            mut socket: tokio::net::TcpStream,
+            handler: &MockHandler,
+            seen: &std::sync::Mutex<Vec<MockRequest>>,
+        ) -> std::io::Result<()> {
+            loop {
+                let response = Self::serve_one(&mut socket, handler, seen).await?;
+                if !response.is_some_and(|r| r.keep_alive && r.cut_after.is_none()) {
+                    return socket.shutdown().await;
+                }
+            }
+        }
+
+        /// Answers one request on `socket`; `None` when the client closed it instead.
+        async fn serve_one(
+            socket: &mut tokio::net::TcpStream,
+            handler: &MockHandler,
+            seen: &std::sync::Mutex<Vec<MockRequest>>,
+        ) -> std::io::Result<Option<MockResponse>> {
+            let mut head = Vec::new();
+            let mut byte = [0u8; 1];
+            while !head.ends_with(b"\r\n\r\n") {
+                if socket.read(&mut byte).await? == 0 {
+                    return Ok(None);
+                }
+                head.push(byte[0]);
+            }
+            let head = String::from_utf8_lossy(&head).to_string();
+            let mut lines = head.lines();
+            let mut start = lines.next().unwrap_or_default().split(' ');
+            let request = MockRequest {
+                method: start.next().unwrap_or_default().to_string(),
+                path: start.next().unwrap_or_default().to_string(),
+                headers: lines
+                    .filter_map(|line| line.split_once(':'))
+                    .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().into()))
+                    .collect(),
+            };
+            seen.lock().unwrap().push(request.clone());
+            let response = handler(&request);
+
+            let mut out = format!("HTTP/1.1 {} Mock\r\n", response.status);
+            for (name, value) in &response.headers {
+                out.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            out.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+            match response.keep_alive && response.cut_after.is_none() {
+                true => out.push_str("Connection: keep-alive\r\n\r\n"),
+                false => out.push_str("Connection: close\r\n\r\n"),
+            }
+            // One write, so Nagle's algorithm doesn't hold the body back on a kept-alive
+            // connection.
+            let mut out = out.into_bytes();
+            if request.method != "HEAD" {
+                let sent = response.cut_after.unwrap_or(response.body.len());
+                out.extend_from_slice(&response.body[..sent.min(response.body.len())]);
+            }
+            socket.write_all(&out).await?;
+            Ok(Some(response))
+        }
+
+        fn url(&self, path: &str) -> String {
+            format!("{}{}", self.base, path)
+        }
+
+        /// Connections accepted so far.
+        fn connections(&self) -> usize {
+            self.connections.load(Ordering::SeqCst)
+        }
+
+        fn requests(&self) -> Vec<MockRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        /// Paths requested so far with `method`.
+        fn paths(&self, method: &str) -> Vec<String> {
+            self.requests().into_iter().filter(|r| r.method == method).map(|r| r.path).collect()
+        }
+    }
+
+    /// `body`, or the part of it a `Range: bytes=a-b` header asks for as a 206.
+    fn ranged(request: &MockRequest, body: &[u8]) -> MockResponse {
+        let range = request
+            .headers
+            .get("range")
+            .and_then(|range| range.strip_prefix("bytes="))
+            .and_then(|range| range.split_once('-'));
+        let Some((start, end)) = range else {
+            return MockResponse::ok(body).header("Accept-Ranges", "bytes");
+        };
+        let start: usize = start.parse().unwrap();
+        let end = end.parse::<usize>().map_or(body.len() - 1, |end| end.min(body.len() - 1));
+        MockResponse {
+            status: 206,
+            body: body[start..=end].to_vec(),
+            ..Default::default()
+        }
+        .header("Accept-Ranges", "bytes")
+        .header("Content-Range", &format!("bytes {}-{}/{}", start, end, body.len()))
+    }
+
+    /// Stands in for FFmpeg: "transcodes" by copying the merged TS to the output.
+    #[derive(Debug)]
+    struct CopyTranscoder;
+
+    impl Transcoder for CopyTranscoder {
+        fn transcode<'a>(
+            &'a self,
+            input: TranscodeInput<'a>,
+            output: &'a str,
+            _options: &'a DownloadOptions,
+            _progress: &'a TranscodeProgress<'a>,
+        ) -> BoxFuture<'a, Result<TranscodeOutcome>> {
+            Box::pin(async move {
+                let TranscodeInput::File(input) = input else {
+                    bail!("CopyTranscoder only copies files");
+                };
+                fs::copy(input, output).await?;
+                Ok(TranscodeOutcome { kind: TranscoderKind::Custom, log: None })
+            })
+        }
+    }
+
+    /// Options for a quiet download from a [`MockServer`] with FFmpeg stubbed out.
+    fn test_options() -> DownloadOptions {
+        DownloadOptions {
+            progress_mode: ProgressMode::Quiet,
+            network: NetworkOptions { allow_private_addresses: true, ..Default::default() },
+            transcoder: Some(Arc::new(CopyTranscoder)),
+            duration_check: DurationCheck::Off,
+            overwrite: OverwritePolicy::Overwrite,
+            ..Default::default()
+        }
+    }
+
+    /// A fresh directory under the system temp dir.
+    fn scratch_dir() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "m3u8dl-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Downloads `url` and returns the bytes handed to the transcoder.
+    async fn download(url: &str, options: DownloadOptions) -> Result<Vec<u8>> {
+        let dir = scratch_dir();
+        let output = dir.join("out.mp4").to_string_lossy().to_string();
+        let result = run(url.to_string(), output.clone(), options).await;
+        let bytes = fs::read(&output).await;
+        let _ = std::fs::remove_dir_all(&dir);
+        result?;
+        Ok(bytes?)
+    }
+
+    /// `packets` MPEG-TS packets of recognizable filler.
+    fn ts_packets(packets: usize, seed: u8) -> Vec<u8> {
+        (0..packets * TS_PACKET_SIZE)
+            .map(|i| if i % TS_PACKET_SIZE == 0 { 0x47 } else { (i as u8) ^ seed })
+            .collect()
+    }
+
+    /// AES-128-CBC ciphertext of `plain`, PKCS#7 padded or (block-aligned input) unpadded.
+    fn encrypt(key: &[u8], iv: &[u8], plain: &[u8], padded: bool) -> Vec<u8> {
+        let mut buf = plain.to_vec();
+        buf.resize(plain.len() + 16, 0);
+        let encryptor = cbc::Encryptor::<aes::Aes128>::new_from_slices(key, iv).unwrap();
+        let len = if padded {
+            encryptor.encrypt_padded_mut::<Pkcs7>(&mut buf, plain.len()).unwrap().len()
+        } else {
+            encryptor.encrypt_padded_mut::<NoPadding>(&mut buf, plain.len()).unwrap().len()
+        };
+        buf.truncate(len);
+        buf
+    }
+
+    const KEY: [u8; 16] = *b"0123456789abcdef";
+
+    #[test]
+    fn decrypt_segment_strips_pkcs7_padding() {
+        let iv = [3u8; 16];
+        let plain = ts_packets(3, 2);
+        let mut data = encrypt(&KEY, &iv, &plain, true);
+        assert_eq!(data.len() % 16, 0);
+        decrypt_segment(&KEY, &iv, &mut data).unwrap();
+        assert_eq!(data, plain);
+    }
+
+    #[test]
+    fn decrypt_segment_keeps_block_aligned_unpadded_data() {
+        let iv = [3u8; 16];
+        // 4 packets are 752 bytes, a whole number of blocks.
+        let plain = ts_packets(4, 2);
+        let mut data = encrypt(&KEY, &iv, &plain, false);
+        assert_eq!(data.len(), plain.len());
+        decrypt_segment(&KEY, &iv, &mut data).unwrap();
+        assert_eq!(data, plain);
+    }
+
+    #[test]
+    fn decrypt_segment_falls_back_to_no_padding() {
+        let iv = [5u8; 16];
+        // Ends in 0x00 and then in 0x01: invalid PKCS#7 padding, and a byte that only looks
+        // like padding but would cut a TS packet short.
+        for last in [0x00, 0x01] {
+            let mut plain = ts_packets(4, 9);
+            *plain.last_mut().unwrap() = last;
+            let mut data = encrypt(&KEY, &iv, &plain, false);
+            decrypt_segment(&KEY, &iv, &mut data).unwrap();
+            assert_eq!(data, plain, "last byte {:#04x}", last);
+        }
+    }
+
+    /// A TS packet starting a video PES packet with `pts` and `dts`, and a PCR if given.
+    fn pes_packet(pts: u64, dts: Option<u64>, pcr: Option<u64>) -> Vec<u8> {
+        let mut packet = vec![0x47, 0x41, 0x00, 0x10];
+        if let Some(pcr) = pcr {
+            packet[3] = 0x30;
+            packet.extend([7, 0x10, 0, 0, 0, 0, 0x7E, 0]);
+            write_pcr_base(&mut packet[6..], pcr);
+        }
+        let (flags, header_len) = if dts.is_some() { (0xC0, 10) } else { (0x80, 5) };
+        packet.extend([0, 0, 1, 0xE0, 0, 0, 0x80, flags, header_len]);
+        let at = packet.len();
+        packet.extend([if dts.is_some() { 0x30 } else { 0x20 }, 0, 0, 0, 0]);
+        write_pes_timestamp(&mut packet[at..], pts);
+        if let Some(dts) = dts {
+            packet.extend([0x10, 0, 0, 0, 0]);
+            write_pes_timestamp(&mut packet[at + 5..], dts);
+        }
+        packet.resize(TS_PACKET_SIZE, 0xFF);
+        packet
+    }
+
+    /// Every PTS/DTS (as `(false, ts)`) and PCR base (`(true, ts)`) in `data`.
+    fn ts_clocks(data: &[u8]) -> Vec<(bool, u64)> {
+        ts_timestamp_fields(data)
+            .into_iter()
+            .map(|(at, clock)| match clock {
+                TsClock::Pes => (false, read_pes_timestamp(&data[at..])),
+                TsClock::Pcr => (true, read_pcr_base(&data[at..])),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rebaser_continues_each_discontinuity_group_on_the_playlist_timeline() {
+        let playlist = "#EXTM3U\n#EXT-X-TARGETDURATION:4\n\
+            #EXTINF:4,\na.ts\n#EXTINF:4,\nb.ts\n#EXT-X-DISCONTINUITY\n\
+            #EXTINF:4,\nc.ts\n#EXTINF:4,\nd.ts\n#EXT-X-ENDLIST\n";
+        let Ok(Playlist::MediaPlaylist(playlist)) =
+            parse_m3u8(playlist.as_bytes(), &PlaylistLimits::default())
+        else {
+            panic!("not a media playlist");
+        };
+        let rebaser = TimestampRebaser::new(&playlist).unwrap();
+        let mut segments = [
+            pes_packet(1_000, Some(900), Some(800)),
+            pes_packet(361_000, None, None),
+            // The encoder's clock restarts after the discontinuity.
+            pes_packet(50, None, Some(40)),
+            [pes_packet(360_050, None, None), pes_packet(363_050, None, None)].concat(),
+        ];
+        let mut offsets = BTreeMap::new();
+        for (idx, data) in segments.iter_mut().enumerate() {
+            rebaser.rebase(idx, data, &mut offsets);
+        }
+
+        let origin = REBASE_ORIGIN as u64;
+        assert_eq!(
+            ts_clocks(&segments[0]),
+            [(true, origin - 100), (false, origin + 100), (false, origin)]
+        );
+        assert_eq!(ts_clocks(&segments[1]), [(false, origin + 360_100)]);
+        assert_eq!(ts_clocks(&segments[2]), [(true, origin + 719_990), (false, origin + 720_000)]);
+        assert_eq!(
+            ts_clocks(&segments[3]),
+            [(false, origin + 1_080_000), (false, origin + 1_083_000)]
+        );
+        assert_eq!(offsets.keys().copied().collect::<Vec<_>>(), [0, 2]);
+        // The PTS prefix and marker bits survive the rewrite.
+        let pts = &segments[1][13..18];
+        assert_eq!((pts[0] & 0xF1, pts[2] & 1, pts[4] & 1), (0x21, 1, 1));
+    }
+
+    #[test]
+    fn rebaser_skips_playlists_without_discontinuities() {
+        let playlist = "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4,\na.ts\n#EXT-X-ENDLIST\n";
+        let Ok(Playlist::MediaPlaylist(playlist)) =
+            parse_m3u8(playlist.as_bytes(), &PlaylistLimits::default())
+        else {
+            panic!("not a media playlist");
+        };
+        assert!(TimestampRebaser::new(&playlist).is_none());
+    }
+
+    #[tokio::test]
+    async fn relative_key_resolves_against_media_playlist() {
+        let plain = ts_packets(3, 1);
+        let iv = [7u8; 16];
+        let master = "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1000\n../media/v1/prog.m3u8\n";
+        let media = format!(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:4\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x{}\n\
+             #EXTINF:4,\nseg0.ts\n#EXT-X-ENDLIST\n",
+            hex::encode(iv)
+        );
+        let server = MockServer::files(vec![
+            ("/master/index.m3u8", master.into()),
+            ("/media/v1/prog.m3u8", media.into()),
+            ("/media/v1/key.bin", KEY.to_vec()),
+            ("/media/v1/seg0.ts", encrypt(&KEY, &iv, &plain, true)),
+        ])
+        .await;
+
+        let merged = download(&server.url("/master/index.m3u8"), test_options()).await.unwrap();
+        assert_eq!(merged, plain);
+        assert!(server.paths("GET").contains(&"/media/v1/key.bin".to_string()));
+    }
+
+    #[tokio::test]
+    async fn session_key_is_prefetched_from_master_playlist() {
+        let plain = ts_packets(3, 4);
+        let iv = [1u8; 16];
+        let master = "#EXTM3U\n#EXT-X-SESSION-KEY:METHOD=AES-128,URI=\"/keys/k1\"\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=1000\nmedia.m3u8\n";
+        let media = format!(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:4\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"/keys/k1\",IV=0x{}\n\
+             #EXTINF:4,\nseg0.ts\n#EXTINF:4,\nseg1.ts\n#EXT-X-ENDLIST\n",
+            hex::encode(iv)
+        );
+        let server = MockServer::files(vec![
+            ("/master.m3u8", master.into()),
+            ("/media.m3u8", media.into()),
+            ("/keys/k1", KEY.to_vec()),
+            ("/seg0.ts", encrypt(&KEY, &iv, &plain, true)),
+            ("/seg1.ts", encrypt(&KEY, &iv, &plain, true)),
+        ])
+        .await;
+
+        let merged = download(&server.url("/master.m3u8"), test_options()).await.unwrap();
+        assert_eq!(merged, [plain.clone(), plain].concat());
+        let paths = server.paths("GET");
+        let key_requests: Vec<usize> = paths
+            .iter()
+            .enumerate()
+            .filter(|(_, path)| *path == "/keys/k1")
+            .map(|(i, _)| i)
+            .collect();
+        let media_request = paths.iter().position(|path| path == "/media.m3u8").unwrap();
+        assert_eq!(key_requests.len(), 1, "{:?}", paths);
+        assert!(key_requests[0] < media_request, "{:?}", paths);
+    }
+
+    #[test]
+    fn parse_iv_accepts_prefixes_case_and_quotes() {
+        let expected: Vec<u8> = (0..16).collect();
+        for raw in [
+            "0x000102030405060708090a0b0c0d0e0f",
+            "0X000102030405060708090A0B0C0D0E0F",
+            "000102030405060708090a0b0c0d0e0f",
+            " \"0x000102030405060708090a0b0c0d0e0f\" ",
+        ] {
+            assert_eq!(parse_iv(raw).unwrap(), expected, "{}", raw);
+        }
+    }
+
+    #[test]
+    fn parse_iv_rejects_wrong_length_and_non_hex() {
+        for raw in [
+            "",
+            "0x",
+            "0x1",
+            "0x0102030405060708090a0b0c0d0e0f",
+            "0x000102030405060708090a0b0c0d0e0f10",
+            "0x000102030405060708090a0b0c0d0e0g",
+        ] {
+            assert!(parse_iv(raw).is_err(), "{:?} was accepted", raw);
+        }
+    }
+
+    #[tokio::test]
+    async fn merge_and_convert_skips_earlier_merged_files() {
+        let dir = scratch_dir();
+        let segments = [ts_packets(2, 1), ts_packets(2, 2)];
+        std::fs::write(dir.join("seg_00000.ts"), &segments[0]).unwrap();
+        std::fs::write(dir.join("seg_00001.ts"), &segments[1]).unwrap();
+        std::fs::write(dir.join("temp_merged.ts"), b"stale").unwrap();
+        std::fs::write(dir.join("m3u8dl-0123456789abcdef_temp_merged.ts"), b"stale").unwrap();
+
+        let output = dir.join("out.mp4").to_string_lossy().to_string();
+        let dir_str = dir.to_string_lossy().to_string();
+        merge_and_convert(dir_str, "*.ts".into(), output.clone(), None, test_options())
+            .await
+            .unwrap();
+        let merged = std::fs::read(&output).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(merged, segments.concat());
+    }
+
+    /// A server where `/hop/0` leads to a media playlist after `hops` redirects.
+    async fn redirect_chain_server(hops: usize) -> MockServer {
+        let plain = ts_packets(1, 3);
+        MockServer::start(move |request| {
+            let path = request.path.as_str();
+            if let Some(n) = path.strip_prefix("/hop/").and_then(|n| n.parse::<usize>().ok()) {
+                return match n + 1 < hops {
+                    true => MockResponse::redirect(&format!("/hop/{}", n + 1)),
+                    false => MockResponse::redirect("/media/index.m3u8"),
+                };
+            }
+            match path {
+                "/media/index.m3u8" => MockResponse::ok(
+                    "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4,\nseg0.ts\n#EXT-X-ENDLIST\n",
+                ),
+                "/media/seg0.ts" => MockResponse::ok(plain.clone()),
+                _ => MockResponse::not_found(),
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn max_redirects_limits_playlist_redirects() {
+        let server = redirect_chain_server(2).await;
+        let url = server.url("/hop/0");
+        let limited = |max| DownloadOptions {
+            network: NetworkOptions {
+                max_redirects: Some(max),
+                allow_private_addresses: true,
+                ..Default::default()
+            },
+            retries: 1,
+            ..test_options()
+        };
+
+        assert_eq!(download(&url, limited(2)).await.unwrap(), ts_packets(1, 3));
+        assert!(download(&url, limited(1)).await.is_err());
+        assert!(download(&url, limited(0)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn segments_resolve_against_redirected_playlist_url() {
+        let plain = ts_packets(2, 6);
+        let body = plain.clone();
+        let server = MockServer::start(move |request| match request.path.as_str() {
+            "/live/master.m3u8" => MockResponse::redirect("/cdn/eu/master.m3u8"),
+            "/cdn/eu/master.m3u8" => {
+                MockResponse::ok("#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1000\nv1/index.m3u8\n")
+            }
+            "/cdn/eu/v1/index.m3u8" => MockResponse::redirect("/edge/7/v1/index.m3u8"),
+            "/edge/7/v1/index.m3u8" => MockResponse::ok(
+                "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4,\nseg0.ts\n#EXT-X-ENDLIST\n",
+            ),
+            "/edge/7/v1/seg0.ts" => MockResponse::ok(body.clone()),
+            _ => MockResponse::not_found(),
+        })
+        .await;
+
+        let merged = download(&server.url("/live/master.m3u8"), test_options()).await.unwrap();
+        assert_eq!(merged, plain);
+    }
+
+    /// A playlist whose first two segments are AES-128 encrypted with `KEY` and an explicit
+    /// IV, followed by METHOD=NONE and two clear segments. Returns the server and the
+    /// segments' plaintext.
+    async fn encrypted_then_clear_server() -> (MockServer, Vec<Vec<u8>>) {
+        let iv = [9u8; 16];
+        let plain: Vec<Vec<u8>> = (0..4).map(|i| ts_packets(2, i)).collect();
+        let media = format!(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:4\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x{}\n\
+             #EXTINF:4,\nseg0.ts\n#EXTINF:4,\nseg1.ts\n\
+             #EXT-X-KEY:METHOD=NONE\n\
+             #EXTINF:4,\nseg2.ts\n#EXTINF:4,\nseg3.ts\n#EXT-X-ENDLIST\n",
+            hex::encode(iv)
+        );
+        let server = MockServer::files(vec![
+            ("/index.m3u8", media.into()),
+            ("/key.bin", KEY.to_vec()),
+            ("/seg0.ts", encrypt(&KEY, &iv, &plain[0], true)),
+            ("/seg1.ts", encrypt(&KEY, &iv, &plain[1], true)),
+            ("/seg2.ts", plain[2].clone()),
+            ("/seg3.ts", plain[3].clone()),
+        ])
+        .await;
+        (server, plain)
+    }
+
+    #[tokio::test]
+    async fn method_none_passes_later_segments_through() {
+        let (server, plain) = encrypted_then_clear_server().await;
+        let merged = download(&server.url("/index.m3u8"), test_options()).await.unwrap();
+        assert_eq!(merged, plain.concat());
+    }
+
+    #[tokio::test]
+    async fn override_key_leaves_method_none_segments_clear() {
+        let (server, plain) = encrypted_then_clear_server().await;
+        let options = DownloadOptions { decryption_key: Some(KEY.to_vec()), ..test_options() };
+        let merged = download(&server.url("/index.m3u8"), options).await.unwrap();
+        assert_eq!(merged, plain.concat());
+        assert!(!server.paths("GET").contains(&"/key.bin".to_string()));
+    }
+
+    #[tokio::test]
+    async fn segments_follow_relative_redirects() {
+        let plain: Vec<Vec<u8>> = (0..2).map(|i| ts_packets(2, i)).collect();
+        let bodies = plain.clone();
+        let server = MockServer::start(move |req| match req.path.as_str() {
+            "/v/index.m3u8" => MockResponse::ok(
+                "#EXTM3U\n#EXT-X-TARGETDURATION:4\n\
+                 #EXTINF:4,\nseg0.ts\n#EXTINF:4,\nseg1.ts\n#EXT-X-ENDLIST\n",
+            ),
+            "/v/seg0.ts" => MockResponse::redirect("../signed/seg0.ts?sig=a"),
+            "/v/seg1.ts" => MockResponse::redirect("signed/seg1.ts?sig=b"),
+            "/signed/seg0.ts?sig=a" => MockResponse::ok(bodies[0].clone()),
+            "/v/signed/seg1.ts?sig=b" => MockResponse::ok(bodies[1].clone()),
+            _ => MockResponse::not_found(),
+        })
+        .await;
+        let merged = download(&server.url("/v/index.m3u8"), test_options()).await.unwrap();
+        assert_eq!(merged, plain.concat());
+    }
+
+    #[test]
+    fn inherit_query_copies_the_playlist_token_onto_relative_uris() {
+        let base = playlist_base_url("https://cdn.test/v/index.m3u8?token=abc").unwrap();
+        let inherited = |uri: &str| {
+            let mut url = resolve_uri(base.as_ref(), uri, "Segment").unwrap();
+            inherit_query(&mut url, uri, base.as_ref());
+            url.to_string()
+        };
+        assert_eq!(inherited("seg0.ts"), "https://cdn.test/v/seg0.ts?token=abc");
+        assert_eq!(inherited("/root/seg0.ts"), "https://cdn.test/root/seg0.ts?token=abc");
+        assert_eq!(inherited("seg0.ts?sig=1"), "https://cdn.test/v/seg0.ts?sig=1");
+        assert_eq!(inherited("https://other.test/seg0.ts"), "https://other.test/seg0.ts");
+
+        let bare = playlist_base_url("https://cdn.test/v/index.m3u8").unwrap();
+        let mut url = resolve_uri(bare.as_ref(), "seg0.ts", "Segment").unwrap();
+        inherit_query(&mut url, "seg0.ts", bare.as_ref());
+        assert_eq!(url.query(), None);
+    }
+
+    #[tokio::test]
+    async fn inherit_query_signs_key_and_segment_requests() {
+        let iv = [3u8; 16];
+        let plain: Vec<Vec<u8>> = (0..2).map(|i| ts_packets(2, i)).collect();
+        let media = format!(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:4\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x{}\n\
+             #EXTINF:4,\nseg0.ts\n#EXTINF:4,\nseg1.ts\n#EXT-X-ENDLIST\n",
+            hex::encode(iv)
+        );
+        let files: HashMap<&str, Vec<u8>> = HashMap::from([
+            ("/index.m3u8", media.into_bytes()),
+            ("/key.bin", KEY.to_vec()),
+            ("/seg0.ts", encrypt(&KEY, &iv, &plain[0], true)),
+            ("/seg1.ts", encrypt(&KEY, &iv, &plain[1], true)),
+        ]);
+        // Only requests carrying the playlist's token are served.
+        let server = MockServer::start(move |req| {
+            let file = req.path.strip_suffix("?token=abc").and_then(|path| files.get(path));
+            match file {
+                Some(body) => MockResponse::ok(body.clone()),
+                None => MockResponse { status: 403, ..Default::default() },
+            }
+        })
+        .await;
+        let options = DownloadOptions { inherit_query: true, ..test_options() };
+        let merged = download(&server.url("/index.m3u8?token=abc"), options).await.unwrap();
+        assert_eq!(merged, plain.concat());
+    }
+
+    /// The IV an EXT-X-KEY without an IV attribute implies: the big-endian media sequence
+    /// number.
+    fn iv_for_sequence(sequence: u64) -> [u8; 16] {
+        let mut iv = [0u8; 16];
+        iv[8..].copy_from_slice(&sequence.to_be_bytes());
+        iv
+    }
+
+    #[tokio::test]
+    async fn aes128_segments_decrypt_and_merge_to_the_plaintext() {
+        let explicit_iv = [0x5au8; 16];
+        // Four packets are block-aligned and can go unpadded; three need padding.
+        let plain: Vec<Vec<u8>> =
+            [3, 4, 3, 4].iter().enumerate().map(|(i, &n)| ts_packets(n, i as u8)).collect();
+        let media = format!(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:4\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x{}\n\
+             #EXTINF:4,\nseg0.ts\n#EXTINF:4,\nseg1.ts\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\"\n\
+             #EXTINF:4,\nseg2.ts\n#EXTINF:4,\nseg3.ts\n#EXT-X-ENDLIST\n",
+            hex::encode(explicit_iv)
+        );
+        let server = MockServer::files(vec![
+            ("/index.m3u8", media.into()),
+            ("/key.bin", KEY.to_vec()),
+            ("/seg0.ts", encrypt(&KEY, &explicit_iv, &plain[0], true)),
+            ("/seg1.ts", encrypt(&KEY, &explicit_iv, &plain[1], false)),
+            ("/seg2.ts", encrypt(&KEY, &iv_for_sequence(2), &plain[2], true)),
+            ("/seg3.ts", encrypt(&KEY, &iv_for_sequence(3), &plain[3], false)),
+        ])
+        .await;
+        let merged = download(&server.url("/index.m3u8"), test_options()).await.unwrap();
+        assert_eq!(merged, plain.concat());
+    }
+
+    #[tokio::test]
+    async fn split_download_matches_single_download() {
+        let plain: Vec<Vec<u8>> = (0..3).map(|i| ts_packets(40 + i as usize, i)).collect();
+        let server = MockServer::files(vec![
+            (
+                "/index.m3u8",
+                "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4,\nseg0.ts\n#EXTINF:4,\nseg1.ts\n\
+                 #EXTINF:4,\nseg2.ts\n#EXT-X-ENDLIST\n"
+                    .into(),
+            ),
+            ("/seg0.ts", plain[0].clone()),
+            ("/seg1.ts", plain[1].clone()),
+            ("/seg2.ts", plain[2].clone()),
+        ])
+        .await;
+        let url = server.url("/index.m3u8");
+        let single = download(&url, test_options()).await.unwrap();
+        let options =
+            DownloadOptions { split_threshold: Some(1024), split_parts: 3, ..test_options() };
+        let split = download(&url, options).await.unwrap();
+        assert_eq!(split, single);
+        assert_eq!(split, plain.concat());
+
+        let ranged = server.requests().iter().filter(|r| r.headers.contains_key("range")).count();
+        assert_eq!(ranged, 9);
+    }
+
+    #[tokio::test]
+    async fn failed_head_does_not_stop_split_downloads() {
+        let plain: Vec<Vec<u8>> = (0..2).map(|i| ts_packets(40, i)).collect();
+        let bodies = plain.clone();
+        let heads = AtomicUsize::new(0);
+        let server = MockServer::start(move |req| {
+            if req.method == "HEAD" && heads.fetch_add(1, Ordering::SeqCst) == 0 {
+                return MockResponse { status: 503, ..Default::default() };
+            }
+            match req.path.as_str() {
+                "/index.m3u8" => MockResponse::ok(
+                    "#EXTM3U\n#EXT-X-TARGETDURATION:4\n\
+                     #EXTINF:4,\nseg0.ts\n#EXTINF:4,\nseg1.ts\n#EXT-X-ENDLIST\n",
+                ),
+                "/seg0.ts" => ranged(req, &bodies[0]),
+                "/seg1.ts" => ranged(req, &bodies[1]),
+                _ => MockResponse::not_found(),
+            }
+        })
+        .await;
+        let options = DownloadOptions {
+            concurrency: 1,
+            split_threshold: Some(1024),
+            split_parts: 2,
+            ..test_options()
+        };
+        let merged = download(&server.url("/index.m3u8"), options).await.unwrap();
+        assert_eq!(merged, plain.concat());
+        assert_eq!(server.paths("HEAD"), ["/seg0.ts", "/seg1.ts"]);
+        let ranged = server.requests().iter().filter(|r| r.headers.contains_key("range")).count();
+        assert_eq!(ranged, 2);
+    }
+
+    /// Serves `plain` as `/seg0.ts` and `/seg1.ts`, cutting the first response for
+    /// `/seg1.ts` off after 500 bytes; `resumable` decides whether it offers byte ranges.
+    async fn cut_body_server(plain: &[Vec<u8>], resumable: bool) -> MockServer {
+        let bodies = plain.to_vec();
+        let cut = AtomicUsize::new(0);
+        MockServer::start(move |req| {
+            let response = match req.path.as_str() {
+                "/index.m3u8" => {
+                    return MockResponse::ok(
+                        "#EXTM3U\n#EXT-X-TARGETDURATION:4\n\
+                         #EXTINF:4,\nseg0.ts\n#EXTINF:4,\nseg1.ts\n#EXT-X-ENDLIST\n",
+                    )
+                }
+                "/seg0.ts" => ranged(req, &bodies[0]),
+                "/seg1.ts" => ranged(req, &bodies[1]),
+                _ => return MockResponse::not_found(),
+            };
+            let mut response = match resumable {
+                true => response,
+                false => MockResponse { headers: Vec::new(), ..response },
+            };
+            if req.path == "/seg1.ts" && cut.fetch_add(1, Ordering::SeqCst) == 0 {
+                response.cut_after = Some(500);
+            }
+            response
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn body_cut_mid_stream_resumes_with_a_range() {
+        let plain: Vec<Vec<u8>> = (0..2).map(|i| ts_packets(8, i)).collect();
+        let server = cut_body_server(&plain, true).await;
+        let merged = download(&server.url("/index.m3u8"), test_options()).await.unwrap();
+        assert_eq!(merged, plain.concat());
+        let resumed: Vec<_> =
+            server.requests().into_iter().filter_map(|r| r.headers.get("range").cloned()).collect();
+        assert_eq!(resumed, ["bytes=500-"]);
+    }
+
+    #[tokio::test]
+    async fn body_cut_mid_stream_without_ranges_is_retried_whole() {
+        let plain: Vec<Vec<u8>> = (0..2).map(|i| ts_packets(8, i)).collect();
+        let server = cut_body_server(&plain, false).await;
+        let merged = download(&server.url("/index.m3u8"), test_options()).await.unwrap();
+        assert_eq!(merged, plain.concat());
+        let seg1 = server.paths("GET").iter().filter(|p| *p == "/seg1.ts").count();
+        assert_eq!(seg1, 2);
+    }
+
+    #[tokio::test]
+    async fn missing_iv_counts_from_media_sequence() {
+        let plain: Vec<Vec<u8>> = (0..3).map(|i| ts_packets(3, i)).collect();
+        let server = MockServer::files(vec![
+            (
+                "/index.m3u8",
+                "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:100\n\
+                 #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\"\n\
+                 #EXTINF:4,\nseg0.ts\n#EXTINF:4,\nseg1.ts\n#EXTINF:4,\nseg2.ts\n\
+                 #EXT-X-ENDLIST\n"
+                    .into(),
+            ),
+            ("/key.bin", KEY.to_vec()),
+            ("/seg0.ts", encrypt(&KEY, &iv_for_sequence(100), &plain[0], true)),
+            ("/seg1.ts", encrypt(&KEY, &iv_for_sequence(101), &plain[1], true)),
+            ("/seg2.ts", encrypt(&KEY, &iv_for_sequence(102), &plain[2], true)),
+        ])
+        .await;
+        let merged = download(&server.url("/index.m3u8"), test_options()).await.unwrap();
+        assert_eq!(merged, plain.concat());
+    }
+
+    /// A playlist of three EXT-X-BYTERANGE segments of `media.ts`, with the file's contents.
+    fn byte_range_playlist() -> (String, Vec<Vec<u8>>) {
+        let plain: Vec<Vec<u8>> = (0..3).map(|i| ts_packets(2 + i as usize, i)).collect();
+        let mut media = "#EXTM3U\n#EXT-X-VERSION:4\n#EXT-X-TARGETDURATION:4\n".to_string();
+        let mut offset = 0;
+        for part in &plain {
+            media += &format!("#EXTINF:4,\n#EXT-X-BYTERANGE:{}@{}\nmedia.ts\n", part.len(), offset);
+            offset += part.len();
+        }
+        media += "#EXT-X-ENDLIST\n";
+        (media, plain)
+    }
+
+    #[tokio::test]
+    async fn byte_ranges_accept_206_and_a_whole_file_200() {
+        let (media, plain) = byte_range_playlist();
+        let file = plain.concat();
+        for honors_ranges in [true, false] {
+            let (media, file) = (media.clone(), file.clone());
+            let server = MockServer::start(move |req| match req.path.as_str() {
+                "/index.m3u8" => MockResponse::ok(media.clone()),
+                "/media.ts" if honors_ranges => ranged(req, &file),
+                "/media.ts" => MockResponse::ok(file.clone()),
+                _ => MockResponse::not_found(),
+            })
+            .await;
+            let merged = download(&server.url("/index.m3u8"), test_options()).await.unwrap();
+            assert_eq!(merged, plain.concat(), "honors_ranges: {}", honors_ranges);
+            assert_eq!(server.paths("GET").len(), 4);
+        }
+    }
+
+    #[test]
+    fn temp_file_names_need_the_default_prefix() {
+        for name in [
+            "m3u8dl-0123456789abcdef_seg_00001.ts",
+            "m3u8dl-0123456789abcdef_group_00001.m4a.zst",
+            "m3u8dl-0123456789abcdef_temp_merged.mp4",
+            "m3u8dl-0123456789ABCDEF_probe.ts",
+        ] {
+            assert!(is_temp_file_name(name), "{}", name);
+        }
+        for name in [
+            "seg_00001.mp4",
+            "temp_merged.mp4",
+            "group_00001.m4a",
+            "probe.ts",
+            "m3u8dl-0123_seg_00001.ts",
+            "m3u8dl-0123456789abcdeg_seg_00001.ts",
+            "m3u8dl-0123456789abcdef_holiday.mp4",
+        ] {
+            assert!(!is_temp_file_name(name), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn clean_stale_temp_keeps_unprefixed_files() {
+        let dir = scratch_dir();
+        let job = dir.join("variant_00");
+        let user = dir.join("variant_01");
+        std::fs::create_dir_all(&job).unwrap();
+        std::fs::create_dir_all(&user).unwrap();
+        for path in [
+            dir.join("seg_00001.mp4"),
+            dir.join("temp_merged.mp4"),
+            dir.join("m3u8dl-0123456789abcdef_seg_00001.ts"),
+            job.join("m3u8dl-0123456789abcdef_temp_merged.ts"),
+            user.join("group_00001.m4a"),
+        ] {
+            std::fs::write(path, b"x").unwrap();
+        }
+
+        assert_eq!(clean_stale_temp(&dir, Duration::ZERO).await, 3);
+        let mut left: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["seg_00001.mp4", "temp_merged.mp4", "variant_01"]);
+        assert!(user.join("group_00001.m4a").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn playlist_base_url_forms() {
+        let base = |url: &str| playlist_base_url(url).unwrap().map(|url| url.to_string());
+        let joined = |url: &str, uri: &str| {
+            resolve_uri(playlist_base_url(url).unwrap().as_ref(), uri, "Segment")
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(base("https://cdn.test/index.m3u8").unwrap(), "https://cdn.test/");
+        assert_eq!(base("https://cdn.test:8443").unwrap(), "https://cdn.test:8443/");
+        assert_eq!(base("http://cdn.test:8080/a/b.m3u8").unwrap(), "http://cdn.test:8080/a/");
+        assert_eq!(base("https://cdn.test/live/").unwrap(), "https://cdn.test/live/");
+        assert_eq!(base("https://cdn.test/a/b.m3u8#t=10").unwrap(), "https://cdn.test/a/");
+        assert_eq!(base("https://cdn.test/a/b.m3u8?x=1").unwrap(), "https://cdn.test/a/?x=1");
+        assert_eq!(base("file:///tmp/b.m3u8"), None);
+        assert_eq!(base("b.m3u8"), None);
+
+        assert_eq!(joined("https://cdn.test/index.m3u8", "seg0.ts"), "https://cdn.test/seg0.ts");
+        assert_eq!(joined("https://cdn.test:8443", "seg0.ts"), "https://cdn.test:8443/seg0.ts");
+        assert_eq!(joined("https://cdn.test/live/", "seg0.ts"), "https://cdn.test/live/seg0.ts");
+        assert_eq!(joined("https://cdn.test/a/b.m3u8#t", "seg0.ts"), "https://cdn.test/a/seg0.ts");
+        assert_eq!(joined("https://cdn.test/a/b.m3u8?x=1", "s.ts"), "https://cdn.test/a/s.ts");
+    }
+
+    #[test]
+    fn chunked_decrypt_matches_one_shot_decrypt() {
+        let iv = [7u8; 16];
+        let mut ends_in_one = ts_packets(4, 6);
+        *ends_in_one.last_mut().unwrap() = 0x01;
+        let cases = [
+            encrypt(&KEY, &iv, &ts_packets(3, 1), true),
+            encrypt(&KEY, &iv, &ts_packets(4, 2), false),
+            encrypt(&KEY, &iv, &ts_packets(4, 3), true),
+            encrypt(&KEY, &iv, &ends_in_one, false),
+            encrypt(&KEY, &iv, b"not a transport stream", true),
+        ];
+        for (case, encrypted) in cases.iter().enumerate() {
+            let mut one_shot = encrypted.clone();
+            decrypt_segment(&KEY, &iv, &mut one_shot).unwrap();
+            for chunk in [1, 7, 16, 17, 100, encrypted.len()] {
+                let mut decryptor = CbcStreamDecryptor::new(&KEY, &iv).unwrap();
+                let mut chunked = Vec::new();
+                for part in encrypted.chunks(chunk) {
+                    decryptor.update(part, &mut chunked);
+                }
+                decryptor.finish(&mut chunked).unwrap();
+                assert_eq!(chunked, one_shot, "case {} in {}-byte chunks", case, chunk);
+            }
+        }
+    }
+
+    #[test]
+    fn equal_bandwidth_variants_are_broken_by_codec_preference() {
+        let variant = |uri: &str, codecs: &str| VariantInfo {
+            kind: StreamKind::Main,
+            uri: uri.to_string(),
+            bandwidth: 2_000_000,
+            average_bandwidth: None,
+            resolution: Some((1920, 1080)),
+            codecs: Some(codecs.to_string()),
+            frame_rate: None,
+            audio_group: None,
+            subtitle_group: None,
+        };
+        let variants = [
+            variant("hevc.m3u8", "hvc1.2.4.L123.B0,mp4a.40.2"),
+            variant("avc.m3u8", "avc1.640028"),
+        ];
+        let picked = |preferred: &[&str], choice| {
+            let options = DownloadOptions {
+                preferred_codecs: preferred.iter().map(|c| c.to_string()).collect(),
+                variant_choice: choice,
+                ..DownloadOptions::default()
+            };
+            select_variant(&variants, &options).unwrap().uri
+        };
+        for choice in [VariantChoice::Best, VariantChoice::Lowest] {
+            assert_eq!(picked(&["AVC1"], choice), "avc.m3u8");
+            assert_eq!(picked(&["hvc1", "avc1"], choice), "hevc.m3u8");
+            assert_eq!(picked(&["vp09", "avc1"], choice), "avc.m3u8");
+            // Without a preference the playlist order decides.
+            assert_eq!(picked(&[], choice), "hevc.m3u8");
+        }
+    }
+
+    /// Benchmark: many small segments over HTTP/1.1, with pooled connections against a new
+    /// connection per request. Run with `--nocapture` to see the timings.
+    #[tokio::test]
+    async fn pooled_connections_are_reused_across_segments() {
+        const SEGMENTS: usize = 200;
+        let segment = ts_packets(1, 0);
+        let mut media = "#EXTM3U\n#EXT-X-TARGETDURATION:1\n".to_string();
+        for idx in 0..SEGMENTS {
+            media += &format!("#EXTINF:1,\nseg{}.ts\n", idx);
+        }
+        media += "#EXT-X-ENDLIST\n";
+
+        let mut runs = Vec::new();
+        for pool in [None, Some(0)] {
+            let (media, segment) = (media.clone(), segment.clone());
+            let server = MockServer::start(move |req| {
+                let response = match req.path.as_str() {
+                    "/index.m3u8" => MockResponse::ok(media.clone()),
+                    _ => MockResponse::ok(segment.clone()),
+                };
+                MockResponse { keep_alive: true, ..response }
+            })
+            .await;
+            let options = DownloadOptions {
+                concurrency: 4,
+                http: HttpTuning { pool_max_idle_per_host: pool, ..HttpTuning::default() },
+                ..test_options()
+            };
+            let started = Instant::now();
+            download(&server.url("/index.m3u8"), options).await.unwrap();
+            runs.push((started.elapsed(), server.connections()));
+        }
+
+        let [(pooled_time, pooled), (unpooled_time, unpooled)] = runs[..] else {
+            unreachable!()
+        };
+        eprintln!(
+            "{} segments: pooled {:?} over {} connections, unpooled {:?} over {} connections",
+            SEGMENTS, pooled_time, pooled, unpooled_time, unpooled
+        );
+        // The playlist client is separate; the segment client needs one per worker at most.
+        assert!(pooled <= 4 + 2, "{} connections", pooled);
+        assert!(unpooled >= SEGMENTS, "{} connections", unpooled);
+    }
+
+    #[test]
+    fn private_address_ranges() {
+        let private = |ip: &str| is_private_address(ip.parse().unwrap());
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "0.1.2.3",
+            "100.64.0.1",
+            "100.100.100.200",
+            "100.127.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::7f00:1",
+            "64:ff9b::c0a8:101",
+        ] {
+            assert!(private(ip), "{}", ip);
+        }
+        for ip in [
+            "1.1.1.1",
+            "100.63.255.255",
+            "100.128.0.1",
+            "93.184.216.34",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+            "64:ff9b::808:808",
+        ] {
+            assert!(!private(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn playlist_limits_reject_oversized_input() {
+        let limits = PlaylistLimits {
+            max_size: 4096,
+            max_line_length: 100,
+            max_segments: 3,
+            max_variants: 2,
+        };
+        let media = |segments: usize| {
+            let mut text = "#EXTM3U\n#EXT-X-TARGETDURATION:4\n".to_string();
+            for idx in 0..segments {
+                text += &format!("#EXTINF:4,\nseg{}.ts\n", idx);
+            }
+            text + "#EXT-X-ENDLIST\n"
+        };
+        let master = |variants: usize| {
+            let mut text = "#EXTM3U\n".to_string();
+            for idx in 0..variants {
+                text += &format!("#EXT-X-STREAM-INF:BANDWIDTH={}\nv{}.m3u8\n", idx + 1, idx);
+            }
+            text
+        };
+        let error = |text: &str| check_playlist_limits(text.as_bytes(), &limits).unwrap_err();
+
+        assert!(check_playlist_limits(media(3).as_bytes(), &limits).is_ok());
+        assert!(check_playlist_limits(master(2).as_bytes(), &limits).is_ok());
+        assert!(error(&media(4)).to_string().contains("4 segments"));
+        assert!(error(&master(3)).to_string().contains("3 variants"));
+        let oversized = media(1) + &"#\n".repeat(2048);
+        assert!(error(&oversized).to_string().contains("byte limit"));
+        let long_uri = media(1).replace("seg0.ts", &"a".repeat(101));
+        assert!(error(&long_uri).to_string().contains("101-byte line"));
+        // A lone CR ends a line as well.
+        let cr_only = media(2).replace('\n', "\r");
+        assert!(check_playlist_limits(cr_only.as_bytes(), &limits).is_ok());
+        let long_cr = long_uri.replace('\n', "\r");
+        assert!(error(&long_cr).to_string().contains("101-byte line"));
+    }
+
+    #[tokio::test]
+    async fn oversized_playlist_body_is_cut_off() {
+        let mut body = "#EXTM3U\n#EXT-X-TARGETDURATION:4\n".to_string();
+        body += &"#EXTINF:4,\nseg.ts\n".repeat(1000);
+        let server = MockServer::files(vec![("/index.m3u8", body.into())]).await;
+        let options = DownloadOptions {
+            playlist_limits: PlaylistLimits { max_size: 1024, ..PlaylistLimits::default() },
+            retries: 1,
+            ..test_options()
+        };
+        let error = download(&server.url("/index.m3u8"), options).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("1024-byte limit"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn transcode_parts_of_another_transcode_are_discarded() {
+        let dir = scratch_dir();
+        let input = dir.join("merged.ts").to_string_lossy().to_string();
+        let output = dir.join("out.mp4").to_string_lossy().to_string();
+        std::fs::write(&input, ts_packets(600, 1)).unwrap();
+        let streams = StreamPresence { video: true, audio: true };
+        let options = DownloadOptions { video_bitrate: 2000, ..test_options() };
+        let identity = |options: DownloadOptions| {
+            let input = input.clone();
+            async move {
+                TranscodeParts::identity(&input, &options, AccelType::CPU, streams).await.unwrap()
+            }
+        };
+
+        let first = identity(options.clone()).await;
+        assert_eq!(identity(options.clone()).await, first);
+        let other_bitrate = DownloadOptions { video_bitrate: 3000, ..options.clone() };
+        assert_ne!(identity(other_bitrate).await, first);
+        let mut changed = ts_packets(600, 1);
+        *changed.last_mut().unwrap() ^= 1;
+        std::fs::write(&input, changed).unwrap();
+        let second = identity(options.clone()).await;
+        assert_ne!(second, first);
+
+        // Parts left by the first input's transcode don't count towards the second's.
+        std::fs::write(TranscodeParts::identity_path(&output), &first).unwrap();
+        for idx in 0..2 {
+            std::fs::write(TranscodeParts::part_path(&output, idx), b"part").unwrap();
+        }
+        let parts = TranscodeParts::load(&output, &second).await.unwrap();
+        assert!(parts.paths.is_empty());
+        assert!(!TranscodeParts::part_path(&output, 0).exists());
+        assert!(!TranscodeParts::part_path(&output, 1).exists());
+        let recorded = std::fs::read_to_string(TranscodeParts::identity_path(&output)).unwrap();
+        assert_eq!(recorded, second);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn zero_retries_still_makes_one_attempt() {
+        let (server, plain) = encrypted_then_clear_server().await;
+        let options = DownloadOptions { retries: 0, ..test_options() };
+        let merged = download(&server.url("/index.m3u8"), options).await.unwrap();
+        assert_eq!(merged, plain.concat());
+    }
+
+    /// A master playlist with two variants, each with one clear segment.
+    async fn master_server() -> MockServer {
+        MockServer::files(vec![
+            (
+                "/master.m3u8",
+                "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=800000,RESOLUTION=640x360\nlow.m3u8\n\
+                 #EXT-X-STREAM-INF:BANDWIDTH=2000000,RESOLUTION=1280x720\nhigh.m3u8\n"
+                    .into(),
+            ),
+            ("/low.m3u8", "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4,\nlow.ts\n".into()),
+            (
+                "/high.m3u8",
+                "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4,\nhigh.ts\n#EXT-X-ENDLIST\n"
+                    .into(),
+            ),
+        ])
+        .await
+    }
+
+    #[tokio::test]
+    async fn inspect_and_probe_reach_loopback_servers_when_allowed() {
+        let server = master_server().await;
+        let url = server.url("/master.m3u8");
+
+        let probe = probe_playlist(url.clone(), test_options()).await.unwrap();
+        assert!(probe.is_master);
+        assert_eq!(probe.variants.len(), 2);
+
+        let inspection = inspect(url.clone(), test_options()).await.unwrap();
+        assert_eq!(inspection.kind, PlaylistKind::Master);
+        assert_eq!(inspection.segment_count, 1);
+        assert!(!inspection.is_live);
+
+        let options = DownloadOptions { retries: 1, ..DownloadOptions::default() };
+        let refused = probe_playlist(url, options).await.unwrap_err();
+        assert!(format!("{:#}", refused).contains("allow_private_addresses"), "{:#}", refused);
+    }
+}